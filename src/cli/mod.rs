@@ -1,40 +1,233 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use crate::client::TaganrogClient;
-use crate::entities::{MediaPage, TagsAutocomplete};
+use crate::entities::{CompactionResult, DuplicateGroup, HistoryEntry, LibraryRootInfo, Media, MediaId, MergeResult, MediaPage, MediaSort, RebaseResult, RekeyResult, RelinkCandidate, RescanResult, RestorePoint, RestoreResult, Tag, TagsAutocomplete, Verification};
 use crate::error::TaganrogError;
-use crate::storage::Storage;
-use crate::utils::normalize_query;
+use crate::storage::{DbOperation, LoggedOperation, Storage};
+use crate::query::QueryExpr;
 
-pub async fn tag_media<T: Storage>(client: &mut TaganrogClient<T>, filepath: &str, tag: &String) -> Result<bool, TaganrogError> {
+pub async fn tag_media<T: Storage>(client: &mut TaganrogClient<T>, filepath: &str, tags: &[Tag]) -> Result<Vec<Tag>, TaganrogError> {
     let filepath: PathBuf = filepath.into();
-    let mut media = client.create_media_from_file(&filepath).await?;
-    if client.get_media_by_id(&media.id).is_none() {
-        media = client.add_media(media).await?.safe_unwrap();
-    }
-    let was_added = client.add_tag_to_media(&media.id, tag).await?;
-    Ok(was_added)
+    let media = client.create_media_from_file(&filepath).await?;
+    let added_tags = client.tag_media(media, tags).await?;
+    Ok(added_tags)
 }
 
-pub async fn untag_media<T: Storage>(client: &mut TaganrogClient<T>, filepath: &str, tag: &String) -> Result<bool, TaganrogError> {
+pub async fn untag_media<T: Storage>(client: &mut TaganrogClient<T>, filepath: &str, tags: &[Tag]) -> Result<Vec<Tag>, TaganrogError> {
     let filepath: PathBuf = filepath.into();
-    let media = client.create_media_from_file(&filepath).await?;
-    let was_removed = client.remove_tag_from_media(&media.id, tag).await?;
-    Ok(was_removed)
+    // the file of a media can be untagged after it was deleted
+    let maybe_missing_media = match filepath.is_file() {
+        true => None,
+        false => client.canonicalize_location(&filepath).ok().and_then(|x| client.get_media_by_location(&x.to_string_lossy())),
+    };
+    let media = match maybe_missing_media {
+        Some(media) => media,
+        None => client.create_media_from_file(&filepath).await?,
+    };
+    let removed_tags = client.remove_tags_from_media(&media.id, tags).await?;
+    Ok(removed_tags)
 }
 
 pub async fn list_tags<T: Storage>(client: &TaganrogClient<T>, tag_name: String, max_items: usize) -> Vec<TagsAutocomplete> {
-    let normalized_query = normalize_query(&tag_name);
-    if normalized_query.is_empty() {
+    if tag_name.trim().is_empty() {
         return client.get_all_tags();
     }
-    client.autocomplete_tags(&normalized_query, max_items)
+    client.autocomplete_tags(tag_name.trim_start(), max_items)
+}
+
+/// Searches with the query made of the arguments, all the media if there are none.
+pub async fn search_media<T: Storage>(client: &TaganrogClient<T>, tags: Vec<String>, sort: MediaSort, page_size: usize, page_index: usize) -> Result<MediaPage, TaganrogError> {
+    let query = match tags.join(" ") {
+        query if query.trim().is_empty() => "all".to_string(),
+        query => query,
+    };
+    let terms = QueryExpr::parse(&query)?.map(|x| x.terms().into_iter().cloned().collect::<Vec<Tag>>()).unwrap_or_default();
+    // there's no background scan in the CLI
+    if terms.iter().any(|x| x == "missing") {
+        client.scan_missing_media().await;
+    }
+    if terms.iter().any(|x| x == "modified") {
+        client.scan_modified_media().await;
+    }
+    client.search_media(&query, sort, page_size, page_index)
+}
+
+/// A query with the unknown tags of the search replaced by the closest known ones, when the search found nothing.
+pub async fn suggest_query<T: Storage>(client: &TaganrogClient<T>, tags: Vec<String>) -> Option<String> {
+    client.suggest_query(&tags.join(" "))
+}
+
+pub async fn compact_db<T: Storage>(client: &mut TaganrogClient<T>) -> Result<CompactionResult, TaganrogError> {
+    client.compact().await
+}
+
+pub async fn rekey_media<T: Storage>(client: &mut TaganrogClient<T>) -> Result<RekeyResult, TaganrogError> {
+    client.rekey_media().await
+}
+
+/// Returns the images similar to the one in `filepath`, closest first, with the number of bits their perceptual hashes differ in.
+/// Images that don't have a perceptual hash yet are hashed first.
+pub async fn find_similar_media<T: Storage>(client: &mut TaganrogClient<T>, filepath: &str, max_distance: u32) -> Result<Vec<(Media, u32)>, TaganrogError> {
+    let filepath: PathBuf = filepath.into();
+    let media = client.create_media_from_file(&filepath).await?;
+    if !media.is_image() {
+        return Err(TaganrogError::NotAnImage(media.location));
+    }
+    client.hash_images().await?;
+    let media = client.get_media_by_id(&media.id).unwrap_or(media);
+    Ok(client.find_similar_media(&media, max_distance).await)
+}
+
+pub async fn find_duplicates<T: Storage>(client: &TaganrogClient<T>) -> Result<Vec<DuplicateGroup>, TaganrogError> {
+    client.find_duplicates().await
+}
+
+/// Keeps the first (oldest) media of the group with the tags of all, and forgets (or with `delete_files` deletes) the others.
+pub async fn merge_duplicates<T: Storage>(client: &mut TaganrogClient<T>, group: &DuplicateGroup, delete_files: bool) -> Result<MergeResult, TaganrogError> {
+    let duplicate_ids = group.media.iter().skip(1).map(|x| x.id.clone()).collect::<Vec<MediaId>>();
+    client.merge_duplicates(&group.media[0].id, &duplicate_ids, delete_files).await
+}
+
+/// Deletes the media whose file is missing, or with `dry_run` only lists them.
+pub async fn prune_missing_media<T: Storage>(client: &mut TaganrogClient<T>, dry_run: bool) -> Result<Vec<Media>, TaganrogError> {
+    client.prune_missing_media(dry_run).await
 }
 
-pub async fn search_media<T: Storage>(client: &TaganrogClient<T>, tags: Vec<String>, page_size: usize, page_index: usize) -> MediaPage {
-    let query = tags.join(" ");
-    let normalized_query = normalize_query(&query);
-    if normalized_query.is_empty() {
-        return client.get_all_media(page_size, page_index);
+/// Finds the moved files of missing media in `dirs` (and their subdirectories).
+pub async fn find_relink_candidates<T: Storage>(client: &TaganrogClient<T>, dirs: &[String]) -> Result<Vec<RelinkCandidate>, TaganrogError> {
+    let mut abs_dirs = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        let abs_dir = client.canonicalize_location(Path::new(dir)).map_err(|_| TaganrogError::FileNotFound)?;
+        if !abs_dir.is_dir() {
+            return Err(TaganrogError::FileNotFound);
+        }
+        abs_dirs.push(abs_dir);
     }
-    client.search_media(&normalized_query, page_size, page_index)
+    client.find_relink_candidates(&abs_dirs).await
+}
+
+/// Looks for media whose file content changed, recording the file info of the others where needed.
+pub async fn rescan_media<T: Storage>(client: &mut TaganrogClient<T>) -> Result<RescanResult, TaganrogError> {
+    client.rescan_media().await
+}
+
+/// Regenerates the thumbnail of a changed media and records its new file info, so that it's no longer modified.
+/// Returns whether a new thumbnail was saved.
+pub async fn refresh_media<T: Storage>(client: &mut TaganrogClient<T>, media_id: &MediaId) -> Result<bool, TaganrogError> {
+    let has_new_thumbnail = client.regenerate_thumbnail(media_id).await?;
+    client.record_file_info(media_id).await?;
+    Ok(has_new_thumbnail)
+}
+
+/// Rehashes the files of the media matching the tags (all media without tags) and compares them with their checksum.
+/// With `record` the verification time of the intact ones is recorded.
+pub async fn verify_media<T: Storage>(client: &mut TaganrogClient<T>, tags: Vec<String>, record: bool, on_progress: impl Fn(usize, usize)) -> Result<Vec<Verification>, TaganrogError> {
+    let media_vec = search_media(client, tags, MediaSort::default(), usize::MAX, 0).await?.media_vec;
+    let verifications = client.verify_media(media_vec, on_progress).await;
+    if record {
+        client.record_verifications(&verifications).await?;
+    }
+    Ok(verifications)
+}
+
+pub async fn relink_media<T: Storage>(client: &mut TaganrogClient<T>, media_id: &MediaId, location: &str) -> Result<bool, TaganrogError> {
+    client.update_media_location(media_id, location.to_string()).await
+}
+
+pub async fn get_library_roots<T: Storage>(client: &TaganrogClient<T>) -> Vec<LibraryRootInfo> {
+    client.get_library_roots()
+}
+
+/// Stores every location under a library root relative to it. With `from` (a directory and a root id),
+/// the media under that directory are moved under the root first.
+pub async fn rebase_locations<T: Storage>(client: &mut TaganrogClient<T>, from: Option<(&str, &str)>) -> Result<RebaseResult, TaganrogError> {
+    let from = from.map(|(old_dir, root_id)| (PathBuf::from(old_dir), root_id));
+    client.rebase_locations(from.as_ref().map(|(old_dir, root_id)| (old_dir.as_path(), *root_id))).await
+}
+
+/// Encrypts the DB (and thumbnails if configured) with `passphrase`, or decrypts it if `None`.
+/// Returns the number of converted thumbnails.
+pub async fn set_encryption<T: Storage>(client: &mut TaganrogClient<T>, passphrase: Option<&str>) -> Result<usize, TaganrogError> {
+    client.set_encryption(passphrase).await
+}
+
+/// Returns the last `max_items` changes of a file (if `target` is an existing file), of a tag (otherwise) or of everything.
+pub async fn get_history<T: Storage>(client: &mut TaganrogClient<T>, target: Option<&str>, max_items: usize) -> Result<Vec<HistoryEntry>, TaganrogError> {
+    let history = client.get_history().await?;
+    let mut locations = HashMap::<MediaId, String>::new();
+    for (_, logged_operation) in history.iter() {
+        if let DbOperation::CreateMedia { media } = &logged_operation.operation {
+            locations.insert(media.id.clone(), media.location.clone());
+        }
+    }
+
+    let filepath = target.map(PathBuf::from).filter(|x| x.is_file());
+    let media_id = match &filepath {
+        Some(filepath) => Some(client.create_media_from_file(filepath).await?.id),
+        None => None,
+    };
+    let tag = target.filter(|_| media_id.is_none()).map(|x| x.to_string());
+    let entries = history.into_iter()
+        .filter(|(_, x)| match (&media_id, &tag) {
+            (Some(media_id), _) => x.operation.media_id() == Some(media_id),
+            (None, Some(tag)) => matches!(&x.operation, DbOperation::AddTag { tag: t, .. } | DbOperation::RemoveTag { tag: t, .. } if t == tag),
+            (None, None) => true,
+        })
+        .map(|(line, x)| create_history_entry(client, Some(line), x, &locations))
+        .collect::<Vec<HistoryEntry>>();
+    let skipped = entries.len().saturating_sub(max_items);
+    Ok(entries.into_iter().skip(skipped).collect())
+}
+
+pub async fn undo<T: Storage>(client: &mut TaganrogClient<T>) -> Result<Option<HistoryEntry>, TaganrogError> {
+    let maybe_operation = client.undo().await?;
+    Ok(maybe_operation.map(|x| create_history_entry(client, None, x.into(), &HashMap::new())))
+}
+
+pub async fn redo<T: Storage>(client: &mut TaganrogClient<T>) -> Result<Option<HistoryEntry>, TaganrogError> {
+    let maybe_operation = client.redo().await?;
+    Ok(maybe_operation.map(|x| create_history_entry(client, None, x.into(), &HashMap::new())))
+}
+
+/// Parses the `--at` of `tgk restore`: a change number, an RFC 3339 timestamp or a local date and time.
+pub fn parse_restore_point(value: &str) -> Result<RestorePoint, String> {
+    if let Ok(line) = value.parse::<usize>() {
+        return Ok(RestorePoint::Line(line));
+    }
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(RestorePoint::Timestamp(timestamp.with_timezone(&Utc)));
+    }
+    let local_timestamp = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"].iter()
+        .find_map(|x| NaiveDateTime::parse_from_str(value, x).ok())
+        .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|x| x.and_hms_opt(0, 0, 0)))
+        .ok_or_else(|| format!("invalid restore point '{}', expected a change number or a date and time", value))?;
+    let timestamp = Local.from_local_datetime(&local_timestamp).earliest()
+        .ok_or_else(|| format!("invalid local time '{}'", value))?;
+    Ok(RestorePoint::Timestamp(timestamp.with_timezone(&Utc)))
+}
+
+/// Returns the changes restoring the DB to `point` makes, with the locations of their media.
+pub async fn restore_db<T: Storage>(client: &mut TaganrogClient<T>, point: RestorePoint, dry_run: bool) -> Result<(RestoreResult, Vec<HistoryEntry>), TaganrogError> {
+    let result = client.restore(point, dry_run).await?;
+    let entries = result.diff.iter().cloned()
+        .map(|x| create_history_entry(client, None, x.into(), &HashMap::new()))
+        .collect();
+    Ok((result, entries))
+}
+
+/// `locations` are the last known locations of media that might be deleted by now.
+fn create_history_entry<T: Storage>(client: &TaganrogClient<T>, line: Option<usize>, logged_operation: LoggedOperation, locations: &HashMap<MediaId, String>) -> HistoryEntry {
+    let operation = logged_operation.operation;
+    let media_id = operation.media_id().cloned().unwrap_or_default();
+    let media_location = client.get_media_by_id(&media_id).map(|x| x.location)
+        .or_else(|| locations.get(&media_id).cloned())
+        .or_else(|| match &operation {
+            DbOperation::CreateMedia { media } => Some(media.location.clone()),
+            DbOperation::DeleteMedia { previous: Some(media), .. } => Some(media.location.clone()),
+            _ => None,
+        })
+        .unwrap_or(media_id);
+    let meta = logged_operation.meta;
+    HistoryEntry { line, timestamp: meta.timestamp, origin: meta.origin, kind: meta.kind, media_location, operation }
 }
@@ -1,7 +1,9 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use dashmap::DashMap;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
 use itertools::Itertools;
+use log::{info, warn};
 use rand::SeedableRng;
 use rand::seq::SliceRandom;
 use rand_chacha::ChaCha8Rng;
@@ -9,40 +11,421 @@ use tokio::time::Instant;
 use crate::config::AppConfig;
 use crate::entities::*;
 use crate::error::TaganrogError;
-use crate::storage::{DbOperation, Storage};
-use crate::utils::hash_utils::MurMurHasher;
+use crate::query::{Filter, QueryExpr};
+use crate::text_index::TrigramIndex;
+use crate::utils::str_utils::{fuzzy_find, max_typos, StringExtensions};
+use crate::storage::{media_to_operations, ChangeKind, DbOperation, InMemoryStorage, LogChanges, LoggedOperation, OperationMeta, Origin, StateMeta, Storage};
+use crate::storage::encryption::Cipher;
+use crate::storage::snapshot::Snapshot;
+use crate::utils::fs_utils::{canonicalize_file, walk_files};
+use crate::utils::is_virtual_tag;
+use crate::utils::hash_utils::{ContentHasher, MurMurHasher, PerceptualHasher};
+use crate::utils::image_utils::png_thumbnail;
 
 pub struct TaganrogClient<T: Storage> {
     cfg: AppConfig,
+    // recorded with every operation this client writes
+    origin: Origin,
 
     // persistent storage
     storage: T,
+    log_len: usize,
+    operations_since_snapshot: usize,
+    replay_issues: Vec<TaganrogError>,
+    // read from the history on the first undo or redo, then kept up to date with the writes
+    undo_stack: Option<UndoStack>,
 
     // in-memory storage
     media_map: DashMap<MediaId, Media>,
     tags_map: DashMap<Tag, HashSet<MediaId>>,
+    // media whose file was missing at the last scan
+    missing_media: DashSet<MediaId>,
+    // media whose file content changed since it was recorded, as of the last scan
+    modified_media: DashSet<MediaId>,
+    // images that failed to decode, not hashed again until their file changes
+    undecodable_images: DashSet<MediaId>,
+    // locations of the media, for `name:` and `path:` searches
+    location_index: TrigramIndex,
+    // the media at each location, to find a media by its file
+    location_map: DashMap<String, MediaId>,
 }
 
 impl<T: Storage> TaganrogClient<T> {
     pub fn new(cfg: AppConfig, storage: T) -> Self {
         Self {
             cfg,
+            origin: Origin::default(),
             storage,
+            log_len: 0,
+            operations_since_snapshot: 0,
+            replay_issues: vec![],
+            undo_stack: None,
             media_map: DashMap::new(),
             tags_map: DashMap::new(),
+            missing_media: DashSet::new(),
+            modified_media: DashSet::new(),
+            undecodable_images: DashSet::new(),
+            location_index: TrigramIndex::default(),
+            location_map: DashMap::new(),
         }
     }
 
+    pub fn with_origin(mut self, origin: Origin) -> Self {
+        self.origin = origin;
+        self
+    }
+
     pub async fn init(&mut self) -> Result<(), TaganrogError> {
-        let operations = self.storage.read_all().await?;
+        self.load().await?;
+        if let Some(threshold) = self.cfg.auto_compact_threshold {
+            let redundant_operations = self.log_len.saturating_sub(self.get_compacted_len());
+            if redundant_operations >= threshold {
+                info!("auto-compacting db: {} redundant operations", redundant_operations);
+                self.compact().await?;
+            }
+        }
+        self.save_snapshot_if_due().await?;
+        Ok(())
+    }
+
+    /// (Re)builds the in-memory state from the storage.
+    async fn load(&mut self) -> Result<(), TaganrogError> {
+        let (maybe_snapshot, operations) = self.storage.read_latest().await?;
+        self.media_map.clear();
+        self.tags_map.clear();
+        self.location_index.clear();
+        self.location_map.clear();
+        self.undo_stack = None;
+        self.replay_issues = self.storage.take_replay_issues();
+        let tail_len = operations.iter().map(|x| x.operations_count()).sum();
+        self.log_len = tail_len;
+        if let Some(snapshot) = maybe_snapshot {
+            self.log_len += snapshot.operations;
+            for media in snapshot.media {
+                let location = self.cfg.library_roots.resolve(&media.location);
+                self.restore_media_in_memory(Media { location, ..media });
+            }
+        }
+        self.operations_since_snapshot = tail_len;
         for operation in operations {
-            match operation {
-                DbOperation::CreateMedia { media } => { self.create_media_in_memory(media); }
-                DbOperation::DeleteMedia { media_id } => { self.delete_media_in_memory(&media_id); }
-                DbOperation::AddTag { media_id, tag } => { self.add_tag_to_media_in_memory(&media_id, &tag); }
-                DbOperation::RemoveTag { media_id, tag } => { self.remove_tag_from_media_in_memory(&media_id, &tag); }
+            self.apply_operation_in_memory(operation);
+        }
+        Ok(())
+    }
+
+    /// Whether another process changed the storage since the last `sync`, cheap enough to poll.
+    pub async fn has_changes(&self) -> Result<bool, TaganrogError> {
+        self.storage.has_changes().await
+    }
+
+    /// Applies the changes other processes made to the storage since this client last read or wrote it.
+    /// Returns the number of applied operations.
+    pub async fn sync(&mut self) -> Result<usize, TaganrogError> {
+        match self.storage.read_changes().await? {
+            LogChanges::Appended(operations) => {
+                let operations_count = operations.iter().map(|x| x.operation.operations_count()).sum::<usize>();
+                self.log_len += operations_count;
+                self.operations_since_snapshot += operations_count;
+                for logged_operation in operations {
+                    self.apply_operation_in_memory(logged_operation.operation.clone());
+                    self.push_undo_stack(logged_operation);
+                }
+                Ok(operations_count)
+            },
+            LogChanges::Replaced => {
+                info!("db was rewritten by another process, reloading");
+                self.load().await?;
+                Ok(self.log_len)
+            },
+        }
+    }
+
+    fn apply_operation_in_memory(&mut self, operation: DbOperation) {
+        let operation = self.resolve_operation(operation);
+        match operation {
+            DbOperation::CreateMedia { media } => { self.restore_media_in_memory(media); }
+            DbOperation::DeleteMedia { media_id, .. } => { self.delete_media_in_memory(&media_id); }
+            DbOperation::AddTag { media_id, tag } => { self.add_tag_to_media_in_memory(&media_id, &tag); }
+            DbOperation::RemoveTag { media_id, tag } => { self.remove_tag_from_media_in_memory(&media_id, &tag); }
+            DbOperation::UpdateLocation { media_id, location, .. } => { self.update_location_in_memory(&media_id, location); }
+            DbOperation::UpdatePerceptualHash { media_id, perceptual_hash, .. } => { self.update_perceptual_hash_in_memory(&media_id, perceptual_hash); }
+            DbOperation::UpdateFileInfo { media_id, file_info, .. } => { self.update_file_info_in_memory(&media_id, file_info); }
+            DbOperation::UpdateVerifiedAt { media_id, verified_at, .. } => { self.update_verified_at_in_memory(&media_id, verified_at); }
+            DbOperation::Batch { operations } => {
+                for operation in operations {
+                    self.apply_operation_in_memory(operation);
+                }
+            }
+        }
+    }
+
+    /// The operation with absolute locations, as they are kept in memory.
+    fn resolve_operation(&self, operation: DbOperation) -> DbOperation {
+        operation.map_locations(&|x| self.cfg.library_roots.resolve(x))
+    }
+
+    /// The operation with locations relative to their library root, as they are stored.
+    fn to_stored_operation(&self, operation: DbOperation) -> DbOperation {
+        operation.map_locations(&|x| self.cfg.library_roots.to_stored(x))
+    }
+
+    fn to_stored_operations(&self, operations: Vec<DbOperation>) -> Vec<DbOperation> {
+        operations.into_iter().map(|x| self.to_stored_operation(x)).collect()
+    }
+
+    /// Snapshots the in-memory state once `snapshot_interval` operations were replayed or written since the last one,
+    /// so that the next start only has to replay the operations after it.
+    async fn save_snapshot_if_due(&mut self) -> Result<(), TaganrogError> {
+        let snapshot_interval = self.cfg.snapshot_interval;
+        if snapshot_interval == 0 || self.operations_since_snapshot < snapshot_interval {
+            return Ok(());
+        }
+        let media = self.media_map.iter()
+            .map(|x| Media { location: self.cfg.library_roots.to_stored(&x.value().location), ..x.value().clone() })
+            .collect();
+        let snapshot = Snapshot { operations: self.log_len, media };
+        self.storage.save_snapshot(snapshot).await?;
+        self.operations_since_snapshot = 0;
+        Ok(())
+    }
+
+    /// Problems (skipped or truncated lines) the storage recovered from while opening the DB.
+    pub fn get_replay_issues(&self) -> &[TaganrogError] {
+        &self.replay_issues
+    }
+
+    /// Rewrites the storage log so that it contains only the operations needed
+    /// to rebuild the current in-memory state (including untagged media).
+    pub async fn compact(&mut self) -> Result<CompactionResult, TaganrogError> {
+        self.sync().await?;
+        let state_meta = StateMeta::from_history(&self.storage.read_history().await?);
+        let operations = self.to_stored_state(self.get_media_in_creation_order(), &state_meta);
+        let operations_before = self.log_len;
+        let operations_after = operations.len();
+        self.storage.rewrite(operations).await?;
+        self.undo_stack = None;
+        self.log_len = operations_after;
+        self.operations_since_snapshot = operations_after;
+        Ok(CompactionResult { operations_before, operations_after })
+    }
+
+    /// The operations that recreate the media, with stored locations and the meta of the changes they stand for.
+    fn to_stored_state(&self, media_vec: Vec<Media>, state_meta: &StateMeta) -> Vec<LoggedOperation> {
+        let media_vec = media_vec.into_iter()
+            .map(|x| Media { location: self.cfg.library_roots.to_stored(&x.location), ..x });
+        state_meta.media_to_operations(media_vec)
+    }
+
+    fn get_media_in_creation_order(&self) -> Vec<Media> {
+        self.media_map.iter()
+            .map(|x| x.value().clone())
+            .sorted_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)))
+            .collect()
+    }
+
+    /// Reverts the latest change that wasn't undone yet by writing the operations compensating it.
+    /// Returns the reverted change, `None` if there's nothing to undo.
+    pub async fn undo(&mut self) -> Result<Option<DbOperation>, TaganrogError> {
+        self.sync().await?;
+        let Some(operation) = self.get_undo_stack().await?.undo.last().cloned() else {
+            return Ok(None);
+        };
+        let inverse = operation.inverse()
+            .ok_or_else(|| TaganrogError::CannotUndo("the deleted media was not recorded".to_string()))?;
+        self.apply_operation_in_memory(inverse.clone());
+        self.write_change(inverse, ChangeKind::Undo).await?;
+        Ok(Some(self.resolve_operation(operation)))
+    }
+
+    /// Applies again the latest undone change, as long as no other change was made after the undo.
+    /// Returns the repeated change, `None` if there's nothing to redo.
+    pub async fn redo(&mut self) -> Result<Option<DbOperation>, TaganrogError> {
+        self.sync().await?;
+        let Some(operation) = self.get_undo_stack().await?.redo.last().cloned() else {
+            return Ok(None);
+        };
+        self.apply_operation_in_memory(operation.clone());
+        self.write_change(operation.clone(), ChangeKind::Redo).await?;
+        Ok(Some(self.resolve_operation(operation)))
+    }
+
+    async fn get_undo_stack(&mut self) -> Result<&UndoStack, TaganrogError> {
+        if self.undo_stack.is_none() {
+            let history = self.storage.read_history().await?;
+            self.undo_stack = Some(UndoStack::from_history(history));
+        }
+        Ok(self.undo_stack.as_ref().unwrap())
+    }
+
+    fn push_undo_stack(&mut self, logged_operation: LoggedOperation) {
+        if let Some(undo_stack) = &mut self.undo_stack {
+            undo_stack.push(logged_operation);
+        }
+    }
+
+    /// Returns the past changes, oldest first, with batches unpacked (their operations share the batch's meta),
+    /// each paired with the number of the log entry it belongs to, starting at 1.
+    /// Before the last rewrite, only the changes that made the state it kept are left, the others are only in the backup.
+    pub async fn get_history(&mut self) -> Result<Vec<(usize, LoggedOperation)>, TaganrogError> {
+        let mut history = Vec::new();
+        for (index, logged_operation) in self.storage.read_history().await?.into_iter().enumerate() {
+            let mut operations = Vec::new();
+            unpack_batches(self.resolve_operation(logged_operation.operation), &logged_operation.meta, &mut operations);
+            history.extend(operations.into_iter().map(|x| (index + 1, x)));
+        }
+        Ok(history)
+    }
+
+    /// Rebuilds the state the DB was in at `point` by replaying the log up to it and, unless `dry_run`,
+    /// rewrites the storage with it like `compact` does. A rewrite keeps when the media and tags it kept were added,
+    /// but what it dropped can't be restored. See `is_up_to` for the changes without a timestamp.
+    pub async fn restore(&mut self, point: RestorePoint, dry_run: bool) -> Result<RestoreResult, TaganrogError> {
+        self.sync().await?;
+        let history = self.storage.read_history().await?;
+        let history_len = history.len();
+        let mut restored = TaganrogClient::new(self.cfg.clone(), InMemoryStorage::default());
+        let mut restored_meta = StateMeta::default();
+        let mut changes_kept = 0;
+        let is_up_to = is_up_to(&history, point)?;
+        for (logged_operation, is_kept) in history.into_iter().zip(is_up_to) {
+            if !is_kept {
+                continue;
+            }
+            restored_meta.record(&logged_operation.operation, &logged_operation.meta);
+            restored.apply_operation_in_memory(logged_operation.operation);
+            changes_kept += 1;
+        }
+
+        let media_ids = self.media_map.iter().map(|x| x.key().clone())
+            .chain(restored.media_map.iter().map(|x| x.key().clone()))
+            .unique()
+            .sorted();
+        let diff = media_ids
+            .filter_map(|x| diff_media(self.get_media_by_id(&x), restored.get_media_by_id(&x)))
+            .collect::<Vec<DbOperation>>();
+        let result = RestoreResult { changes_kept, changes_dropped: history_len - changes_kept, diff };
+        if dry_run {
+            return Ok(result);
+        }
+
+        let operations = self.to_stored_state(restored.get_media_in_creation_order(), &restored_meta);
+        self.storage.rewrite(operations).await?;
+        self.load().await?;
+        Ok(result)
+    }
+
+    /// Whether the DB is encrypted and has to be unlocked before it can be loaded.
+    pub async fn is_locked(&mut self) -> Result<bool, TaganrogError> {
+        self.storage.is_locked().await
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.storage.cipher().is_some()
+    }
+
+    /// Unlocks an encrypted DB with the passphrase and loads it.
+    pub async fn unlock(&mut self, passphrase: &str) -> Result<(), TaganrogError> {
+        self.storage.unlock(passphrase).await?;
+        self.init().await
+    }
+
+    /// Encrypts the DB with a key derived from `passphrase`, or decrypts it if `None`.
+    /// Thumbnails are encrypted as well when `encrypt_thumbnails` is set, returns how many were converted.
+    /// They are converted next to the old ones before the DB is rewritten, so that a failure leaves both as they were.
+    pub async fn set_encryption(&mut self, passphrase: Option<&str>) -> Result<usize, TaganrogError> {
+        self.sync().await?;
+        let old_cipher = self.storage.cipher().cloned();
+        let new_cipher = passphrase.map(Cipher::create).transpose()?;
+        let mut staged_thumbnails = Vec::new();
+        let mut result = self.stage_thumbnails(old_cipher.as_ref(), new_cipher.as_ref(), &mut staged_thumbnails).await;
+        if result.is_ok() {
+            result = self.storage.set_encryption(passphrase, new_cipher).await;
+        }
+        if let Err(e) = result {
+            for (staged_path, _, _) in staged_thumbnails {
+                let _ = tokio::fs::remove_file(staged_path).await;
             }
+            return Err(e);
+        }
+        self.operations_since_snapshot = self.log_len;
+
+        for (staged_path, path, old_path) in staged_thumbnails.iter() {
+            tokio::fs::rename(staged_path, path).await.map_err(TaganrogError::FileWriteError)?;
+            if old_path != path {
+                tokio::fs::remove_file(old_path).await.map_err(TaganrogError::FileWriteError)?;
+            }
+        }
+        Ok(staged_thumbnails.len())
+    }
+
+    /// Writes each thumbnail stored with `old_cipher` the way it's stored with `new_cipher` to a temporary file,
+    /// collecting the temporary file, the path it's meant for and the path of the old thumbnail.
+    async fn stage_thumbnails(&self, old_cipher: Option<&Cipher>, new_cipher: Option<&Cipher>, staged_thumbnails: &mut Vec<(PathBuf, PathBuf, PathBuf)>) -> Result<(), TaganrogError> {
+        for media_id in self.media_map.iter().map(|x| x.key().clone()).collect::<Vec<MediaId>>() {
+            let png_path = self.cfg.thumbnails_dir.join(format!("{}.png", media_id));
+            let encrypted_path = self.cfg.thumbnails_dir.join(format!("{}.png.enc", media_id));
+            let (png_bytes, old_path) = match old_cipher {
+                Some(old_cipher) if encrypted_path.exists() => {
+                    let bytes = tokio::fs::read(&encrypted_path).await.map_err(TaganrogError::FileReadError)?;
+                    (old_cipher.decrypt(&bytes).map_err(TaganrogError::DbEncryptionError)?, encrypted_path.clone())
+                },
+                None if new_cipher.is_some() && self.cfg.encrypt_thumbnails && png_path.exists() => {
+                    (tokio::fs::read(&png_path).await.map_err(TaganrogError::FileReadError)?, png_path.clone())
+                },
+                _ => continue,
+            };
+            let (path, bytes) = match new_cipher {
+                Some(cipher) => (encrypted_path, cipher.encrypt(&png_bytes)),
+                None => (png_path, png_bytes),
+            };
+            let staged_path = self.cfg.thumbnails_dir.join(format!("{}.png.tmp", media_id));
+            staged_thumbnails.push((staged_path.clone(), path, old_path));
+            tokio::fs::write(&staged_path, bytes).await.map_err(TaganrogError::FileWriteError)?;
+        }
+        Ok(())
+    }
+
+    fn get_compacted_len(&self) -> usize {
+        self.media_map.iter().map(|x| 1 + x.value().tags.len()).sum()
+    }
+
+    async fn write_operation(&mut self, operation: DbOperation) -> Result<(), TaganrogError> {
+        self.write_change(operation, ChangeKind::Edit).await
+    }
+
+    async fn write_change(&mut self, operation: DbOperation, kind: ChangeKind) -> Result<(), TaganrogError> {
+        let operations_count = operation.operations_count();
+        let meta = OperationMeta { kind, ..OperationMeta::now(self.origin) };
+        let logged_operation = LoggedOperation { operation: self.to_stored_operation(operation), meta };
+        self.storage.write(logged_operation.clone()).await?;
+        self.on_written(operations_count, Some(logged_operation)).await
+    }
+
+    async fn write_batch(&mut self, operations: Vec<DbOperation>) -> Result<(), TaganrogError> {
+        self.write_batch_of_kind(operations, ChangeKind::Edit).await
+    }
+
+    async fn write_batch_of_kind(&mut self, operations: Vec<DbOperation>, kind: ChangeKind) -> Result<(), TaganrogError> {
+        let operations_count = operations.iter().map(|x| x.operations_count()).sum();
+        let operations = self.to_stored_operations(operations);
+        let meta = OperationMeta { kind, ..OperationMeta::now(self.origin) };
+        self.storage.write_batch(operations.clone(), meta.clone()).await?;
+        let logged_operation = DbOperation::batch(operations).map(|operation| LoggedOperation { operation, meta });
+        self.on_written(operations_count, logged_operation).await
+    }
+
+    async fn on_written(&mut self, operations_count: usize, logged_operation: Option<LoggedOperation>) -> Result<(), TaganrogError> {
+        self.log_len += operations_count;
+        self.operations_since_snapshot += operations_count;
+        // other processes might have written before us, their changes come first in the undo stack
+        if let Err(e) = self.sync().await {
+            self.undo_stack = None;
+            return Err(e);
         }
+        if let Some(logged_operation) = logged_operation {
+            self.push_undo_stack(logged_operation);
+        }
+        self.save_snapshot_if_due().await?;
         Ok(())
     }
 
@@ -80,14 +463,19 @@ impl<T: Storage> TaganrogClient<T> {
         maybe_media
     }
 
-    pub fn get_all_media(&self, page_size: usize, page_index: usize) -> MediaPage {
-        let start = Instant::now();
-        let media_vec = self.media_map.iter()
-            .map(|x| x.value().clone())
-            .skip(page_index * page_size).take(page_size)
-            .collect();
-        let total_count = self.media_map.len();
+    /// Sorts the media by their sort key and clones only the ones of the page.
+    fn create_media_page(&self, media_ids: Vec<MediaId>, sort: MediaSort, page_size: usize, page_index: usize, start: Instant) -> MediaPage {
+        let sorted_ids = media_ids.into_iter()
+            .filter_map(|id| Some((sort.key(self.media_map.get(&id)?.value()), id)))
+            .sorted()
+            .collect::<Vec<(MediaSortKey, MediaId)>>();
+        let total_count = sorted_ids.len();
         let total_pages = (total_count as f64 / page_size as f64).ceil() as usize;
+        let media_vec = sorted_ids.into_iter()
+            .skip(page_index.saturating_mul(page_size))
+            .take(page_size)
+            .filter_map(|(_, id)| self.get_media_by_id(&id))
+            .collect();
         let elapsed = start.elapsed();
         MediaPage {
             media_vec,
@@ -99,80 +487,259 @@ impl<T: Storage> TaganrogClient<T> {
         }
     }
 
-    pub fn get_media_without_thumbnail(&self, page_size: usize, page_index: usize) -> MediaPage {
+    pub fn get_all_media(&self, sort: MediaSort, page_size: usize, page_index: usize) -> MediaPage {
         let start = Instant::now();
-        let media = self.media_map.iter()
-            .filter(|x| !Path::new(&self.cfg.thumbnails_dir).join(format!("{}.png", &x.value().id)).exists())
-            .map(|x| x.key().clone()).collect::<Vec<MediaId>>();
-        let media_vec = media.iter()
-            .skip(page_index * page_size).take(page_size)
-            .map(|x| self.get_media_by_id(x).unwrap()).collect();
-        let total_count = media.len();
-        let total_pages = (total_count as f64 / page_size as f64).ceil() as usize;
-        let elapsed = start.elapsed();
-        MediaPage {
-            media_vec,
-            page_index,
-            page_size,
-            total_count,
-            total_pages,
-            elapsed: elapsed.as_millis() as u64,
+        let media_ids = self.media_map.iter().map(|x| x.key().clone()).collect();
+        self.create_media_page(media_ids, sort, page_size, page_index, start)
+    }
+
+    pub fn get_media_without_thumbnail(&self, sort: MediaSort, page_size: usize, page_index: usize) -> MediaPage {
+        let start = Instant::now();
+        let media_ids = self.media_map.iter()
+            .filter(|x| !self.has_thumbnail(x.key()))
+            .map(|x| x.key().clone())
+            .collect();
+        self.create_media_page(media_ids, sort, page_size, page_index, start)
+    }
+
+    /// Checks that the file of every media still exists. Returns the media whose file is missing.
+    /// Media under an offline library root are not missing, their drive might just be unplugged.
+    pub async fn scan_missing_media(&self) -> Vec<Media> {
+        let missing_media = Self::find_missing_media(self.get_media_to_check()).await;
+        self.set_missing_media(&missing_media);
+        missing_media
+    }
+
+    /// The media whose file doesn't exist, in the given order.
+    /// It doesn't need the client, so that the client isn't held while the files are looked up.
+    pub async fn find_missing_media(media_vec: Vec<Media>) -> Vec<Media> {
+        let result = tokio::task::spawn_blocking(move || media_vec.into_iter()
+            .filter(|x| !Path::new(&x.location).is_file())
+            .collect::<Vec<Media>>()).await;
+        match result {
+            Ok(missing_media) => missing_media,
+            Err(e) => {
+                warn!("failed to look for missing files: {}", e);
+                vec![]
+            },
+        }
+    }
+
+    /// Remembers the missing media found by `find_missing_media`, except those deleted since.
+    pub fn set_missing_media(&self, missing_media: &[Media]) {
+        self.missing_media.clear();
+        for media in missing_media.iter().filter(|x| self.media_map.contains_key(&x.id)) {
+            self.missing_media.insert(media.id.clone());
         }
     }
 
-    pub fn get_untagged_media(&self, page_size: usize, page_index: usize) -> MediaPage {
+    /// Whether the file of the media was missing at the last `scan_missing_media`.
+    pub fn is_media_missing(&self, media_id: &MediaId) -> bool {
+        self.missing_media.contains(media_id)
+    }
+
+    /// Returns the media found missing by the last `scan_missing_media`.
+    pub fn get_missing_media(&self, sort: MediaSort, page_size: usize, page_index: usize) -> MediaPage {
         let start = Instant::now();
-        let media = self.media_map.iter()
-            .filter(|x| x.value().tags.is_empty())
-            .map(|x| x.key().clone()).collect::<Vec<MediaId>>();
-        let media_vec = media.iter()
-            .skip(page_index * page_size).take(page_size)
-            .map(|x| self.get_media_by_id(x).unwrap()).collect();
-        let total_count = self.media_map.iter().filter(|x| x.value().tags.is_empty()).count();
-        let total_pages = (total_count as f64 / page_size as f64).ceil() as usize;
-        let elapsed = start.elapsed();
-        MediaPage {
-            media_vec,
-            page_index,
-            page_size,
-            total_count,
-            total_pages,
-            elapsed: elapsed.as_millis() as u64,
+        let media_ids = self.missing_media.iter().map(|x| x.key().clone()).collect();
+        self.create_media_page(media_ids, sort, page_size, page_index, start)
+    }
+
+    /// Compares the file of every media with what was recorded about it. Returns the media whose content changed.
+    /// Without a recorded content hash any change of size or modification time counts as a change of content.
+    /// Missing files and media under an offline library root are skipped.
+    pub async fn scan_modified_media(&self) -> Vec<Media> {
+        let modified_media = Self::check_files(self.get_media_to_check(), self.cfg.hash_content).await.into_iter()
+            .filter(|(_, x)| *x == FileCheck::Modified)
+            .map(|(x, _)| x)
+            .collect::<Vec<Media>>();
+        self.set_modified_media(&modified_media);
+        modified_media
+    }
+
+    /// Like `scan_modified_media`, but also records the file info of the media that have none yet
+    /// (added by an older version) and of the files that were only touched (same content hash).
+    pub async fn rescan_media(&mut self) -> Result<RescanResult, TaganrogError> {
+        let file_checks = Self::check_files(self.get_media_to_check(), self.cfg.hash_content).await;
+        self.record_file_checks(file_checks, ChangeKind::Edit).await
+    }
+
+    /// Remembers the modified media of the checks and records the file info of the unrecorded and touched ones, all at once.
+    /// A background scan records them as `ChangeKind::Background`, so that they don't land on the user's undo stack.
+    pub async fn record_file_checks(&mut self, file_checks: Vec<(Media, FileCheck)>, kind: ChangeKind) -> Result<RescanResult, TaganrogError> {
+        let mut modified = Vec::new();
+        let mut operations = Vec::new();
+        for (media, file_check) in file_checks {
+            match file_check {
+                FileCheck::Unchanged => {},
+                FileCheck::Modified => {
+                    self.undecodable_images.remove(&media.id);
+                    modified.push(media);
+                },
+                FileCheck::Unrecorded(file_info) | FileCheck::Touched(file_info) => {
+                    self.undecodable_images.remove(&media.id);
+                    if let Some(previous) = self.update_file_info_in_memory(&media.id, file_info.clone()) {
+                        operations.push(DbOperation::UpdateFileInfo { media_id: media.id.clone(), file_info, previous: Some(previous) });
+                    }
+                },
+            }
+        }
+        self.set_modified_media(&modified);
+        let recorded = operations.len();
+        self.write_batch_of_kind(operations, kind).await?;
+        Ok(RescanResult { modified, recorded })
+    }
+
+    fn set_modified_media(&self, modified_media: &[Media]) {
+        self.modified_media.clear();
+        for media in modified_media.iter() {
+            self.modified_media.insert(media.id.clone());
+        }
+    }
+
+    /// The media whose file can be checked, the ones under an offline library root can't.
+    pub fn get_media_to_check(&self) -> Vec<Media> {
+        self.media_map.iter()
+            .filter(|x| !self.cfg.library_roots.is_offline(&x.value().location))
+            .map(|x| x.value().clone())
+            .sorted_by(|a, b| a.location.cmp(&b.location))
+            .collect()
+    }
+
+    /// Compares the files with what was recorded about the media, hashing the files without a recorded file info with `hash_content`.
+    /// It doesn't need the client, so that the client isn't held while the files are read.
+    pub async fn check_files(media_vec: Vec<Media>, hash_content: bool) -> Vec<(Media, FileCheck)> {
+        let mut checked_media = Vec::new();
+        for media in media_vec {
+            if let Some(file_check) = Self::check_file(&media, hash_content).await {
+                checked_media.push((media, file_check));
+            }
+        }
+        checked_media
+    }
+
+    /// `None` if the file is missing.
+    async fn check_file(media: &Media, hash_content: bool) -> Option<FileCheck> {
+        let metadata = std::fs::metadata(&media.location).ok().filter(|x| x.is_file())?;
+        let file_info = FileInfo { size: metadata.len() as i64, modified_at: modified_at_of(&metadata), content_hash: media.content_hash.clone() };
+        if media.modified_at.is_none() {
+            let content_hash = match hash_content {
+                true => Self::compute_content_hash(&media.location).await.ok(),
+                false => None,
+            };
+            return Some(FileCheck::Unrecorded(FileInfo { content_hash, ..file_info }));
+        }
+        if file_info == media.file_info() {
+            return Some(FileCheck::Unchanged);
         }
+        if let Some(content_hash) = media.content_hash.as_ref().filter(|_| file_info.size == media.size) {
+            // a file that can't be read anymore counts as modified, it's there but not as it was recorded
+            if Self::compute_content_hash(&media.location).await.ok().as_ref() == Some(content_hash) {
+                return Some(FileCheck::Touched(file_info));
+            }
+        }
+        Some(FileCheck::Modified)
+    }
+
+    /// Whether the content of the file changed according to the last `scan_modified_media`.
+    pub fn is_media_modified(&self, media_id: &MediaId) -> bool {
+        self.modified_media.contains(media_id)
+    }
+
+    /// Returns the media found modified by the last `scan_modified_media`.
+    pub fn get_modified_media(&self, sort: MediaSort, page_size: usize, page_index: usize) -> MediaPage {
+        let start = Instant::now();
+        let media_ids = self.modified_media.iter().map(|x| x.key().clone()).collect();
+        self.create_media_page(media_ids, sort, page_size, page_index, start)
+    }
+
+    pub fn get_media_by_location(&self, location: &str) -> Option<Media> {
+        let media_id = self.location_map.get(location)?;
+        self.get_media_by_id(media_id.value())
     }
 
-    pub fn search_media(&self, query: &str, page_size: usize, page_index: usize) -> MediaPage {
+    pub fn get_untagged_media(&self, sort: MediaSort, page_size: usize, page_index: usize) -> MediaPage {
         let start = Instant::now();
-        if query.is_empty() {
-            return MediaPage::default();
-        }
-        let query_arr = query.split(' ').collect::<Vec<&str>>();
-        if query_arr.is_empty() {
-            return MediaPage::default();
-        }
-        let exact_match_tags = query_arr.iter()
-            .map(|x| x.to_string())
-            .collect::<Vec<Tag>>();
-        let has_unknown_tag = exact_match_tags.iter().any(|x| !self.tags_map.contains_key(x));
-        if has_unknown_tag {
-            return MediaPage::default();
-        }
-        let intersection = self.get_media_intersection(&exact_match_tags);
-        let media_vec = intersection.iter()
-            .sorted_by_key(|x| self.media_map.get(*x).unwrap().created_at).rev()
-            .skip(page_index * page_size).take(page_size)
-            .map(|x| self.get_media_by_id(x).unwrap())
+        let media_ids = self.media_map.iter()
+            .filter(|x| x.value().tags.is_empty())
+            .map(|x| x.key().clone())
             .collect();
-        let elapsed = start.elapsed();
-        let total_count = intersection.len();
-        let total_pages = (total_count as f64 / page_size as f64).ceil() as usize;
-        MediaPage {
-            media_vec,
-            page_index,
-            page_size,
-            total_count,
-            total_pages,
-            elapsed: elapsed.as_millis() as u64,
+        self.create_media_page(media_ids, sort, page_size, page_index, start)
+    }
+
+    /// Returns the media matching the query (see `QueryExpr`).
+    /// The virtual tags (`all`, `missing`...) can be used as terms.
+    pub fn search_media(&self, query: &str, sort: MediaSort, page_size: usize, page_index: usize) -> Result<MediaPage, TaganrogError> {
+        let start = Instant::now();
+        let Some(query) = QueryExpr::parse(query)? else {
+            return Ok(MediaPage::default());
+        };
+        let media_ids = self.evaluate_query(&query).into_iter().collect();
+        Ok(self.create_media_page(media_ids, sort, page_size, page_index, start))
+    }
+
+    /// The ids of the media matching the query.
+    fn evaluate_query(&self, query: &QueryExpr) -> HashSet<MediaId> {
+        match query {
+            QueryExpr::Term(tag) => self.evaluate_term(tag),
+            QueryExpr::Filter(filter) => self.evaluate_filter(filter),
+            QueryExpr::Not(expr) => {
+                let excluded = self.evaluate_query(expr);
+                self.media_map.iter().map(|x| x.key().clone()).filter(|x| !excluded.contains(x)).collect()
+            },
+            QueryExpr::And(exprs) => {
+                // intersect the positive operands first, then check the filters and subtract the negated operands
+                let (negated, positive): (Vec<&QueryExpr>, Vec<&QueryExpr>) = exprs.iter().partition(|x| matches!(x, QueryExpr::Not(_)));
+                let (filters, positive): (Vec<&QueryExpr>, Vec<&QueryExpr>) = positive.into_iter().partition(|x| matches!(x, QueryExpr::Filter(_)));
+                let filters = filters.into_iter().filter_map(|x| match x {
+                    QueryExpr::Filter(filter) => Some(filter),
+                    _ => None,
+                }).collect::<Vec<&Filter>>();
+                let mut media_ids = match positive.split_first() {
+                    Some((first, rest)) => rest.iter().fold(self.evaluate_query(first), |media_ids, x| {
+                        let other_ids = self.evaluate_query(x);
+                        media_ids.into_iter().filter(|x| other_ids.contains(x)).collect()
+                    }),
+                    // a text filter can use the index instead of going through all media
+                    None => match filters.iter().find(|x| matches!(x, Filter::Name(_) | Filter::Path(_))) {
+                        Some(filter) => self.evaluate_filter(filter),
+                        None => self.media_map.iter().map(|x| x.key().clone()).collect(),
+                    },
+                };
+                if !filters.is_empty() {
+                    media_ids.retain(|x| self.media_map.get(x).is_some_and(|x| filters.iter().all(|filter| filter.matches(x.value()))));
+                }
+                for expr in negated {
+                    let QueryExpr::Not(expr) = expr else { continue };
+                    let excluded = self.evaluate_query(expr);
+                    media_ids.retain(|x| !excluded.contains(x));
+                }
+                media_ids
+            },
+            QueryExpr::Or(exprs) => exprs.iter().flat_map(|x| self.evaluate_query(x)).collect(),
+        }
+    }
+
+    fn evaluate_filter(&self, filter: &Filter) -> HashSet<MediaId> {
+        let candidates = match filter {
+            Filter::Name(term) | Filter::Path(term) => self.location_index.candidates(term, max_typos(term)),
+            _ => None,
+        };
+        match candidates {
+            Some(media_ids) => media_ids.into_iter()
+                .filter(|x| self.media_map.get(x).is_some_and(|x| filter.matches(x.value())))
+                .collect(),
+            None => self.media_map.iter().filter(|x| filter.matches(x.value())).map(|x| x.key().clone()).collect(),
+        }
+    }
+
+    fn evaluate_term(&self, tag: &Tag) -> HashSet<MediaId> {
+        match tag.as_str() {
+            "all" => self.media_map.iter().map(|x| x.key().clone()).collect(),
+            "no-thumbnail" => self.media_map.iter().filter(|x| !self.has_thumbnail(x.key())).map(|x| x.key().clone()).collect(),
+            "missing" => self.missing_media.iter().map(|x| x.key().clone()).collect(),
+            "modified" => self.modified_media.iter().map(|x| x.key().clone()).collect(),
+            _ => self.tags_map.get(tag).map(|x| x.value().clone()).unwrap_or_default(),
         }
     }
 
@@ -181,7 +748,7 @@ impl<T: Storage> TaganrogClient<T> {
             .map(|x| {
                 let media_count = x.value().len();
                 TagsAutocomplete {
-                    head: vec![],
+                    head: String::new(),
                     last: x.key().clone(),
                     media_count,
                 }
@@ -192,48 +759,125 @@ impl<T: Storage> TaganrogClient<T> {
             .collect()
     }
 
+    /// The tags of the media matching the query with the number of those media having them, the tags of all
+    /// media if the query is blank. The tags the query is made of are left out.
+    pub fn get_query_tags(&self, query: &str) -> Result<Vec<TagsAutocomplete>, TaganrogError> {
+        let Some(query) = QueryExpr::parse(query)? else {
+            return Ok(self.get_all_tags());
+        };
+        let terms = query.terms();
+        let tag_counts = self.evaluate_query(&query).iter()
+            .filter_map(|x| self.media_map.get(x))
+            .flat_map(|x| x.tags.clone())
+            .filter(|x| !terms.contains(&x))
+            .counts();
+        let tags = tag_counts.into_iter()
+            .map(|(tag, media_count)| TagsAutocomplete { head: String::new(), last: tag, media_count })
+            .sorted_by(|a, b| b.media_count.cmp(&a.media_count).then_with(|| a.last.cmp(&b.last)))
+            .collect();
+        Ok(tags)
+    }
+
+    /// Completes the last term of the query with the tags matching it (see `tag_match_quality`), the best matches
+    /// first and then those matching the most media. The count of a tag is how many media the whole query matches
+    /// when completed with it.
     pub fn autocomplete_tags(&self, query: &str, max_items: usize) -> Vec<TagsAutocomplete> {
-        if query.is_empty() {
+        let Some((head, last_term)) = split_last_term(query) else {
             return vec![];
-        }
-        let query_arr = query.split(' ').collect::<Vec<&str>>();
-        if query_arr.is_empty() {
+        };
+        let last_term = last_term.slugify();
+        if last_term.is_empty() {
             return vec![];
         }
-        let exact_match_tags = query_arr.iter()
-            .take(query_arr.len() - 1)
-            .map(|x| x.to_string())
-            .collect::<Vec<Tag>>();
-        let has_unknown_tag = exact_match_tags.iter().any(|x| !self.tags_map.contains_key(x));
-        if has_unknown_tag {
-            return vec![];
+        // the query is usually typed before its parentheses are closed
+        let unclosed_parentheses = head.matches('(').count().saturating_sub(head.matches(')').count());
+        let closing = ")".repeat(unclosed_parentheses);
+        let head_terms = QueryExpr::parse(&format!("{}{}", head, closing)).ok().flatten()
+            .map(|x| x.terms().into_iter().cloned().collect::<Vec<Tag>>())
+            .unwrap_or_default();
+        let head_media = self.evaluate_head(head, &last_term, &closing);
+        let media_count = |tag: &Tag| match &head_media {
+            Some(HeadMedia::With(media_ids)) => self.evaluate_term(tag).iter().filter(|x| media_ids.contains(*x)).count(),
+            Some(HeadMedia::Without(media_ids)) => media_ids.len() - self.evaluate_term(tag).iter().filter(|x| media_ids.contains(*x)).count(),
+            None => QueryExpr::parse(&format!("{}{}{}", head, tag, closing)).ok().flatten()
+                .map(|x| self.evaluate_query(&x).len())
+                .unwrap_or_default(),
+        };
+        let tags_by_quality = self.tags_map.iter()
+            .filter(|x| !head_terms.contains(x.key()))
+            .filter_map(|x| Some((tag_match_quality(x.key(), &last_term)?, x.key().clone())))
+            .into_group_map();
+        // the media are only counted for the qualities that make it into the list
+        let mut autocompletes = Vec::new();
+        for (_, tags) in tags_by_quality.into_iter().sorted_by_key(|(quality, _)| *quality) {
+            if autocompletes.len() >= max_items {
+                break;
+            }
+            let ranked_autocompletes = tags.into_iter()
+                .map(|tag| TagsAutocomplete { head: head.to_string(), media_count: media_count(&tag), last: tag })
+                .filter(|x| x.media_count > 0)
+                .sorted_by(|a, b| b.media_count.cmp(&a.media_count).then_with(|| a.last.cmp(&b.last)))
+                .take(max_items - autocompletes.len())
+                .collect::<Vec<TagsAutocomplete>>();
+            autocompletes.extend(ranked_autocompletes);
         }
-        let last_tag = query_arr.last().unwrap().to_string();
-        let possible_last_tags = self.tags_map.iter()
-            .filter(|x| x.key().starts_with(&last_tag))
-            .filter(|x| !exact_match_tags.contains(x.key()))
-            .map(|x| x.key().clone())
-            .collect::<Vec<Tag>>();
-        let matching_media_ids = if exact_match_tags.is_empty() {
-            self.media_map.iter().map(|x| x.key().clone()).collect()
-        } else {
-            self.get_media_intersection(&exact_match_tags)
+        autocompletes
+    }
+
+    /// The media of the head of a query being completed, when the completed query is the head and the last term,
+    /// so that the media of each completion are counted without evaluating the whole query again.
+    /// `None` if the last term is part of a larger expression, e.g. after an `OR`.
+    fn evaluate_head(&self, head: &str, last_term: &Tag, closing: &str) -> Option<HeadMedia> {
+        let completed_query = QueryExpr::parse(&format!("{}{}{}", head, last_term, closing)).ok().flatten()?;
+        if completed_query.terms().into_iter().filter(|x| *x == last_term).count() != 1 {
+            return None;
+        }
+        let (head_exprs, last_expr) = match completed_query {
+            QueryExpr::And(mut exprs) => {
+                let last_expr = exprs.pop()?;
+                (exprs, last_expr)
+            },
+            expr => (vec![], expr),
         };
-        let autocomplete = possible_last_tags.iter()
-            .map(|x| {
-                let media_ids = self.tags_map.get(x).map(|x| x.value().clone()).unwrap_or_default();
-                let count = media_ids.intersection(&matching_media_ids).count();
-                TagsAutocomplete {
-                    head: exact_match_tags.clone(),
-                    last: x.clone(),
-                    media_count: count,
-                }
+        let head_media_ids = match head_exprs.is_empty() {
+            true => self.media_map.iter().map(|x| x.key().clone()).collect(),
+            false => self.evaluate_query(&QueryExpr::And(head_exprs)),
+        };
+        match last_expr {
+            QueryExpr::Term(tag) if tag == *last_term => Some(HeadMedia::With(head_media_ids)),
+            QueryExpr::Not(expr) if *expr == QueryExpr::Term(last_term.clone()) => Some(HeadMedia::Without(head_media_ids)),
+            _ => None,
+        }
+    }
+
+    /// The query with its unknown tags replaced by the closest known ones, for a "did you mean" on a search
+    /// that found nothing. `None` if no tag could be replaced or the new query finds nothing either.
+    pub fn suggest_query(&self, query: &str) -> Option<String> {
+        let query = QueryExpr::parse(query).ok().flatten()?;
+        let mut is_changed = false;
+        let suggestion = query.map_terms(&mut |tag| {
+            let is_known = is_virtual_tag(tag) || self.tags_map.get(tag).is_some_and(|x| !x.is_empty());
+            match is_known {
+                true => tag.clone(),
+                false => self.closest_tag(tag).inspect(|_| is_changed = true).unwrap_or_else(|| tag.clone()),
+            }
+        });
+        if !is_changed || self.evaluate_query(&suggestion).is_empty() {
+            return None;
+        }
+        Some(suggestion.to_string())
+    }
+
+    /// The tag the typed one most likely meant, a tag it's only a part of in the middle of a word doesn't count.
+    fn closest_tag(&self, typed: &str) -> Option<Tag> {
+        self.tags_map.iter()
+            .filter(|x| !x.value().is_empty())
+            .filter_map(|x| {
+                let quality = tag_match_quality(x.key(), typed).filter(|x| *x != INFIX_MATCH)?;
+                Some((quality, std::cmp::Reverse(x.value().len()), x.key().clone()))
             })
-            .sorted_by_key(|x| x.media_count).rev()
-            .take(max_items)
-            .filter(|x| x.media_count > 0)
-            .collect();
-        autocomplete
+            .min()
+            .map(|(_, _, tag)| tag)
     }
 
     pub fn export_db_operations(&self) -> Vec<DbOperation> {
@@ -256,16 +900,33 @@ impl<T: Storage> TaganrogClient<T> {
         if self.media_map.contains_key(&id) {
             return InsertResult::Existing(media);
         }
+        self.location_index.insert(&id, &media.location);
+        self.location_map.insert(media.location.clone(), id.clone());
         self.media_map.insert(id, media.clone());
         InsertResult::New(media)
     }
 
+    fn restore_media_in_memory(&mut self, mut media: Media) {
+        let media_tags = std::mem::take(&mut media.tags);
+        let media_id = media.id.clone();
+        if let InsertResult::Existing(_) = self.create_media_in_memory(media) {
+            return;
+        }
+        for tag in media_tags.iter() {
+            self.add_tag_to_media_in_memory(&media_id, tag);
+        }
+    }
+
     fn delete_media_in_memory(&mut self, media_id: &MediaId) -> Option<Media> {
         let maybe_media = self.media_map.remove(media_id);
         if maybe_media.is_none() {
             return None;
         }
         let media = maybe_media.unwrap().1;
+        self.location_index.remove(media_id, &media.location);
+        self.location_map.remove_if(&media.location, |_, x| x == media_id);
+        self.missing_media.remove(media_id);
+        self.modified_media.remove(media_id);
         for tag in media.tags.iter() {
             let mut entry = self.tags_map.entry(tag.clone()).or_default();
             entry.value_mut().remove(media_id);
@@ -300,13 +961,60 @@ impl<T: Storage> TaganrogClient<T> {
         false
     }
 
-    pub async fn create_media_from_file(&self, abs_path: &PathBuf) -> Result<Media, TaganrogError> {
+    fn update_location_in_memory(&mut self, media_id: &MediaId, location: String) -> Option<String> {
+        let mut media = self.media_map.get_mut(media_id)?;
+        let previous = media.location.clone();
+        self.location_index.remove(media_id, &previous);
+        self.location_index.insert(media_id, &location);
+        self.location_map.remove_if(&previous, |_, x| x == media_id);
+        self.location_map.insert(location.clone(), media_id.clone());
+        media.value_mut().set_location(location);
+        self.missing_media.remove(media_id);
+        Some(previous)
+    }
+
+    fn update_perceptual_hash_in_memory(&mut self, media_id: &MediaId, perceptual_hash: Option<u64>) -> Option<Option<u64>> {
+        let mut media = self.media_map.get_mut(media_id)?;
+        Some(std::mem::replace(&mut media.value_mut().perceptual_hash, perceptual_hash))
+    }
+
+    fn update_file_info_in_memory(&mut self, media_id: &MediaId, file_info: FileInfo) -> Option<FileInfo> {
+        let mut media = self.media_map.get_mut(media_id)?;
+        let previous = media.file_info();
+        media.value_mut().set_file_info(file_info);
+        self.modified_media.remove(media_id);
+        Some(previous)
+    }
+
+    fn update_verified_at_in_memory(&mut self, media_id: &MediaId, verified_at: Option<DateTime<Utc>>) -> Option<Option<DateTime<Utc>>> {
+        let mut media = self.media_map.get_mut(media_id)?;
+        Some(std::mem::replace(&mut media.value_mut().verified_at, verified_at))
+    }
+
+    pub async fn create_media_from_file(&self, abs_path: &Path) -> Result<Media, TaganrogError> {
         if !abs_path.exists() || abs_path.is_dir() {
             return Err(TaganrogError::FileNotFound);
         }
+        // the same file reached through a symlink, a relative path or `..` is the same media
+        let abs_path = &canonicalize_file(abs_path, &self.cfg.library_roots).map_err(TaganrogError::FileReadError)?;
 
         let abs_path_str = abs_path.to_string_lossy().to_string();
-        let hash = MurMurHasher::hash_str(&abs_path_str);
+        let mut hash = self.compute_media_id(abs_path).await?;
+        let is_taken = |id: &MediaId| self.media_map.get(id).is_some_and(|x| x.value().location != abs_path_str);
+        if self.cfg.media_identity == MediaIdentity::Path && !self.media_map.get(&hash).is_some_and(|x| x.value().location == abs_path_str) {
+            match self.get_media_by_location(&abs_path_str) {
+                // a relinked file keeps the id derived from its old path
+                Some(media) => hash = media.id,
+                // a new file at the old path of a relinked one needs another id
+                None => {
+                    let mut suffix = 1;
+                    while is_taken(&hash) {
+                        hash = MurMurHasher::hash_str(&format!("{}#{}", abs_path_str, suffix));
+                        suffix += 1;
+                    }
+                },
+            }
+        }
         let metadata = std::fs::metadata(&abs_path)
             .map_err(TaganrogError::FileMetadataError)?;
         let guess = mime_guess::from_path(&abs_path_str);
@@ -324,55 +1032,879 @@ impl<T: Storage> TaganrogClient<T> {
             size,
             location,
             tags: vec![],
+            perceptual_hash: None,
+            modified_at: modified_at_of(&metadata),
+            content_hash: None,
+            verified_at: None,
+        };
+        let is_new = !self.media_map.contains_key(&media.id);
+        let perceptual_hash = match is_new && media.is_image() {
+            true => Self::compute_perceptual_hash(abs_path).await,
+            false => None,
+        };
+        let content_hash = match is_new && self.cfg.hash_content {
+            true if self.cfg.media_identity == MediaIdentity::Blake3 => Some(media.id.clone()),
+            true => Some(Self::compute_content_hash(&media.location).await?),
+            false => None,
         };
 
-        Ok(media)
+        Ok(Media { perceptual_hash, content_hash, ..media })
     }
 
-    pub async fn add_media(&mut self, media: Media) -> Result<InsertResult<Media>, TaganrogError> {
-        let hash = media.id.clone();
-        if self.media_map.contains_key(&hash) {
-            return Ok(InsertResult::Existing(self.media_map.get(&hash).map(|x| x.value().clone()).unwrap()));
-        }
-        let result = self.create_media_in_memory(media.clone());
-        if let InsertResult::New(media) = &result {
-            self.storage.write(DbOperation::CreateMedia { media: media.clone() }).await?;
-        }
-        Ok(result)
+    /// `None` if the file is not an image that can be decoded.
+    async fn compute_perceptual_hash(abs_path: &Path) -> Option<u64> {
+        let abs_path = abs_path.to_path_buf();
+        tokio::task::spawn_blocking(move || PerceptualHasher::dhash(&abs_path)).await.ok()?.ok()
     }
 
-    pub async fn delete_media(&mut self, media_id: &MediaId) -> Result<Option<Media>, TaganrogError> {
-        let maybe_media = self.delete_media_in_memory(media_id);
-        if let Some(media) = &maybe_media {
-            self.storage.write(DbOperation::DeleteMedia { media_id: media.id.clone() }).await?;
-        }
-        Ok(maybe_media)
+    /// Computes the perceptual hashes of the images that don't have one yet (e.g. added by an older version).
+    /// Returns how many were hashed.
+    pub async fn hash_images(&mut self) -> Result<usize, TaganrogError> {
+        let perceptual_hashes = Self::compute_perceptual_hashes(self.get_unhashed_images()).await;
+        self.record_perceptual_hashes(perceptual_hashes, ChangeKind::Edit).await
     }
 
-    pub async fn add_tag_to_media(&mut self, media_id: &MediaId, tag: &Tag) -> Result<bool, TaganrogError> {
+    /// The images that don't have a perceptual hash yet, except those that failed to decode.
+    pub fn get_unhashed_images(&self) -> Vec<Media> {
+        self.media_map.iter()
+            .filter(|x| x.value().is_image() && x.value().perceptual_hash.is_none())
+            .filter(|x| !self.undecodable_images.contains(x.key()))
+            .map(|x| x.value().clone())
+            .collect()
+    }
+
+    /// Computes the perceptual hashes of the images, `None` for those that can't be decoded.
+    /// It doesn't need the client, so that the client isn't held while the files are read.
+    pub async fn compute_perceptual_hashes(images: Vec<Media>) -> Vec<(MediaId, Option<u64>)> {
+        let mut perceptual_hashes = Vec::new();
+        for media in images {
+            let perceptual_hash = Self::compute_perceptual_hash(Path::new(&media.location)).await;
+            perceptual_hashes.push((media.id, perceptual_hash));
+        }
+        perceptual_hashes
+    }
+
+    /// Records the perceptual hashes of the media that are still there, all at once, as a change of the given kind.
+    /// The images that failed to decode are remembered, so that they aren't decoded again until their file changes.
+    /// Returns how many were recorded.
+    pub async fn record_perceptual_hashes(&mut self, perceptual_hashes: Vec<(MediaId, Option<u64>)>, kind: ChangeKind) -> Result<usize, TaganrogError> {
+        let mut operations = Vec::new();
+        for (media_id, perceptual_hash) in perceptual_hashes {
+            let Some(perceptual_hash) = perceptual_hash else {
+                self.undecodable_images.insert(media_id);
+                continue;
+            };
+            if let Some(previous) = self.update_perceptual_hash_in_memory(&media_id, Some(perceptual_hash)) {
+                operations.push(DbOperation::UpdatePerceptualHash { media_id, perceptual_hash: Some(perceptual_hash), previous });
+            }
+        }
+        let hashed_count = operations.len();
+        self.write_batch_of_kind(operations, kind).await?;
+        Ok(hashed_count)
+    }
+
+    /// Returns the images whose perceptual hash differs from the one of `media` in at most `max_distance` bits,
+    /// closest first, with their distance. Images without a hash are not compared, see `hash_images`.
+    pub async fn find_similar_media(&self, media: &Media, max_distance: u32) -> Vec<(Media, u32)> {
+        match Self::perceptual_hash_of(media).await {
+            Some(perceptual_hash) => self.find_similar_to_hash(&media.id, perceptual_hash, max_distance),
+            None => vec![],
+        }
+    }
+
+    /// The recorded perceptual hash of the media, or the one of its file if it's an image without one.
+    /// It doesn't need the client, so that the client isn't held while the file is decoded.
+    pub async fn perceptual_hash_of(media: &Media) -> Option<u64> {
+        match media.perceptual_hash {
+            Some(perceptual_hash) => Some(perceptual_hash),
+            None if media.is_image() => Self::compute_perceptual_hash(Path::new(&media.location)).await,
+            None => None,
+        }
+    }
+
+    /// Like `find_similar_media`, for a perceptual hash computed beforehand.
+    pub fn find_similar_to_hash(&self, media_id: &MediaId, perceptual_hash: u64, max_distance: u32) -> Vec<(Media, u32)> {
+        self.media_map.iter()
+            .filter(|x| x.key() != media_id)
+            .filter_map(|x| {
+                let distance = PerceptualHasher::distance(perceptual_hash, x.value().perceptual_hash?);
+                (distance <= max_distance).then(|| (x.value().clone(), distance))
+            })
+            .sorted_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.location.cmp(&b.0.location)))
+            .collect()
+    }
+
+    /// Derives the id of the media stored in the file, as configured by `media_identity`.
+    pub async fn compute_media_id(&self, abs_path: &Path) -> Result<MediaId, TaganrogError> {
+        let media_identity = self.cfg.media_identity;
+        let abs_path = abs_path.to_path_buf();
+        tokio::task::spawn_blocking(move || match media_identity {
+            MediaIdentity::Path => Ok(MurMurHasher::hash_str(&abs_path.to_string_lossy())),
+            MediaIdentity::Sampled => ContentHasher::sampled_hash(&abs_path),
+            MediaIdentity::Blake3 => ContentHasher::blake3_hash(&abs_path),
+        }).await
+            .map_err(|e| TaganrogError::FileReadError(std::io::Error::other(e)))?
+            .map_err(TaganrogError::FileReadError)
+    }
+
+    /// Derives the ids of all media again from their files, e.g. after `media_identity` was changed,
+    /// then rewrites the storage. Locations are canonicalized first, so media added through different paths
+    /// to the same file are merged like media with the same content are (their tags are united),
+    /// thumbnails are renamed along. Media whose file is missing keep their id.
+    pub async fn rekey_media(&mut self) -> Result<RekeyResult, TaganrogError> {
+        self.sync().await?;
+        let mut result = RekeyResult::default();
+        let media_vec = self.media_map.iter()
+            .map(|x| x.value().clone())
+            .sorted_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)))
+            .collect::<Vec<Media>>();
+        let mut rekeyed_media = Vec::<Media>::new();
+        let mut rekeyed_indexes = HashMap::<MediaId, usize>::new();
+        let mut renamed_ids = Vec::<(MediaId, MediaId)>::new();
+        for mut media in media_vec {
+            let path = PathBuf::from(&media.location);
+            let new_id = match path.is_file() {
+                true => {
+                    let canonical_path = canonicalize_file(&path, &self.cfg.library_roots).map_err(TaganrogError::FileReadError)?;
+                    if canonical_path != path {
+                        media.set_location(canonical_path.to_string_lossy().to_string());
+                        result.canonicalized += 1;
+                    }
+                    self.compute_media_id(&canonical_path).await?
+                },
+                false => {
+                    result.missing += 1;
+                    media.id.clone()
+                },
+            };
+            if new_id != media.id {
+                result.rekeyed += 1;
+                renamed_ids.push((media.id.clone(), new_id.clone()));
+            }
+            match rekeyed_indexes.get(&new_id) {
+                Some(index) => {
+                    let existing = &mut rekeyed_media[*index];
+                    for tag in media.tags {
+                        if !existing.tags.contains(&tag) {
+                            existing.tags.push(tag);
+                        }
+                    }
+                    result.merged += 1;
+                },
+                None => {
+                    rekeyed_indexes.insert(new_id.clone(), rekeyed_media.len());
+                    media.id = new_id;
+                    rekeyed_media.push(media);
+                },
+            }
+        }
+        if renamed_ids.is_empty() && result.canonicalized == 0 {
+            return Ok(result);
+        }
+
+        let mut staged_thumbnails = Vec::new();
+        let mut old_thumbnails = Vec::new();
+        let mut rewrite_result = self.stage_thumbnail_renames(&renamed_ids, &mut staged_thumbnails, &mut old_thumbnails).await;
+        if rewrite_result.is_ok() {
+            let state_meta = StateMeta::from_history(&self.storage.read_history().await?).renamed(&renamed_ids.iter().cloned().collect());
+            rewrite_result = self.storage.rewrite(self.to_stored_state(rekeyed_media, &state_meta)).await;
+        }
+        if let Err(e) = rewrite_result {
+            for (staged_path, _) in staged_thumbnails {
+                let _ = tokio::fs::remove_file(staged_path).await;
+            }
+            return Err(e);
+        }
+
+        for old_path in old_thumbnails.iter() {
+            tokio::fs::remove_file(old_path).await.map_err(TaganrogError::FileWriteError)?;
+        }
+        for (staged_path, path) in staged_thumbnails.iter() {
+            tokio::fs::rename(staged_path, path).await.map_err(TaganrogError::FileWriteError)?;
+        }
+        self.load().await?;
+        Ok(result)
+    }
+
+    /// The location a path to a file or directory is recorded at, see `canonicalize_file`.
+    pub fn canonicalize_location(&self, path: &Path) -> std::io::Result<PathBuf> {
+        canonicalize_file(path, &self.cfg.library_roots)
+    }
+
+    /// The configured library roots, with whether they are online and how many media are under them.
+    pub fn get_library_roots(&self) -> Vec<LibraryRootInfo> {
+        self.cfg.library_roots.iter()
+            .map(|(root_id, root)| LibraryRootInfo {
+                id: root_id.clone(),
+                path: root.to_string_lossy().to_string(),
+                is_online: root.is_dir(),
+                media_count: self.media_map.iter()
+                    .filter(|x| self.cfg.library_roots.root_of(&x.value().location).is_some_and(|(id, _)| id == root_id))
+                    .count(),
+            })
+            .collect()
+    }
+
+    /// Rewrites the storage (like `compact` does) so that every location under a library root is stored relative to it.
+    /// With `from`, the media under that directory (e.g. where a drive used to be mounted) are moved under the root first.
+    pub async fn rebase_locations(&mut self, from: Option<(&Path, &str)>) -> Result<RebaseResult, TaganrogError> {
+        self.sync().await?;
+        let mut result = RebaseResult::default();
+        // the in-memory state is only replaced once the storage was rewritten
+        let mut media_vec = self.get_media_in_creation_order();
+        if let Some((old_dir, root_id)) = from {
+            let root = self.cfg.library_roots.get(root_id).cloned()
+                .ok_or_else(|| TaganrogError::UnknownLibraryRoot(root_id.to_string()))?;
+            for media in media_vec.iter_mut() {
+                let Ok(relative_path) = Path::new(&media.location).strip_prefix(old_dir) else {
+                    continue;
+                };
+                let location = root.join(relative_path).to_string_lossy().to_string();
+                media.set_location(location);
+                result.moved += 1;
+            }
+        }
+        result.relative = media_vec.iter()
+            .filter(|x| self.cfg.library_roots.root_of(&x.location).is_some())
+            .count();
+
+        let state_meta = StateMeta::from_history(&self.storage.read_history().await?);
+        self.storage.rewrite(self.to_stored_state(media_vec, &state_meta)).await?;
+        self.load().await?;
+        Ok(result)
+    }
+
+    /// Keeps the thumbnail of the media under its new id, unless there's one already.
+    /// Copies the thumbnail of each renamed media to a temporary file, collecting the temporary file
+    /// with the path it's meant for, and the old thumbnails to remove once the DB is rewritten.
+    /// A media keeps its own thumbnail when it already has one and isn't renamed itself.
+    async fn stage_thumbnail_renames(&self, renamed_ids: &[(MediaId, MediaId)], staged_thumbnails: &mut Vec<(PathBuf, PathBuf)>, old_thumbnails: &mut Vec<PathBuf>) -> Result<(), TaganrogError> {
+        let old_ids = renamed_ids.iter().map(|(old_id, _)| old_id).collect::<HashSet<&MediaId>>();
+        for (old_id, new_id) in renamed_ids {
+            for extension in ["png", "png.enc"] {
+                let old_path = self.cfg.thumbnails_dir.join(format!("{}.{}", old_id, extension));
+                if !old_path.exists() {
+                    continue;
+                }
+                old_thumbnails.push(old_path.clone());
+                let kept = !old_ids.contains(new_id) && self.has_thumbnail(new_id);
+                let path = self.cfg.thumbnails_dir.join(format!("{}.{}", new_id, extension));
+                if kept || staged_thumbnails.iter().any(|(_, x)| x == &path) {
+                    continue;
+                }
+                let staged_path = self.cfg.thumbnails_dir.join(format!("{}.{}.tmp", new_id, extension));
+                staged_thumbnails.push((staged_path.clone(), path));
+                tokio::fs::copy(&old_path, &staged_path).await.map_err(TaganrogError::FileWriteError)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn add_media(&mut self, media: Media) -> Result<InsertResult<Media>, TaganrogError> {
+        let hash = media.id.clone();
+        if self.media_map.contains_key(&hash) {
+            return Ok(InsertResult::Existing(self.media_map.get(&hash).map(|x| x.value().clone()).unwrap()));
+        }
+        let result = self.create_media_in_memory(media.clone());
+        if let InsertResult::New(media) = &result {
+            self.write_operation(DbOperation::CreateMedia { media: media.clone() }).await?;
+        }
+        Ok(result)
+    }
+
+    pub async fn delete_media(&mut self, media_id: &MediaId) -> Result<Option<Media>, TaganrogError> {
+        let maybe_media = self.delete_media_in_memory(media_id);
+        if let Some(media) = &maybe_media {
+            self.write_operation(DbOperation::DeleteMedia { media_id: media.id.clone(), previous: Some(media.clone()) }).await?;
+        }
+        Ok(maybe_media)
+    }
+
+    pub async fn add_tag_to_media(&mut self, media_id: &MediaId, tag: &Tag) -> Result<bool, TaganrogError> {
         let was_added = self.add_tag_to_media_in_memory(media_id, tag);
         if was_added {
-            self.storage.write(DbOperation::AddTag { media_id: media_id.clone(), tag: tag.clone() }).await?;
+            self.write_operation(DbOperation::AddTag { media_id: media_id.clone(), tag: tag.clone() }).await?;
         }
         Ok(was_added)
     }
 
+    /// Adds the media (unless it's already there) together with the tags it doesn't have yet, all or nothing.
+    /// Returns the added tags.
+    pub async fn tag_media(&mut self, media: Media, tags: &[Tag]) -> Result<Vec<Tag>, TaganrogError> {
+        let media_id = media.id.clone();
+        let mut operations = Vec::new();
+        if let InsertResult::New(media) = self.create_media_in_memory(media) {
+            operations.push(DbOperation::CreateMedia { media });
+        }
+        let added_tags = tags.iter().filter(|x| self.add_tag_to_media_in_memory(&media_id, x)).cloned().collect::<Vec<Tag>>();
+        operations.extend(added_tags.iter().map(|x| DbOperation::AddTag { media_id: media_id.clone(), tag: x.clone() }));
+        self.write_batch(operations).await?;
+        Ok(added_tags)
+    }
+
+    /// Removes the given tags from the media, all or nothing. Returns the removed tags.
+    pub async fn remove_tags_from_media(&mut self, media_id: &MediaId, tags: &[Tag]) -> Result<Vec<Tag>, TaganrogError> {
+        let removed_tags = tags.iter().filter(|x| self.remove_tag_from_media_in_memory(media_id, x)).cloned().collect::<Vec<Tag>>();
+        let operations = removed_tags.iter().map(|x| DbOperation::RemoveTag { media_id: media_id.clone(), tag: x.clone() }).collect();
+        self.write_batch(operations).await?;
+        Ok(removed_tags)
+    }
+
     pub async fn remove_tag_from_media(&mut self, media_id: &MediaId, tag: &Tag) -> Result<bool, TaganrogError> {
         let was_removed = self.remove_tag_from_media_in_memory(media_id, tag);
         if was_removed {
-            self.storage.write(DbOperation::RemoveTag { media_id: media_id.clone(), tag: tag.clone() }).await?;
+            self.write_operation(DbOperation::RemoveTag { media_id: media_id.clone(), tag: tag.clone() }).await?;
         }
         Ok(was_removed)
     }
 
+    /// Groups the media whose files have the same content. Missing and unreadable files are skipped.
+    pub async fn find_duplicates(&self) -> Result<Vec<DuplicateGroup>, TaganrogError> {
+        Self::group_duplicates(self.get_duplicate_candidates()).await
+    }
+
+    /// The media with the same size as another one, only they can have duplicates.
+    pub fn get_duplicate_candidates(&self) -> Vec<Media> {
+        self.media_map.iter()
+            .map(|x| x.value().clone())
+            .into_group_map_by(|x| x.size)
+            .into_values()
+            .filter(|x| x.len() > 1)
+            .flatten()
+            .collect()
+    }
+
+    /// Groups the candidates with the same content by their recorded content hash, hashing only the files without one.
+    /// It doesn't need the client, so that the client isn't held while the files are read.
+    pub async fn group_duplicates(candidates: Vec<Media>) -> Result<Vec<DuplicateGroup>, TaganrogError> {
+        let media_by_size = candidates.into_iter()
+            .filter(|x| Path::new(&x.location).is_file())
+            .into_group_map_by(|x| x.size);
+        let mut groups = Vec::new();
+        for (_, same_size_media) in media_by_size.into_iter().filter(|(_, x)| x.len() > 1) {
+            let mut media_by_hash = HashMap::<String, Vec<Media>>::new();
+            for media in same_size_media {
+                if let Ok(content_hash) = Self::content_hash_of(&media, false).await {
+                    media_by_hash.entry(content_hash).or_default().push(media);
+                }
+            }
+            for (content_hash, mut media) in media_by_hash.into_iter().filter(|(_, x)| x.len() > 1) {
+                media.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.location.cmp(&b.location)));
+                groups.push(DuplicateGroup { content_hash, media });
+            }
+        }
+        groups.sort_by(|a, b| a.media[0].location.cmp(&b.media[0].location));
+        Ok(groups)
+    }
+
+    async fn compute_content_hash(location: &str) -> Result<String, TaganrogError> {
+        let path = PathBuf::from(location);
+        tokio::task::spawn_blocking(move || ContentHasher::blake3_hash(&path))
+            .await
+            .map_err(|e| TaganrogError::FileReadError(std::io::Error::other(e)))?
+            .map_err(TaganrogError::FileReadError)
+    }
+
+    /// The recorded content hash of the media, or with `rehash` (or without one) the hash of its file as it is now.
+    async fn content_hash_of(media: &Media, rehash: bool) -> Result<String, TaganrogError> {
+        match media.content_hash.as_ref().filter(|_| !rehash) {
+            Some(content_hash) => Ok(content_hash.clone()),
+            None => Self::compute_content_hash(&media.location).await,
+        }
+    }
+
+    /// Moves the tags of the duplicates onto the kept media and forgets the duplicates, all at once, deleting their thumbnails.
+    /// Checks that they have the same content first, by their recorded content hash, or with `delete_files`
+    /// by hashing the files, which are then deleted too. Files that can't be deleted once the merge is written are reported in the result.
+    pub async fn merge_duplicates(&mut self, keep_id: &MediaId, duplicate_ids: &[MediaId], delete_files: bool) -> Result<MergeResult, TaganrogError> {
+        let kept_media = self.get_media_by_id(keep_id).ok_or_else(|| TaganrogError::MediaNotFound(keep_id.clone()))?;
+        let mut duplicates = Vec::new();
+        for duplicate_id in duplicate_ids.iter().filter(|x| *x != keep_id).unique() {
+            duplicates.push(self.get_media_by_id(duplicate_id).ok_or_else(|| TaganrogError::MediaNotFound(duplicate_id.clone()))?);
+        }
+        let kept_hash = Self::content_hash_of(&kept_media, delete_files).await?;
+        for duplicate in duplicates.iter() {
+            let is_same_file = delete_files && duplicate.location == kept_media.location;
+            if is_same_file || Self::content_hash_of(duplicate, delete_files).await? != kept_hash {
+                return Err(TaganrogError::NotADuplicate(duplicate.location.clone()));
+            }
+        }
+
+        let mut operations = Vec::new();
+        let mut added_tags = Vec::new();
+        for duplicate in duplicates.iter() {
+            for tag in duplicate.tags.iter() {
+                if self.add_tag_to_media_in_memory(keep_id, tag) {
+                    operations.push(DbOperation::AddTag { media_id: keep_id.clone(), tag: tag.clone() });
+                    added_tags.push(tag.clone());
+                }
+            }
+            if let Some(media) = self.delete_media_in_memory(&duplicate.id) {
+                operations.push(DbOperation::DeleteMedia { media_id: media.id.clone(), previous: Some(media) });
+            }
+        }
+        self.write_batch(operations).await?;
+
+        let mut result = MergeResult { added_tags, ..Default::default() };
+        for duplicate in duplicates.iter() {
+            let mut paths = self.get_thumbnail_paths(&duplicate.id).to_vec();
+            if delete_files {
+                paths.push(PathBuf::from(&duplicate.location));
+            }
+            for path in paths {
+                match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => result.undeleted_files.push((path.to_string_lossy().to_string(), e.to_string())),
+                    _ => {},
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Deletes the media whose file is missing, all at once. With `dry_run` only returns them.
+    pub async fn prune_missing_media(&mut self, dry_run: bool) -> Result<Vec<Media>, TaganrogError> {
+        let missing_media = self.scan_missing_media().await;
+        if dry_run {
+            return Ok(missing_media);
+        }
+        let operations = missing_media.iter()
+            .filter_map(|x| self.delete_media_in_memory(&x.id))
+            .map(|x| DbOperation::DeleteMedia { media_id: x.id.clone(), previous: Some(x) })
+            .collect();
+        self.write_batch(operations).await?;
+        Ok(missing_media)
+    }
+
+    /// Rehashes the files of the media in parallel and compares them with their recorded checksum, in the given order.
+    /// `on_progress` is called with the number of verified files and the total after each file.
+    /// Media under an offline library root are skipped.
+    pub async fn verify_media(&self, media_vec: Vec<Media>, on_progress: impl Fn(usize, usize)) -> Vec<Verification> {
+        let media_vec = media_vec.into_iter()
+            .filter(|x| !self.cfg.library_roots.is_offline(&x.location))
+            .collect::<Vec<Media>>();
+        let total_count = media_vec.len();
+        let parallelism = std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1);
+        let mut pending_media = media_vec.into_iter().enumerate();
+        let mut running_media = HashMap::new();
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut verifications = Vec::with_capacity(total_count);
+        let mut failed_count = 0;
+        loop {
+            while tasks.len() < parallelism {
+                let Some((index, media)) = pending_media.next() else {
+                    break;
+                };
+                running_media.insert(index, media.clone());
+                tasks.spawn_blocking(move || {
+                    // a panic while reading the file fails the verification instead of dropping it
+                    let status = std::panic::catch_unwind(|| verify_file(&media))
+                        .unwrap_or_else(|_| VerifyStatus::Unreadable("the verification panicked".to_string()));
+                    (index, Verification { media, status })
+                });
+            }
+            let Some(result) = tasks.join_next().await else {
+                break;
+            };
+            match result {
+                Ok((index, verification)) => {
+                    running_media.remove(&index);
+                    verifications.push((index, verification));
+                },
+                Err(e) => {
+                    warn!("a verification task failed: {}", e);
+                    failed_count += 1;
+                },
+            }
+            on_progress(verifications.len() + failed_count, total_count);
+        }
+        // the media of the failed tasks are the ones left running
+        for (index, media) in running_media {
+            verifications.push((index, Verification { media, status: VerifyStatus::Unreadable("the verification failed".to_string()) }));
+        }
+        verifications.sort_by_key(|(index, _)| *index);
+        verifications.into_iter().map(|(_, x)| x).collect()
+    }
+
+    /// Records now as the verification time of the media whose file matched its checksum,
+    /// and the checksum of those that had none, all at once. Returns how many media were recorded.
+    pub async fn record_verifications(&mut self, verifications: &[Verification]) -> Result<usize, TaganrogError> {
+        let verified_at = Some(Utc::now());
+        let mut operations = Vec::new();
+        let mut recorded_count = 0;
+        for verification in verifications.iter() {
+            let media_id = &verification.media.id;
+            match &verification.status {
+                VerifyStatus::Ok => {},
+                VerifyStatus::Unchecked { content_hash } => {
+                    let Some(media) = self.get_media_by_id(media_id) else {
+                        continue;
+                    };
+                    let file_info = FileInfo { content_hash: Some(content_hash.clone()), ..media.file_info() };
+                    if let Some(previous) = self.update_file_info_in_memory(media_id, file_info.clone()) {
+                        operations.push(DbOperation::UpdateFileInfo { media_id: media_id.clone(), file_info, previous: Some(previous) });
+                    }
+                },
+                _ => continue,
+            }
+            if let Some(previous) = self.update_verified_at_in_memory(media_id, verified_at) {
+                operations.push(DbOperation::UpdateVerifiedAt { media_id: media_id.clone(), verified_at, previous });
+                recorded_count += 1;
+            }
+        }
+        self.write_batch(operations).await?;
+        Ok(recorded_count)
+    }
+
+    /// Records the current size, modification time and content hash of the file, e.g. once its changes were reviewed,
+    /// so that it's no longer modified. Returns `false` if there's no such media.
+    pub async fn record_file_info(&mut self, media_id: &MediaId) -> Result<bool, TaganrogError> {
+        let Some(media) = self.get_media_by_id(media_id) else {
+            return Ok(false);
+        };
+        let metadata = std::fs::metadata(&media.location).map_err(TaganrogError::FileMetadataError)?;
+        let content_hash = match media.content_hash.is_some() || self.cfg.hash_content {
+            true => Some(Self::compute_content_hash(&media.location).await?),
+            false => None,
+        };
+        let file_info = FileInfo { size: metadata.len() as i64, modified_at: modified_at_of(&metadata), content_hash };
+        let previous = self.update_file_info_in_memory(media_id, file_info.clone());
+        self.write_operation(DbOperation::UpdateFileInfo { media_id: media_id.clone(), file_info, previous }).await?;
+        Ok(true)
+    }
+
+    /// Replaces the thumbnail of an image by one made from the current content of its file.
+    /// Other media (e.g. videos, whose thumbnails are drawn by the GUI) only lose their stale thumbnail,
+    /// a new one is drawn the next time they are added. Returns whether a new thumbnail was saved.
+    pub async fn regenerate_thumbnail(&self, media_id: &MediaId) -> Result<bool, TaganrogError> {
+        let media = self.get_media_by_id(media_id).ok_or_else(|| TaganrogError::MediaNotFound(media_id.clone()))?;
+        let png_bytes = match media.is_image() {
+            true => {
+                let path = PathBuf::from(&media.location);
+                let png_bytes = tokio::task::spawn_blocking(move || png_thumbnail(&path))
+                    .await
+                    .map_err(|e| TaganrogError::FileReadError(std::io::Error::other(e)))?
+                    .map_err(|_| TaganrogError::NotAnImage(media.location.clone()))?;
+                Some(png_bytes)
+            },
+            false => None,
+        };
+        for extension in ["png", "png.enc"] {
+            let path = self.cfg.thumbnails_dir.join(format!("{}.{}", media_id, extension));
+            if path.exists() {
+                tokio::fs::remove_file(path).await.map_err(TaganrogError::FileWriteError)?;
+            }
+        }
+        let Some(png_bytes) = png_bytes else {
+            return Ok(false);
+        };
+        self.save_thumbnail(media_id, &png_bytes).await?;
+        Ok(true)
+    }
+
+    /// Moves the media to another file, keeping its id, tags and thumbnail. Returns `false` if there's no such media.
+    pub async fn update_media_location(&mut self, media_id: &MediaId, location: String) -> Result<bool, TaganrogError> {
+        let Some(previous) = self.update_location_in_memory(media_id, location.clone()) else {
+            return Ok(false);
+        };
+        self.write_operation(DbOperation::UpdateLocation { media_id: media_id.clone(), location, previous: Some(previous) }).await?;
+        Ok(true)
+    }
+
+    /// Looks for the files of media that are missing in the directories (and their subdirectories).
+    /// With a content-based `MediaIdentity` a file matches if it has the content of the media,
+    /// otherwise if it has the same filename and size, or else the same size and recorded content hash (a renamed file).
+    /// Files that already belong to a media are skipped, as are those that can't be read.
+    pub async fn find_relink_candidates(&self, dirs: &[PathBuf]) -> Result<Vec<RelinkCandidate>, TaganrogError> {
+        let missing_media = self.media_map.iter()
+            .filter(|x| !self.cfg.library_roots.is_offline(&x.value().location))
+            .filter(|x| !Path::new(&x.value().location).is_file())
+            .map(|x| x.value().clone())
+            .sorted_by(|a, b| a.location.cmp(&b.location))
+            .collect::<Vec<Media>>();
+        if missing_media.is_empty() {
+            return Ok(vec![]);
+        }
+        let known_locations = self.media_map.iter().map(|x| x.value().location.clone()).collect::<HashSet<String>>();
+        let mut files_by_size = HashMap::<i64, Vec<PathBuf>>::new();
+        for file in walk_files(dirs) {
+            if known_locations.contains(file.to_string_lossy().as_ref()) {
+                continue;
+            }
+            match std::fs::metadata(&file) {
+                Ok(metadata) => files_by_size.entry(metadata.len() as i64).or_default().push(file),
+                Err(e) => warn!("skipping {:?}: {}", file, e),
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for media in missing_media {
+            let same_size_files = files_by_size.get(&media.size).map(|x| x.as_slice()).unwrap_or_default();
+            let mut locations = Vec::new();
+            let mut relink_match = match self.cfg.media_identity {
+                MediaIdentity::Path => {
+                    for file in same_size_files.iter().filter(|x| filename_of(&x.to_string_lossy()) == media.filename) {
+                        locations.push(file.to_string_lossy().to_string());
+                    }
+                    RelinkMatch::FilenameAndSize
+                },
+                MediaIdentity::Sampled | MediaIdentity::Blake3 => {
+                    for file in same_size_files {
+                        match self.compute_media_id(file).await {
+                            Ok(media_id) if media_id == media.id => locations.push(file.to_string_lossy().to_string()),
+                            Ok(_) => {},
+                            Err(e) => warn!("skipping {:?}: {}", file, e),
+                        }
+                    }
+                    RelinkMatch::Content
+                },
+            };
+            if let Some(content_hash) = media.content_hash.as_ref().filter(|_| locations.is_empty()) {
+                for file in same_size_files {
+                    let location = file.to_string_lossy().to_string();
+                    match Self::compute_content_hash(&location).await {
+                        Ok(file_content_hash) if file_content_hash == *content_hash => locations.push(location),
+                        Ok(_) => {},
+                        Err(e) => warn!("skipping {:?}: {}", file, e),
+                    }
+                }
+                relink_match = RelinkMatch::Content;
+            }
+            if !locations.is_empty() {
+                candidates.push(RelinkCandidate { media, locations, relink_match });
+            }
+        }
+        Ok(candidates)
+    }
+
     pub fn get_media_path(&self, media_id: &MediaId) -> Option<PathBuf> {
         let media = self.get_media_by_id(media_id)?;
         let media_path = PathBuf::from(&media.location);
         Some(media_path)
     }
 
+    /// Where a new thumbnail of the media is saved.
     pub fn get_thumbnail_path(&self, media_id: &MediaId) -> PathBuf {
-        self.cfg.thumbnails_dir.join(format!("{}.png", media_id))
+        match self.encrypts_thumbnails() {
+            true => self.cfg.thumbnails_dir.join(format!("{}.png.enc", media_id)),
+            false => self.cfg.thumbnails_dir.join(format!("{}.png", media_id)),
+        }
+    }
+
+    pub fn has_thumbnail(&self, media_id: &MediaId) -> bool {
+        self.get_thumbnail_paths(media_id).iter().any(|x| x.exists())
+    }
+
+    /// Where the thumbnail of the media is, encrypted or not.
+    fn get_thumbnail_paths(&self, media_id: &MediaId) -> [PathBuf; 2] {
+        ["png", "png.enc"].map(|x| self.cfg.thumbnails_dir.join(format!("{}.{}", media_id, x)))
+    }
+
+    fn encrypts_thumbnails(&self) -> bool {
+        self.cfg.encrypt_thumbnails && self.storage.cipher().is_some()
+    }
+
+    pub async fn save_thumbnail(&self, media_id: &MediaId, png_bytes: &[u8]) -> Result<(), TaganrogError> {
+        let bytes = match self.storage.cipher().filter(|_| self.encrypts_thumbnails()) {
+            Some(cipher) => cipher.encrypt(png_bytes),
+            None => png_bytes.to_vec(),
+        };
+        tokio::fs::write(self.get_thumbnail_path(media_id), bytes).await.map_err(TaganrogError::FileWriteError)
+    }
+
+    /// Returns the PNG bytes of the thumbnail, decrypting it if needed.
+    pub async fn read_thumbnail(&self, media_id: &MediaId) -> Result<Vec<u8>, TaganrogError> {
+        let encrypted_path = self.cfg.thumbnails_dir.join(format!("{}.png.enc", media_id));
+        if !encrypted_path.exists() {
+            let path = self.cfg.thumbnails_dir.join(format!("{}.png", media_id));
+            return tokio::fs::read(path).await.map_err(TaganrogError::FileReadError);
+        }
+        let cipher = self.storage.cipher().ok_or(TaganrogError::DbLocked)?;
+        let bytes = tokio::fs::read(encrypted_path).await.map_err(TaganrogError::FileReadError)?;
+        cipher.decrypt(&bytes).map_err(TaganrogError::DbEncryptionError)
+    }
+}
+
+/// The changes (log lines) that can be undone and redone, the latest ones last.
+struct UndoStack {
+    undo: Vec<DbOperation>,
+    redo: Vec<DbOperation>,
+}
+
+impl UndoStack {
+    fn from_history(history: Vec<LoggedOperation>) -> Self {
+        let mut stack = Self { undo: vec![], redo: vec![] };
+        for logged_operation in history {
+            stack.push(logged_operation);
+        }
+        stack
+    }
+
+    /// Follows a change written after the ones already on the stack.
+    fn push(&mut self, logged_operation: LoggedOperation) {
+        // operations kept by a rewrite (or from an old version, without a timestamp) are the base state,
+        // there's nothing to undo before them
+        if logged_operation.meta.timestamp.is_none() || logged_operation.meta.kind == ChangeKind::Rewrite {
+            self.undo.clear();
+            self.redo.clear();
+            return;
+        }
+        match logged_operation.meta.kind {
+            ChangeKind::Edit => {
+                self.undo.push(logged_operation.operation);
+                self.redo.clear();
+            },
+            ChangeKind::Undo => self.redo.extend(self.undo.pop()),
+            ChangeKind::Redo => self.undo.extend(self.redo.pop()),
+            // not the user's, the changes around it are undone and redone past it
+            ChangeKind::Rewrite | ChangeKind::Background => {},
+        }
+    }
+}
+
+/// Whether each entry of the history was made up to `point`. An entry without a timestamp (from an old version)
+/// is placed by the timestamped ones around it, the point can't be told apart from it when they are on both sides.
+fn is_up_to(history: &[LoggedOperation], point: RestorePoint) -> Result<Vec<bool>, TaganrogError> {
+    let timestamp = match point {
+        RestorePoint::Line(line) => return Ok((0..history.len()).map(|x| x < line).collect()),
+        RestorePoint::Timestamp(timestamp) => timestamp,
+    };
+    let is_before = history.iter()
+        .map(|x| x.meta.timestamp.map(|x| x <= timestamp))
+        .collect::<Vec<Option<bool>>>();
+    // whether the next timestamped entry after each one is before the point
+    let mut next_is_before = vec![None; history.len()];
+    for index in (0..history.len().saturating_sub(1)).rev() {
+        next_is_before[index] = is_before[index + 1].or(next_is_before[index + 1]);
+    }
+    let mut previous_is_before = None;
+    let mut is_up_to = Vec::with_capacity(history.len());
+    for (index, is_before) in is_before.into_iter().enumerate() {
+        let is_kept = match (is_before, previous_is_before, next_is_before[index]) {
+            (Some(is_before), _, _) => is_before,
+            (None, Some(false), _) => false,
+            (None, _, Some(true)) => true,
+            (None, _, _) => return Err(TaganrogError::CannotRestore(
+                format!("change {} has no timestamp and might be before or after that time, restore to a change number instead", index + 1))),
+        };
+        previous_is_before = is_before.or(previous_is_before);
+        is_up_to.push(is_kept);
+    }
+    Ok(is_up_to)
+}
+
+fn unpack_batches(operation: DbOperation, meta: &OperationMeta, result: &mut Vec<LoggedOperation>) {
+    match operation {
+        DbOperation::Batch { operations } => {
+            for operation in operations {
+                unpack_batches(operation, meta, result);
+            }
+        },
+        operation => result.push(LoggedOperation { operation, meta: meta.clone() }),
+    }
+}
+
+const INFIX_MATCH: usize = 2;
+
+/// How well a tag matches what was typed of it, the lower the better: `0` it starts with it, `1` one of its words
+/// does (`beach` for `summer-beach`), `INFIX_MATCH` it's somewhere else in it (only for 3 chars or more), then
+/// `INFIX_MATCH` plus the number of typos for a part of it with some (see `max_typos`, `vacaton` for `vacation`).
+/// `None` if it doesn't match.
+fn tag_match_quality(tag: &str, typed: &str) -> Option<usize> {
+    if tag.starts_with(typed) {
+        return Some(0);
+    }
+    let mut positions = tag.match_indices(typed).map(|(i, _)| i).peekable();
+    if positions.peek().is_some() {
+        return match positions.any(|i| tag[..i].ends_with('-')) {
+            true => Some(1),
+            false if typed.chars().count() >= 3 => Some(INFIX_MATCH),
+            false => None,
+        };
+    }
+    fuzzy_find(tag, typed, max_typos(typed)).map(|(_, typos)| INFIX_MATCH + typos)
+}
+
+/// The media of the head of a query being completed, see `evaluate_head`.
+enum HeadMedia {
+    /// the completed query finds those with the completed tag
+    With(HashSet<MediaId>),
+    /// the last term is negated, the completed query finds those without the completed tag
+    Without(HashSet<MediaId>),
+}
+
+/// Splits the query before the term being typed, `None` if it doesn't end with one (e.g. it ends with a space).
+/// A leading `-` stays in the head, `-ca` completes `ca`.
+fn split_last_term(query: &str) -> Option<(&str, &str)> {
+    let start = query.char_indices().rev()
+        .find(|(_, c)| c.is_whitespace() || ['(', ')', '"'].contains(c))
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    let start = start + query[start..].len() - query[start..].trim_start_matches('-').len();
+    match start < query.len() {
+        true => Some(query.split_at(start)),
+        false => None,
+    }
+}
+
+/// How the file of a media compares with what was recorded about it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileCheck {
+    Unchanged,
+    /// Nothing was recorded yet, this is the current file info.
+    Unrecorded(FileInfo),
+    /// The modification time changed, but not the content hash.
+    Touched(FileInfo),
+    Modified,
+}
+
+fn verify_file(media: &Media) -> VerifyStatus {
+    let path = Path::new(&media.location);
+    if !path.is_file() {
+        return VerifyStatus::Missing;
+    }
+    match (ContentHasher::blake3_hash(path), &media.content_hash) {
+        (Err(e), _) => VerifyStatus::Unreadable(e.to_string()),
+        (Ok(actual), None) => VerifyStatus::Unchecked { content_hash: actual },
+        (Ok(actual), Some(expected)) if actual == *expected => VerifyStatus::Ok,
+        (Ok(actual), Some(expected)) => VerifyStatus::Mismatch { expected: expected.clone(), actual },
+    }
+}
+
+/// The modification time of the file, `None` if the platform doesn't record it.
+fn modified_at_of(metadata: &std::fs::Metadata) -> Option<DateTime<Utc>> {
+    metadata.modified().ok().map(DateTime::<Utc>::from)
+}
+
+/// The change turning `current` into `restored`, `None` if they are the same.
+fn diff_media(current: Option<Media>, restored: Option<Media>) -> Option<DbOperation> {
+    match (current, restored) {
+        (None, None) => None,
+        (None, Some(restored)) => Some(DbOperation::Batch { operations: media_to_operations([restored]) }),
+        (Some(current), None) => Some(DbOperation::DeleteMedia { media_id: current.id.clone(), previous: Some(current) }),
+        (Some(current), Some(restored)) => {
+            let removed_tags = current.tags.iter().filter(|x| !restored.tags.contains(x))
+                .map(|x| DbOperation::RemoveTag { media_id: current.id.clone(), tag: x.clone() });
+            let added_tags = restored.tags.iter().filter(|x| !current.tags.contains(x))
+                .map(|x| DbOperation::AddTag { media_id: current.id.clone(), tag: x.clone() });
+            let mut operations = removed_tags.chain(added_tags).collect::<Vec<DbOperation>>();
+            if current.file_info() != restored.file_info() {
+                operations.push(DbOperation::UpdateFileInfo { media_id: current.id.clone(), file_info: restored.file_info(), previous: Some(current.file_info()) });
+            }
+            if current.verified_at != restored.verified_at {
+                operations.push(DbOperation::UpdateVerifiedAt { media_id: current.id.clone(), verified_at: restored.verified_at, previous: current.verified_at });
+            }
+            if current.location != restored.location {
+                operations.push(DbOperation::UpdateLocation { media_id: current.id.clone(), location: restored.location, previous: Some(current.location) });
+            }
+            if current.perceptual_hash != restored.perceptual_hash {
+                operations.push(DbOperation::UpdatePerceptualHash { media_id: current.id.clone(), perceptual_hash: restored.perceptual_hash, previous: current.perceptual_hash });
+            }
+            match operations.len() {
+                0 => None,
+                1 => operations.pop(),
+                _ => Some(DbOperation::Batch { operations }),
+            }
+        },
     }
 }
 
@@ -381,7 +1913,8 @@ mod tests {
     use rand::Rng;
     use super::*;
     use tempfile::tempdir;
-    use crate::storage::InMemoryStorage;
+    use crate::storage::FileStorage;
+    use crate::config::LibraryRoots;
 
     async fn create_test_client() -> TaganrogClient<InMemoryStorage> {
         let temp_dir = tempdir().unwrap();
@@ -393,6 +1926,7 @@ mod tests {
             tg_homedir,
             db_filepath,
             thumbnails_dir,
+            ..Default::default()
         };
         let storage = InMemoryStorage::default();
         let mut client = TaganrogClient::new(cfg, storage);
@@ -409,7 +1943,7 @@ mod tests {
         let size = 0;
         let location = "test.txt".to_string();
         let tags = vec![];
-        Media { id, filename, content_type, created_at, size, location, tags }
+        Media { id, filename, content_type, created_at, size, location, tags, perceptual_hash: None, modified_at: None, content_hash: None, verified_at: None }
     }
 
     #[tokio::test]
@@ -476,12 +2010,66 @@ mod tests {
         let media2 = create_random_media();
         client.create_media_in_memory(media1.clone());
         client.create_media_in_memory(media2.clone());
-        let page = client.get_all_media(10, 0);
+        let page = client.get_all_media(MediaSort::default(), 10, 0);
         assert_eq!(page.media_vec.len(), 2);
         assert_eq!(page.total_count, 2);
         assert_eq!(page.total_pages, 1);
     }
 
+    #[tokio::test]
+    async fn test_search_media_text() {
+        let mut client = create_test_client().await;
+        let invoice = Media { filename: "2024_Invoice.pdf".to_string(), location: "/docs/2024_Invoice.pdf".to_string(), ..create_random_media() };
+        let photo = Media { filename: "beach.png".to_string(), location: "/photos/2024/beach.png".to_string(), ..create_random_media() };
+        client.create_media_in_memory(invoice.clone());
+        client.create_media_in_memory(photo.clone());
+        client.add_tag_to_media_in_memory(&photo.id, &"trip".to_string());
+        let count = |client: &TaganrogClient<_>, query: &str| client.search_media(query, MediaSort::default(), 10, 0).unwrap().total_count;
+        assert_eq!(count(&client, "name:invoice"), 1);
+        assert_eq!(count(&client, "name:invoce"), 1);
+        assert_eq!(count(&client, "name:2024"), 1);
+        assert_eq!(count(&client, "path:2024"), 2);
+        assert_eq!(count(&client, "path:photos trip"), 1);
+        assert_eq!(count(&client, "path:2024 -name:beach"), 1);
+
+        client.update_location_in_memory(&invoice.id, "/docs/2024_Receipt.pdf".to_string());
+        assert_eq!(count(&client, "name:invoice"), 0);
+        assert_eq!(count(&client, "name:receipt"), 1);
+        client.delete_media_in_memory(&invoice.id);
+        assert_eq!(count(&client, "name:receipt"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sort_media() {
+        let mut client = create_test_client().await;
+        let now = chrono::Utc::now();
+        let media1 = Media { filename: "b.png".to_string(), size: 300, created_at: now, ..create_random_media() };
+        let media2 = Media { filename: "A.png".to_string(), size: 100, created_at: now - chrono::Duration::days(1), ..create_random_media() };
+        let media3 = Media { filename: "c.png".to_string(), size: 200, created_at: now - chrono::Duration::days(2), ..create_random_media() };
+        for media in [&media1, &media2, &media3] {
+            client.create_media_in_memory(media.clone());
+        }
+        client.add_tag_to_media_in_memory(&media3.id, &"tag1".to_string());
+        client.add_tag_to_media_in_memory(&media3.id, &"tag2".to_string());
+        client.add_tag_to_media_in_memory(&media2.id, &"tag1".to_string());
+        let filenames = |sort: &str| {
+            let sort = sort.parse::<MediaSort>().unwrap();
+            // one media per page, so every page has to see the same order
+            (0..3).flat_map(|x| client.get_all_media(sort, 1, x).media_vec).map(|x| x.filename).collect::<Vec<String>>()
+        };
+        assert_eq!(filenames("newest"), vec!["b.png", "A.png", "c.png"]);
+        assert_eq!(filenames("oldest"), vec!["c.png", "A.png", "b.png"]);
+        assert_eq!(filenames("name"), vec!["A.png", "b.png", "c.png"]);
+        assert_eq!(filenames("name-desc"), vec!["c.png", "b.png", "A.png"]);
+        assert_eq!(filenames("largest"), vec!["b.png", "c.png", "A.png"]);
+        assert_eq!(filenames("fewest-tags"), vec!["b.png", "A.png", "c.png"]);
+        assert_eq!(filenames("random:42"), filenames("random:42"));
+        let untagged = client.get_untagged_media(MediaSort::MostTags, 10, 0);
+        assert_eq!(untagged.media_vec, vec![media1]);
+        assert!("random:x".parse::<MediaSort>().is_err());
+        assert!("size".parse::<MediaSort>().is_err());
+    }
+
     #[tokio::test]
     async fn test_get_media_without_thumbnail() {
         let mut client = create_test_client().await;
@@ -489,7 +2077,7 @@ mod tests {
         let media2 = create_random_media();
         client.create_media_in_memory(media1.clone());
         client.create_media_in_memory(media2.clone());
-        let page = client.get_media_without_thumbnail(10, 0);
+        let page = client.get_media_without_thumbnail(MediaSort::default(), 10, 0);
         assert_eq!(page.media_vec.len(), 2);
         assert_eq!(page.total_count, 2);
         assert_eq!(page.total_pages, 1);
@@ -502,18 +2090,39 @@ mod tests {
         let media2 = create_random_media();
         client.create_media_in_memory(media1.clone());
         client.create_media_in_memory(media2.clone());
-        let page = client.get_untagged_media(10, 0);
+        let page = client.get_untagged_media(MediaSort::default(), 10, 0);
         assert_eq!(page.media_vec.len(), 2);
         assert_eq!(page.total_count, 2);
         assert_eq!(page.total_pages, 1);
 
         client.add_tag_to_media_in_memory(&media1.id, &"tag1".to_string());
-        let page = client.get_untagged_media(10, 0);
+        let page = client.get_untagged_media(MediaSort::default(), 10, 0);
         assert_eq!(page.media_vec.len(), 1);
         assert_eq!(page.total_count, 1);
         assert_eq!(page.total_pages, 1);
     }
 
+    #[tokio::test]
+    async fn test_get_all_media_sorted_pages() {
+        let mut client = create_test_client().await;
+        let mut media_vec = (0..5).map(|i| Media { size: i % 3, filename: format!("{}.txt", 5 - i), ..create_random_media() }).collect::<Vec<Media>>();
+        for media in media_vec.iter() {
+            client.create_media_in_memory(media.clone());
+        }
+        let page_ids = |sort: MediaSort, page_index: usize| client.get_all_media(sort, 2, page_index).media_vec.into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<MediaId>>();
+
+        media_vec.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.id.cmp(&b.id)));
+        let expected_ids = media_vec.iter().map(|x| x.id.clone()).collect::<Vec<MediaId>>();
+        assert_eq!(page_ids(MediaSort::Largest, 0), expected_ids[0..2]);
+        assert_eq!(page_ids(MediaSort::Largest, 2), expected_ids[4..]);
+        assert_eq!(client.get_all_media(MediaSort::Largest, 2, 0).total_pages, 3);
+
+        media_vec.sort_by(|a, b| a.filename.cmp(&b.filename));
+        assert_eq!(page_ids(MediaSort::Name, 1), vec![media_vec[2].id.clone(), media_vec[3].id.clone()]);
+    }
+
     #[tokio::test]
     async fn test_search_media() {
         let mut client = create_test_client().await;
@@ -523,12 +2132,59 @@ mod tests {
         client.create_media_in_memory(media2.clone());
         client.add_tag_to_media_in_memory(&media1.id, &"tag1".to_string());
         client.add_tag_to_media_in_memory(&media2.id, &"tag2".to_string());
-        let page = client.search_media("tag1", 10, 0);
+        let page = client.search_media("tag1", MediaSort::default(), 10, 0).unwrap();
         assert_eq!(page.media_vec.len(), 1);
         assert_eq!(page.total_count, 1);
         assert_eq!(page.total_pages, 1);
     }
 
+    #[tokio::test]
+    async fn test_search_media_query() {
+        let mut client = create_test_client().await;
+        let media1 = create_random_media();
+        let media2 = create_random_media();
+        let media3 = create_random_media();
+        client.create_media_in_memory(media1.clone());
+        client.create_media_in_memory(media2.clone());
+        client.create_media_in_memory(media3.clone());
+        client.add_tag_to_media_in_memory(&media1.id, &"cat".to_string());
+        client.add_tag_to_media_in_memory(&media2.id, &"dog".to_string());
+        client.add_tag_to_media_in_memory(&media2.id, &"black".to_string());
+        client.add_tag_to_media_in_memory(&media3.id, &"black".to_string());
+        let count = |query: &str| client.search_media(query, MediaSort::default(), 10, 0).unwrap().total_count;
+        assert_eq!(count("cat OR dog"), 2);
+        assert_eq!(count("(cat OR dog) -black"), 1);
+        assert_eq!(count("NOT black"), 1);
+        assert_eq!(count("black AND NOT (cat OR dog)"), 1);
+        assert_eq!(count("all -cat"), 2);
+        assert_eq!(count("unknown OR cat"), 1);
+        assert!(client.search_media("cat OR", MediaSort::default(), 10, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_media_filters() {
+        let mut client = create_test_client().await;
+        let video = Media { filename: "trip.mp4".to_string(), content_type: "video/mp4".to_string(), size: 200_000_000, ..create_random_media() };
+        let small_video = Media { filename: "clip.mp4".to_string(), content_type: "video/mp4".to_string(), size: 5_000_000, ..create_random_media() };
+        let image = Media { filename: "trip.png".to_string(), content_type: "image/png".to_string(), size: 200_000_000, ..create_random_media() };
+        for media in [&video, &small_video, &image] {
+            client.create_media_in_memory(media.clone());
+            client.add_tag_to_media_in_memory(&media.id, &"trip".to_string());
+        }
+        client.add_tag_to_media_in_memory(&image.id, &"beach".to_string());
+        let count = |query: &str| client.search_media(query, MediaSort::default(), 10, 0).unwrap().total_count;
+        assert_eq!(count("trip type:video size:>100MB"), 1);
+        assert_eq!(count("type:video"), 2);
+        assert_eq!(count("name:trip -ext:png"), 1);
+        assert_eq!(count("beach OR ext:mp4"), 3);
+        assert_eq!(count("trip -type:image"), 2);
+
+        let tags = client.get_query_tags("type:image").unwrap();
+        assert_eq!(tags.iter().map(|x| (x.last.as_str(), x.media_count)).collect::<Vec<_>>(), vec![("beach", 1), ("trip", 1)]);
+        let tags = client.get_query_tags("trip").unwrap();
+        assert_eq!(tags.iter().map(|x| (x.last.as_str(), x.media_count)).collect::<Vec<_>>(), vec![("beach", 1)]);
+    }
+
     #[tokio::test]
     async fn test_get_all_tags() {
         let mut client = create_test_client().await;
@@ -546,6 +2202,18 @@ mod tests {
         assert_eq!(tags[1].media_count, 1);
     }
 
+    #[tokio::test]
+    async fn test_get_all_tags_without_media() {
+        let mut client = create_test_client().await;
+        let media = create_random_media();
+        client.create_media_in_memory(media.clone());
+        client.add_tag_to_media_in_memory(&media.id, &"tag1".to_string());
+        client.add_tag_to_media_in_memory(&media.id, &"tag2".to_string());
+        client.remove_tag_from_media_in_memory(&media.id, &"tag1".to_string());
+        let tags = client.get_all_tags();
+        assert_eq!(tags.iter().map(|x| x.last.as_str()).collect::<Vec<&str>>(), vec!["tag2"]);
+    }
+
     #[tokio::test]
     async fn test_autocomplete_tags() {
         let mut client = create_test_client().await;
@@ -562,5 +2230,797 @@ mod tests {
         assert_eq!(tags[0].media_count, 1);
         assert_eq!(tags[1].last, "tag2");
         assert_eq!(tags[1].media_count, 1);
+
+        client.add_tag_to_media_in_memory(&media1.id, &"other".to_string());
+        let tags = client.autocomplete_tags("(other OR -ta", 10);
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].head, "(other OR -");
+        assert_eq!((tags[0].last.as_str(), tags[0].media_count), ("tag1", 2));
+        assert_eq!((tags[1].last.as_str(), tags[1].media_count), ("tag2", 1));
+        assert!(client.autocomplete_tags("tag1 ", 10).is_empty());
+
+        // counted from the media of the head
+        let completions = |query: &str, max_items: usize| client.autocomplete_tags(query, max_items).into_iter()
+            .map(|x| (x.last, x.media_count))
+            .collect::<Vec<(Tag, usize)>>();
+        assert_eq!(completions("other ta", 10), vec![("tag1".to_string(), 1)]);
+        assert_eq!(completions("other -ta", 10), vec![("tag2".to_string(), 1)]);
+        assert_eq!(completions("ta", 1), vec![("tag1".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_split_last_term() {
+        assert_eq!(split_last_term("other -ta"), Some(("other -", "ta")));
+        assert_eq!(split_last_term("(\"ta"), Some(("(\"", "ta")));
+        // an ideographic and a no-break space are several bytes long
+        assert_eq!(split_last_term("other\u{3000}ta"), Some(("other\u{3000}", "ta")));
+        assert_eq!(split_last_term("другой\u{a0}тег"), Some(("другой\u{a0}", "тег")));
+        assert_eq!(split_last_term("other\u{3000}"), None);
+        assert_eq!(split_last_term(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_tags_fuzzy() {
+        let mut client = create_test_client().await;
+        let media1 = create_random_media();
+        let media2 = create_random_media();
+        client.create_media_in_memory(media1.clone());
+        client.create_media_in_memory(media2.clone());
+        for tag in ["vacation", "summer-beach", "beach-house", "bench"] {
+            client.add_tag_to_media_in_memory(&media1.id, &tag.to_string());
+        }
+        client.add_tag_to_media_in_memory(&media2.id, &"summer-beach".to_string());
+        let completions = |query: &str| client.autocomplete_tags(query, 10).into_iter().map(|x| x.last).collect::<Vec<Tag>>();
+        assert_eq!(completions("vacaton"), vec!["vacation"]);
+        // the start of the tag first, then the start of one of its words, then typos
+        assert_eq!(completions("beach"), vec!["beach-house", "summer-beach", "bench"]);
+        assert_eq!(completions("mer"), vec!["summer-beach"]);
+        assert!(completions("me").is_empty());
+
+        assert_eq!(client.suggest_query("vacaton"), Some("vacation".to_string()));
+        assert_eq!(client.suggest_query("summer-beach -vacaton"), Some("summer-beach -vacation".to_string()));
+        assert_eq!(client.suggest_query("vacation"), None);
+        assert_eq!(client.suggest_query("vacaton -vacation"), None);
+        assert_eq!(client.suggest_query("xyz"), None);
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let mut client = create_test_client().await;
+        let media1 = create_random_media();
+        let media2 = create_random_media();
+        let media3 = create_random_media();
+        client.add_media(media1.clone()).await.unwrap();
+        client.add_media(media2.clone()).await.unwrap();
+        client.add_media(media3.clone()).await.unwrap();
+        client.add_tag_to_media(&media1.id, &"tag1".to_string()).await.unwrap();
+        client.add_tag_to_media(&media1.id, &"tag2".to_string()).await.unwrap();
+        client.remove_tag_from_media(&media1.id, &"tag1".to_string()).await.unwrap();
+        client.add_tag_to_media(&media3.id, &"tag1".to_string()).await.unwrap();
+        client.delete_media(&media3.id).await.unwrap();
+
+        let tag2_meta = client.storage.read_history().await.unwrap().into_iter()
+            .find(|x| matches!(&x.operation, DbOperation::AddTag { tag, .. } if tag == "tag2"))
+            .unwrap().meta;
+        let result = client.compact().await.unwrap();
+        assert_eq!(result.operations_before, 8);
+        assert_eq!(result.operations_after, 3);
+
+        // the surviving changes keep when and where they were made
+        let operations = client.storage.read_history().await.unwrap();
+        assert!(operations.iter().all(|x| x.meta.timestamp.is_some()));
+        let compacted_tag2_meta = operations.iter()
+            .find(|x| matches!(&x.operation, DbOperation::AddTag { tag, .. } if tag == "tag2"))
+            .unwrap().meta.clone();
+        assert_eq!((compacted_tag2_meta.timestamp, compacted_tag2_meta.origin), (tag2_meta.timestamp, tag2_meta.origin));
+        assert_eq!(compacted_tag2_meta.kind, ChangeKind::Rewrite);
+
+        // replaying the compacted log must give back the same state, untagged media included
+        let mut restored = TaganrogClient::new(client.cfg.clone(), InMemoryStorage::default());
+        restored.storage.rewrite(operations).await.unwrap();
+        restored.init().await.unwrap();
+        assert_eq!(restored.get_media_count(), 2);
+        assert_eq!(restored.get_media_by_id(&media1.id).unwrap().tags, vec!["tag2".to_string()]);
+        assert!(restored.get_media_by_id(&media2.id).unwrap().tags.is_empty());
+        assert!(restored.get_media_by_id(&media3.id).is_none());
+        assert_eq!(restored.get_query_count(&["tag2".to_string()]), 1);
+        assert_eq!(restored.get_query_count(&["tag1".to_string()]), 0);
+    }
+
+    #[tokio::test]
+    async fn test_init_from_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), snapshot_interval: 3, ..Default::default() };
+        let media1 = create_random_media();
+        let media2 = create_random_media();
+
+        let mut client = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        client.add_media(media1.clone()).await.unwrap();
+        client.add_tag_to_media(&media1.id, &"tag1".to_string()).await.unwrap();
+        client.add_media(media2.clone()).await.unwrap(); // snapshot is taken here
+        client.add_tag_to_media(&media2.id, &"tag1".to_string()).await.unwrap();
+        client.remove_tag_from_media(&media1.id, &"tag1".to_string()).await.unwrap();
+        assert_eq!(crate::storage::snapshot::list_snapshots(&db_filepath).len(), 1);
+
+        let mut restored = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        restored.init().await.unwrap();
+        assert_eq!(restored.log_len, 5);
+        assert_eq!(restored.operations_since_snapshot, 2);
+        assert_eq!(restored.get_media_count(), 2);
+        assert!(restored.get_media_by_id(&media1.id).unwrap().tags.is_empty());
+        assert_eq!(restored.get_media_by_id(&media2.id).unwrap().tags, vec!["tag1".to_string()]);
+        assert_eq!(restored.get_query_count(&["tag1".to_string()]), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_other_process() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), ..Default::default() };
+        let media = create_random_media();
+
+        let mut gui = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        gui.init().await.unwrap();
+        let mut cli = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        cli.init().await.unwrap();
+
+        assert!(!gui.has_changes().await.unwrap());
+        cli.add_media(media.clone()).await.unwrap();
+        cli.add_tag_to_media(&media.id, &"tag1".to_string()).await.unwrap();
+        assert!(gui.has_changes().await.unwrap());
+        assert_eq!(gui.sync().await.unwrap(), 2);
+        assert!(!gui.has_changes().await.unwrap());
+        assert_eq!(gui.get_query_count(&["tag1".to_string()]), 1);
+
+        cli.add_tag_to_media(&media.id, &"tag2".to_string()).await.unwrap();
+        gui.add_tag_to_media(&media.id, &"tag3".to_string()).await.unwrap();
+        assert_eq!(gui.get_media_by_id(&media.id).unwrap().tags.len(), 3);
+        assert_eq!(gui.log_len, 4);
+
+        cli.delete_media(&media.id).await.unwrap();
+        cli.compact().await.unwrap();
+        assert!(gui.has_changes().await.unwrap());
+        gui.sync().await.unwrap();
+        assert_eq!(gui.get_media_count(), 0);
+        assert_eq!(gui.get_query_count(&["tag1".to_string()]), 0);
+    }
+
+    #[tokio::test]
+    async fn test_encryption_with_thumbnails() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), encrypt_thumbnails: true, ..Default::default() };
+        let media = create_random_media();
+        let mut client = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+        client.save_thumbnail(&media.id, b"png").await.unwrap();
+
+        assert_eq!(client.set_encryption(Some("secret")).await.unwrap(), 1);
+        assert!(client.is_encrypted());
+        assert!(client.get_thumbnail_path(&media.id).extension().is_some_and(|x| x == "enc"));
+        assert_ne!(std::fs::read(client.get_thumbnail_path(&media.id)).unwrap(), b"png");
+
+        let mut restarted = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        assert!(restarted.is_locked().await.unwrap());
+        assert!(restarted.init().await.is_err());
+        restarted.unlock("secret").await.unwrap();
+        assert_eq!(restarted.get_query_count(&["tag1".to_string()]), 1);
+        assert_eq!(restarted.read_thumbnail(&media.id).await.unwrap(), b"png");
+
+        assert_eq!(restarted.set_encryption(None).await.unwrap(), 1);
+        assert!(!restarted.is_encrypted());
+        assert_eq!(std::fs::read(restarted.get_thumbnail_path(&media.id)).unwrap(), b"png");
+        assert!(restarted.has_thumbnail(&media.id));
+    }
+
+    #[tokio::test]
+    async fn test_encryption_failure_keeps_thumbnails() {
+        let temp_dir = tempdir().unwrap();
+        let cfg = AppConfig { thumbnails_dir: temp_dir.path().to_path_buf(), encrypt_thumbnails: true, ..Default::default() };
+        let mut client = TaganrogClient::new(cfg, InMemoryStorage::default());
+        client.init().await.unwrap();
+        let media = create_random_media();
+        client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+        client.save_thumbnail(&media.id, b"png").await.unwrap();
+
+        // the in-memory storage can't be encrypted
+        assert!(client.set_encryption(Some("secret")).await.is_err());
+        assert_eq!(client.read_thumbnail(&media.id).await.unwrap(), b"png");
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rekey_media() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let thumbnails_dir = temp_dir.path().join("thumbnails");
+        std::fs::create_dir(&thumbnails_dir).unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir, ..Default::default() };
+        let file1 = temp_dir.path().join("a.txt");
+        let file2 = temp_dir.path().join("b.txt");
+        std::fs::write(&file1, b"same").unwrap();
+        std::fs::write(&file2, b"same").unwrap();
+
+        let mut client = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let media1 = client.create_media_from_file(&file1).await.unwrap();
+        let media2 = client.create_media_from_file(&file2).await.unwrap();
+        assert_ne!(media1.id, media2.id);
+        client.tag_media(media1.clone(), &["tag1".to_string()]).await.unwrap();
+        client.tag_media(media2.clone(), &["tag2".to_string()]).await.unwrap();
+        client.save_thumbnail(&media1.id, b"png").await.unwrap();
+
+        let cfg = AppConfig { media_identity: MediaIdentity::Blake3, ..cfg };
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        let result = client.rekey_media().await.unwrap();
+        assert_eq!(result, RekeyResult { rekeyed: 2, merged: 1, canonicalized: 0, missing: 0 });
+        assert_eq!(client.get_media_count(), 1);
+        let new_id = client.compute_media_id(&file2).await.unwrap();
+        let media = client.get_media_by_id(&new_id).unwrap();
+        assert_eq!(media.tags, vec!["tag1".to_string(), "tag2".to_string()]);
+        assert_eq!(media.location, file1.to_string_lossy());
+        assert_eq!(client.read_thumbnail(&new_id).await.unwrap(), b"png");
+        assert!(!client.has_thumbnail(&media1.id));
+
+        // moving the file keeps its identity
+        let moved_file = temp_dir.path().join("c.txt");
+        std::fs::rename(&file1, &moved_file).unwrap();
+        assert_eq!(client.create_media_from_file(&moved_file).await.unwrap().id, new_id);
+        assert_eq!(client.rekey_media().await.unwrap(), RekeyResult { rekeyed: 0, merged: 0, canonicalized: 0, missing: 1 });
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rekey_merges_path_aliases() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), ..Default::default() };
+        let sub_dir = temp_dir.path().join("sub");
+        std::fs::create_dir(&sub_dir).unwrap();
+        let file = temp_dir.path().join("a.txt");
+        let link = temp_dir.path().join("link.txt");
+        std::fs::write(&file, b"a").unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        let media = client.create_media_from_file(&file).await.unwrap();
+        assert_eq!(client.create_media_from_file(&link).await.unwrap().id, media.id);
+        assert_eq!(client.create_media_from_file(&sub_dir.join("..").join("a.txt")).await.unwrap().id, media.id);
+        client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+
+        // added through the symlink by an older version, which didn't resolve it
+        let link_location = link.to_string_lossy().to_string();
+        let alias = Media {
+            id: MurMurHasher::hash_str(&link_location),
+            filename: "link.txt".to_string(),
+            location: link_location,
+            created_at: media.created_at + chrono::Duration::seconds(1),
+            ..media.clone()
+        };
+        client.tag_media(alias.clone(), &["tag2".to_string()]).await.unwrap();
+        client.save_thumbnail(&alias.id, b"png").await.unwrap();
+        assert_eq!(client.get_media_count(), 2);
+
+        let result = client.rekey_media().await.unwrap();
+        assert_eq!(result, RekeyResult { rekeyed: 1, merged: 1, canonicalized: 1, missing: 0 });
+        assert_eq!(client.get_media_count(), 1);
+        let media = client.get_media_by_id(&media.id).unwrap();
+        assert_eq!(media.tags, vec!["tag1".to_string(), "tag2".to_string()]);
+        assert_eq!(media.location, file.to_string_lossy());
+        assert_eq!(client.read_thumbnail(&media.id).await.unwrap(), b"png");
+    }
+
+    #[tokio::test]
+    async fn test_library_roots() {
+        let temp_dir = tempdir().unwrap();
+        let old_mount = temp_dir.path().join("old_mount");
+        let new_mount = temp_dir.path().join("new_mount");
+        std::fs::create_dir_all(old_mount.join("photos")).unwrap();
+        let file = old_mount.join("photos").join("a.txt");
+        std::fs::write(&file, b"a").unwrap();
+        let roots_at = |mount: &PathBuf| LibraryRoots::new([("share".to_string(), mount.clone())].into());
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), ..Default::default() };
+
+        // tagged before the root was configured, the location is stored absolute
+        let mut client = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let media = client.create_media_from_file(&file).await.unwrap();
+        client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+
+        let cfg = AppConfig { library_roots: roots_at(&old_mount), ..cfg };
+        let mut client = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        assert_eq!(client.rebase_locations(None).await.unwrap(), RebaseResult { moved: 0, relative: 1 });
+        assert!(std::fs::read_to_string(&db_filepath).unwrap().contains("root://share/photos/a.txt"));
+
+        // the drive is mounted elsewhere
+        std::fs::rename(&old_mount, &new_mount).unwrap();
+        let cfg = AppConfig { library_roots: roots_at(&new_mount), ..cfg };
+        let mut client = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let new_file = new_mount.join("photos").join("a.txt");
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().location, new_file.to_string_lossy());
+        assert_eq!(client.get_library_roots()[0].media_count, 1);
+        assert!(client.scan_missing_media().await.is_empty());
+
+        // and unplugged
+        std::fs::rename(&new_mount, &old_mount).unwrap();
+        assert!(!client.get_library_roots()[0].is_online);
+        assert!(client.scan_missing_media().await.is_empty());
+        std::fs::rename(&old_mount, &new_mount).unwrap();
+
+        // and removed from the config, the location stays relative to it
+        let removed_cfg = AppConfig { library_roots: LibraryRoots::default(), ..cfg.clone() };
+        let mut client = TaganrogClient::new(removed_cfg, FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().location, "root://share/photos/a.txt");
+        assert!(client.scan_missing_media().await.is_empty());
+        assert!(client.get_media_to_check().is_empty());
+
+        // a DB with absolute locations from another machine
+        let other_db_filepath = temp_dir.path().join("other.db.json");
+        std::fs::write(&other_db_filepath, "").unwrap();
+        let other_cfg = AppConfig { db_filepath: other_db_filepath.clone(), library_roots: LibraryRoots::default(), ..cfg.clone() };
+        let mut client = TaganrogClient::new(other_cfg, FileStorage::new(other_db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let other_media = Media { location: "/mnt/other/photos/a.txt".to_string(), ..media.clone() };
+        client.tag_media(other_media, &["tag1".to_string()]).await.unwrap();
+        let other_cfg = AppConfig { db_filepath: other_db_filepath.clone(), ..cfg };
+        let mut client = TaganrogClient::new(other_cfg, FileStorage::new(other_db_filepath).unwrap());
+        client.init().await.unwrap();
+        let result = client.rebase_locations(Some((Path::new("/mnt/other"), "share"))).await.unwrap();
+        assert_eq!(result, RebaseResult { moved: 1, relative: 1 });
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().location, new_file.to_string_lossy());
+        assert!(matches!(client.rebase_locations(Some((Path::new("/mnt"), "unknown"))).await, Err(TaganrogError::UnknownLibraryRoot(_))));
+    }
+
+    #[tokio::test]
+    async fn test_find_similar_media() {
+        let mut client = create_test_client().await;
+        let temp_dir = tempdir().unwrap();
+        let gradient = image::RgbImage::from_fn(120, 80, |x, y| image::Rgb([(x * 2) as u8, (y * 3) as u8, 0]));
+        let original = temp_dir.path().join("original.png");
+        let copy = temp_dir.path().join("copy.jpg");
+        let other = temp_dir.path().join("other.png");
+        gradient.save(&original).unwrap();
+        image::imageops::resize(&gradient, 60, 40, image::imageops::FilterType::Triangle).save(&copy).unwrap();
+        image::imageops::rotate180(&gradient).save(&other).unwrap();
+
+        let original_media = client.create_media_from_file(&original).await.unwrap();
+        assert!(original_media.perceptual_hash.is_some());
+        client.tag_media(original_media.clone(), &["tag1".to_string()]).await.unwrap();
+        let other_media = client.create_media_from_file(&other).await.unwrap();
+        client.tag_media(other_media, &["tag2".to_string()]).await.unwrap();
+        // added before perceptual hashes existed
+        let copy_media = Media { perceptual_hash: None, ..client.create_media_from_file(&copy).await.unwrap() };
+        client.tag_media(copy_media.clone(), &["tag3".to_string()]).await.unwrap();
+        assert!(client.find_similar_media(&original_media, 10).await.is_empty());
+        let broken = temp_dir.path().join("broken.png");
+        std::fs::write(&broken, b"not a png").unwrap();
+        let broken_media = client.create_media_from_file(&broken).await.unwrap();
+        client.tag_media(broken_media, &["tag4".to_string()]).await.unwrap();
+        assert_eq!(client.get_unhashed_images().len(), 2);
+
+        assert_eq!(client.hash_images().await.unwrap(), 1);
+        // the broken image isn't decoded again
+        assert!(client.get_unhashed_images().is_empty());
+        assert_eq!(client.hash_images().await.unwrap(), 0);
+        let similar_media = client.find_similar_media(&original_media, 10).await;
+        assert_eq!(similar_media.len(), 1);
+        assert_eq!(similar_media[0].0.id, copy_media.id);
+        assert_eq!(client.find_similar_media(&original_media, 64).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_merge_duplicates() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), ..Default::default() };
+        let files = ["a.txt", "b.txt", "c.txt", "d.txt"].map(|x| temp_dir.path().join(x));
+        for (file, content) in files.iter().zip([b"same", b"same", b"same", b"diff"]) {
+            std::fs::write(file, content).unwrap();
+        }
+
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        let mut media_vec = Vec::new();
+        for (i, file) in files.iter().enumerate() {
+            let media = client.create_media_from_file(file).await.unwrap();
+            client.tag_media(media.clone(), &[format!("tag{}", i)]).await.unwrap();
+            media_vec.push(media);
+        }
+        let groups = client.find_duplicates().await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].media.iter().map(|x| x.id.clone()).collect::<Vec<MediaId>>(), media_vec[..3].iter().map(|x| x.id.clone()).collect::<Vec<MediaId>>());
+
+        let not_duplicate = client.merge_duplicates(&media_vec[0].id, &[media_vec[3].id.clone()], true).await;
+        assert!(matches!(not_duplicate, Err(TaganrogError::NotADuplicate(_))));
+        let not_duplicate = client.merge_duplicates(&media_vec[0].id, &[media_vec[3].id.clone()], false).await;
+        assert!(matches!(not_duplicate, Err(TaganrogError::NotADuplicate(_))));
+        assert_eq!(client.get_media_count(), 4);
+
+        client.save_thumbnail(&media_vec[1].id, b"png").await.unwrap();
+        let result = client.merge_duplicates(&media_vec[0].id, &[media_vec[1].id.clone()], false).await.unwrap();
+        assert_eq!(result, MergeResult { added_tags: vec!["tag1".to_string()], undeleted_files: vec![] });
+        assert!(files[1].exists());
+        assert!(!client.has_thumbnail(&media_vec[1].id));
+        client.merge_duplicates(&media_vec[0].id, &[media_vec[2].id.clone()], true).await.unwrap();
+        assert!(!files[2].exists());
+        assert_eq!(client.get_media_count(), 2);
+        assert_eq!(client.get_media_by_id(&media_vec[0].id).unwrap().tags, vec!["tag0".to_string(), "tag1".to_string(), "tag2".to_string()]);
+        assert!(client.find_duplicates().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rescan_media() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), hash_content: true, ..Default::default() };
+        let text_file = temp_dir.path().join("a.txt");
+        let image_file = temp_dir.path().join("b.png");
+        std::fs::write(&text_file, b"a").unwrap();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0, 0, 0])).save(&image_file).unwrap();
+        let touch = |path: &Path, seconds: u64| std::fs::File::options().write(true).open(path).unwrap()
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(seconds)).unwrap();
+
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let text_media = client.create_media_from_file(&text_file).await.unwrap();
+        let image_media = client.create_media_from_file(&image_file).await.unwrap();
+        assert!(text_media.modified_at.is_some());
+        assert!(text_media.content_hash.is_some());
+        client.tag_media(text_media.clone(), &["tag1".to_string()]).await.unwrap();
+        client.tag_media(image_media.clone(), &["tag2".to_string()]).await.unwrap();
+        assert!(client.scan_modified_media().await.is_empty());
+
+        // only touched: the content hash is the same, the new modification time is recorded
+        touch(&text_file, 10);
+        assert!(client.scan_modified_media().await.is_empty());
+        assert_eq!(client.rescan_media().await.unwrap(), RescanResult { modified: vec![], recorded: 1 });
+
+        image::RgbImage::from_pixel(4, 4, image::Rgb([255, 255, 255])).save(&image_file).unwrap();
+        touch(&image_file, 20);
+        let modified_media = client.scan_modified_media().await;
+        assert_eq!(modified_media.iter().map(|x| &x.id).collect::<Vec<_>>(), vec![&image_media.id]);
+        assert!(client.is_media_modified(&image_media.id));
+        assert_eq!(client.get_modified_media(MediaSort::default(), 10, 0).total_count, 1);
+
+        assert!(client.regenerate_thumbnail(&image_media.id).await.unwrap());
+        assert!(client.has_thumbnail(&image_media.id));
+        assert!(!client.regenerate_thumbnail(&text_media.id).await.unwrap());
+        assert!(client.record_file_info(&image_media.id).await.unwrap());
+        assert!(!client.is_media_modified(&image_media.id));
+
+        let mut client = TaganrogClient::new(client.cfg.clone(), FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        assert!(client.scan_modified_media().await.is_empty());
+        assert_eq!(client.undo().await.unwrap().map(|x| x.media_id().cloned()), Some(Some(image_media.id.clone())));
+        assert_eq!(client.scan_modified_media().await.len(), 1);
+
+        // what a background scan records isn't undone, the user's rescan before it is
+        touch(&text_file, 30);
+        let file_checks = TaganrogClient::<FileStorage>::check_files(client.get_media_to_check(), true).await;
+        assert_eq!(client.record_file_checks(file_checks, ChangeKind::Background).await.unwrap().recorded, 1);
+        assert_eq!(client.undo().await.unwrap().map(|x| x.media_id().cloned()), Some(Some(text_media.id.clone())));
+    }
+
+    #[tokio::test]
+    async fn test_verify_media() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), ..Default::default() };
+        let files = ["a.txt", "b.txt", "c.txt", "d.txt"].map(|x| temp_dir.path().join(x));
+        for file in files.iter() {
+            std::fs::write(file, b"content").unwrap();
+        }
+
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let mut media_vec = Vec::new();
+        for (i, file) in files.iter().enumerate() {
+            // the last one was added without a checksum
+            client.cfg.hash_content = i < 3;
+            let media = client.create_media_from_file(file).await.unwrap();
+            client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+            media_vec.push(media);
+        }
+        assert!(media_vec[0].content_hash.is_some());
+        assert!(media_vec[3].content_hash.is_none());
+
+        std::fs::write(&files[1], b"c0ntent").unwrap();
+        std::fs::remove_file(&files[2]).unwrap();
+        let progress = std::sync::Mutex::new(Vec::new());
+        let verifications = client.verify_media(media_vec.clone(), |x, total| progress.lock().unwrap().push((x, total))).await;
+        assert_eq!(progress.into_inner().unwrap().last(), Some(&(4, 4)));
+        let statuses = verifications.iter().map(|x| x.status.clone()).collect::<Vec<VerifyStatus>>();
+        assert_eq!(statuses[0], VerifyStatus::Ok);
+        assert!(matches!(&statuses[1], VerifyStatus::Mismatch { expected, .. } if Some(expected) == media_vec[1].content_hash.as_ref()));
+        assert_eq!(statuses[2], VerifyStatus::Missing);
+        assert_eq!(statuses[3], VerifyStatus::Unchecked { content_hash: media_vec[0].content_hash.clone().unwrap() });
+
+        assert_eq!(client.record_verifications(&verifications).await.unwrap(), 2);
+        let mut client = TaganrogClient::new(client.cfg.clone(), FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        assert!(client.get_media_by_id(&media_vec[0].id).unwrap().verified_at.is_some());
+        assert!(client.get_media_by_id(&media_vec[1].id).unwrap().verified_at.is_none());
+        let unchecked_media = client.get_media_by_id(&media_vec[3].id).unwrap();
+        assert_eq!(unchecked_media.content_hash, media_vec[0].content_hash);
+        assert!(unchecked_media.verified_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_prune_missing_media() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), ..Default::default() };
+        let file1 = temp_dir.path().join("a.txt");
+        let file2 = temp_dir.path().join("b.txt");
+        std::fs::write(&file1, b"a").unwrap();
+        std::fs::write(&file2, b"b").unwrap();
+
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        let media1 = client.create_media_from_file(&file1).await.unwrap();
+        let media2 = client.create_media_from_file(&file2).await.unwrap();
+        client.tag_media(media1.clone(), &["tag1".to_string()]).await.unwrap();
+        client.tag_media(media2.clone(), &["tag2".to_string()]).await.unwrap();
+        assert!(client.scan_missing_media().await.is_empty());
+
+        std::fs::remove_file(&file1).unwrap();
+        assert_eq!(client.scan_missing_media().await, vec![client.get_media_by_id(&media1.id).unwrap()]);
+        assert!(client.is_media_missing(&media1.id));
+        assert_eq!(client.get_missing_media(MediaSort::default(), 10, 0).total_count, 1);
+        assert_eq!(client.get_media_by_location(&file1.to_string_lossy()).unwrap().id, media1.id);
+
+        assert_eq!(client.prune_missing_media(true).await.unwrap().len(), 1);
+        assert_eq!(client.get_media_count(), 2);
+        assert_eq!(client.prune_missing_media(false).await.unwrap().len(), 1);
+        assert_eq!(client.get_media_count(), 1);
+        assert!(client.get_media_by_id(&media2.id).is_some());
+        assert!(!client.is_media_missing(&media1.id));
+        assert_eq!(client.search_media("tag1", MediaSort::default(), 10, 0).unwrap().total_count, 0);
+
+        client.undo().await.unwrap();
+        assert_eq!(client.get_media_by_id(&media1.id).unwrap().tags, vec!["tag1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_relink_media() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), thumbnails_dir: temp_dir.path().to_path_buf(), hash_content: true, ..Default::default() };
+        let library_dir = temp_dir.path().join("library");
+        let file = library_dir.join("a.txt");
+        let moved_dir = library_dir.join("moved");
+        std::fs::create_dir_all(&moved_dir).unwrap();
+        std::fs::write(&file, b"content").unwrap();
+        std::fs::write(moved_dir.join("b.txt"), b"content").unwrap();
+        let dirs = vec![library_dir];
+
+        let mut client = TaganrogClient::new(cfg, FileStorage::new(db_filepath.clone()).unwrap());
+        client.init().await.unwrap();
+        let media = client.create_media_from_file(&file).await.unwrap();
+        client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+        assert!(client.find_relink_candidates(&dirs).await.unwrap().is_empty());
+
+        let moved_file = moved_dir.join("a.txt");
+        std::fs::rename(&file, &moved_file).unwrap();
+        let candidates = client.find_relink_candidates(&dirs).await.unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].media.id, media.id);
+        assert_eq!(candidates[0].locations, vec![moved_file.to_string_lossy().to_string()]);
+        assert_eq!(candidates[0].relink_match, RelinkMatch::FilenameAndSize);
+
+        assert!(client.update_media_location(&media.id, moved_file.to_string_lossy().to_string()).await.unwrap());
+        assert!(client.get_media_by_location(&file.to_string_lossy()).is_none());
+        assert_eq!(client.get_media_by_location(&moved_file.to_string_lossy()).unwrap().id, media.id);
+        assert!(client.find_relink_candidates(&dirs).await.unwrap().is_empty());
+        assert_eq!(client.create_media_from_file(&moved_file).await.unwrap().id, media.id);
+
+        let mut client = TaganrogClient::new(client.cfg.clone(), FileStorage::new(db_filepath).unwrap());
+        client.init().await.unwrap();
+        let relinked_media = client.get_media_by_id(&media.id).unwrap();
+        assert_eq!(relinked_media.location, moved_file.to_string_lossy());
+        assert_eq!(relinked_media.filename, "a.txt");
+        assert_eq!(relinked_media.tags, vec!["tag1".to_string()]);
+
+        client.undo().await.unwrap();
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().location, file.to_string_lossy());
+
+        // a new file at the old location of a relinked media is another media
+        client.redo().await.unwrap();
+        std::fs::write(&file, b"new content").unwrap();
+        let new_media = client.create_media_from_file(&file).await.unwrap();
+        assert_ne!(new_media.id, media.id);
+        client.tag_media(new_media.clone(), &["tag2".to_string()]).await.unwrap();
+        assert_eq!(client.create_media_from_file(&file).await.unwrap().id, new_media.id);
+        assert_eq!(client.create_media_from_file(&moved_file).await.unwrap().id, media.id);
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().tags, vec!["tag1".to_string()]);
+
+        // a renamed file is found by its recorded content hash
+        let renamed_file = moved_dir.join("c.txt");
+        std::fs::rename(&moved_file, &renamed_file).unwrap();
+        let candidates = client.find_relink_candidates(&dirs).await.unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].locations, [moved_dir.join("b.txt"), renamed_file].map(|x| x.to_string_lossy().to_string()));
+        assert_eq!(candidates[0].relink_match, RelinkMatch::Content);
+    }
+
+    #[tokio::test]
+    async fn test_tag_media_writes_one_batch() {
+        let mut client = create_test_client().await;
+        let media = create_random_media();
+        let tags = vec!["tag1".to_string(), "tag2".to_string(), "tag1".to_string()];
+        let added_tags = client.tag_media(media.clone(), &tags).await.unwrap();
+        assert_eq!(added_tags, vec!["tag1".to_string(), "tag2".to_string()]);
+        assert_eq!(client.log_len, 3);
+
+        let added_tags = client.tag_media(media.clone(), &["tag2".to_string(), "tag3".to_string()]).await.unwrap();
+        assert_eq!(added_tags, vec!["tag3".to_string()]);
+        let removed_tags = client.remove_tags_from_media(&media.id, &["tag1".to_string(), "tag4".to_string()]).await.unwrap();
+        assert_eq!(removed_tags, vec!["tag1".to_string()]);
+
+        let operations = client.storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 3);
+        assert!(matches!(&operations[0], DbOperation::Batch { operations } if operations.len() == 3));
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().tags, vec!["tag2".to_string(), "tag3".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_history() {
+        let mut client = create_test_client().await.with_origin(Origin::Gui);
+        let media = create_random_media();
+        client.tag_media(media.clone(), &["tag1".to_string(), "tag2".to_string()]).await.unwrap();
+        client.remove_tag_from_media(&media.id, &"tag1".to_string()).await.unwrap();
+
+        let history = client.get_history().await.unwrap();
+        assert_eq!(history.iter().map(|x| x.0).collect::<Vec<usize>>(), vec![1, 1, 1, 2]);
+        let history = history.into_iter().map(|x| x.1).collect::<Vec<LoggedOperation>>();
+        assert!(matches!(&history[0].operation, DbOperation::CreateMedia { media: x } if x.id == media.id));
+        assert!(matches!(&history[3].operation, DbOperation::RemoveTag { tag, .. } if tag == "tag1"));
+        assert!(history.iter().all(|x| x.meta.origin == Origin::Gui && x.meta.timestamp.is_some()));
+        assert_eq!(history[0].meta, history[2].meta);
+    }
+
+    #[tokio::test]
+    async fn test_undo_redo() {
+        let mut client = create_test_client().await;
+        let media = create_random_media();
+        let tag1 = "tag1".to_string();
+        let tag2 = "tag2".to_string();
+        assert!(client.undo().await.unwrap().is_none());
+        client.tag_media(media.clone(), &[tag1.clone(), tag2.clone()]).await.unwrap();
+        client.remove_tag_from_media(&media.id, &tag1).await.unwrap();
+        client.delete_media(&media.id).await.unwrap();
+        assert_eq!(client.get_media_count(), 0);
+
+        // the deleted media comes back with its tags
+        assert!(matches!(client.undo().await.unwrap(), Some(DbOperation::DeleteMedia { .. })));
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().tags, vec![tag2.clone()]);
+        assert_eq!(client.get_query_count(&["tag2".to_string()]), 1);
+        assert!(matches!(client.undo().await.unwrap(), Some(DbOperation::RemoveTag { .. })));
+        assert_eq!(client.get_query_count(&["tag1".to_string()]), 1);
+
+        assert!(matches!(client.redo().await.unwrap(), Some(DbOperation::RemoveTag { .. })));
+        assert_eq!(client.get_query_count(&["tag1".to_string()]), 0);
+
+        // a new change drops what was left to redo
+        client.add_tag_to_media(&media.id, &"tag3".to_string()).await.unwrap();
+        assert!(client.redo().await.unwrap().is_none());
+
+        // undoing everything removes the media again, the state survives a restart
+        while client.undo().await.unwrap().is_some() {}
+        assert_eq!(client.get_media_count(), 0);
+        let mut restarted = TaganrogClient::new(client.cfg.clone(), std::mem::take(&mut client.storage));
+        restarted.init().await.unwrap();
+        assert_eq!(restarted.get_media_count(), 0);
+        assert_eq!(restarted.get_query_count(&[tag2.clone()]), 0);
+    }
+
+    #[tokio::test]
+    async fn test_undo_redo_with_other_process() {
+        let temp_dir = tempdir().unwrap();
+        let db_filepath = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_filepath, "").unwrap();
+        let cfg = AppConfig { db_filepath: db_filepath.clone(), ..Default::default() };
+        let media = create_random_media();
+        let mut gui = TaganrogClient::new(cfg.clone(), FileStorage::new(db_filepath.clone()).unwrap());
+        gui.init().await.unwrap();
+        let mut cli = TaganrogClient::new(cfg, FileStorage::new(db_filepath).unwrap());
+        cli.init().await.unwrap();
+
+        gui.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+        cli.sync().await.unwrap();
+        cli.add_tag_to_media(&media.id, &"tag2".to_string()).await.unwrap();
+        gui.add_tag_to_media(&media.id, &"tag3".to_string()).await.unwrap();
+        assert!(matches!(gui.undo().await.unwrap(), Some(DbOperation::AddTag { tag, .. }) if tag == "tag3"));
+
+        // the stack follows the changes of the other process
+        cli.sync().await.unwrap();
+        cli.add_tag_to_media(&media.id, &"tag4".to_string()).await.unwrap();
+        assert!(gui.redo().await.unwrap().is_none());
+        assert!(matches!(gui.undo().await.unwrap(), Some(DbOperation::AddTag { tag, .. }) if tag == "tag4"));
+        assert!(matches!(gui.undo().await.unwrap(), Some(DbOperation::AddTag { tag, .. }) if tag == "tag2"));
+        assert!(matches!(cli.redo().await.unwrap(), Some(DbOperation::AddTag { tag, .. }) if tag == "tag2"));
+        assert!(matches!(gui.undo().await.unwrap(), Some(DbOperation::AddTag { tag, .. }) if tag == "tag2"));
+        assert_eq!(gui.get_media_by_id(&media.id).unwrap().tags, vec!["tag1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let mut client = create_test_client().await;
+        let media1 = create_random_media();
+        let media2 = create_random_media();
+        client.tag_media(media1.clone(), &["tag1".to_string()]).await.unwrap();
+        client.add_tag_to_media(&media1.id, &"tag2".to_string()).await.unwrap();
+        let timestamp = client.get_history().await.unwrap().last().unwrap().1.meta.timestamp.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        client.tag_media(media2.clone(), &["tag3".to_string()]).await.unwrap();
+        client.remove_tag_from_media(&media1.id, &"tag1".to_string()).await.unwrap();
+
+        // the dry run only reports the diff
+        let result = client.restore(RestorePoint::Timestamp(timestamp), true).await.unwrap();
+        assert_eq!((result.changes_kept, result.changes_dropped), (2, 2));
+        assert_eq!(result.diff.len(), 2);
+        assert_eq!(client.get_media_count(), 2);
+
+        let result = client.restore(RestorePoint::Line(1), false).await.unwrap();
+        assert_eq!((result.changes_kept, result.changes_dropped), (1, 3));
+        assert_eq!(client.get_media_count(), 1);
+        assert_eq!(client.get_media_by_id(&media1.id).unwrap().tags, vec!["tag1".to_string()]);
+        assert_eq!(client.storage.read_all().await.unwrap().len(), 2);
+
+        // the rewritten log keeps the timestamps of the changes, so it can still be cut
+        let result = client.restore(RestorePoint::Line(0), true).await.unwrap();
+        assert_eq!((result.changes_kept, result.changes_dropped), (0, 2));
+        assert_eq!(result.diff.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_restore_without_timestamps() {
+        let mut client = create_test_client().await;
+        let media1 = create_random_media();
+        let media2 = create_random_media();
+        // written by an old version
+        client.storage.write(DbOperation::CreateMedia { media: media1.clone() }.into()).await.unwrap();
+        client.tag_media(media2.clone(), &["tag1".to_string()]).await.unwrap();
+        let timestamp = client.get_history().await.unwrap().last().unwrap().1.meta.timestamp.unwrap();
+
+        let result = client.restore(RestorePoint::Timestamp(timestamp), true).await.unwrap();
+        assert_eq!((result.changes_kept, result.changes_dropped), (2, 0));
+        let before = timestamp - chrono::Duration::seconds(1);
+        assert!(matches!(client.restore(RestorePoint::Timestamp(before), true).await, Err(TaganrogError::CannotRestore(_))));
+        let result = client.restore(RestorePoint::Line(1), true).await.unwrap();
+        assert_eq!((result.changes_kept, result.changes_dropped), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_undo_stops_at_compaction() {
+        let mut client = create_test_client().await;
+        let media = create_random_media();
+        client.tag_media(media.clone(), &["tag1".to_string()]).await.unwrap();
+        client.compact().await.unwrap();
+        assert!(client.undo().await.unwrap().is_none());
+        client.add_tag_to_media(&media.id, &"tag2".to_string()).await.unwrap();
+        assert!(client.undo().await.unwrap().is_some());
+        assert!(client.undo().await.unwrap().is_none());
+        assert_eq!(client.get_media_by_id(&media.id).unwrap().tags, vec!["tag1".to_string()]);
     }
 }
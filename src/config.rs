@@ -1,15 +1,125 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use clap::ArgMatches;
 use colored::Color;
 use fern::colors::ColoredLevelConfig;
 use home::home_dir;
 use log::{error, info, LevelFilter};
+use serde::Deserialize;
+use crate::entities::MediaIdentity;
+use crate::storage::{FsyncPolicy, ReplayMode, StorageBackend};
 
-#[derive(Debug, Clone)]
+const DEFAULT_SNAPSHOT_INTERVAL: usize = 1000;
+const DEFAULT_SIMILAR_MAX_DISTANCE: u32 = 10;
+const DEFAULT_HASH_CONTENT: bool = true;
+/// Prefix of a location stored relative to a library root: `root://<root id>/<relative path>`.
+const ROOT_LOCATION_PREFIX: &str = "root://";
+
+#[derive(Debug, Clone, Default)]
 pub struct AppConfig {
     pub tg_homedir: PathBuf,
     pub db_filepath: PathBuf,
+    pub sqlite_db_filepath: PathBuf,
     pub thumbnails_dir: PathBuf,
+    pub auto_compact_threshold: Option<usize>,
+    pub snapshot_interval: usize,
+    pub fsync_policy: FsyncPolicy,
+    pub replay_mode: ReplayMode,
+    pub storage_backend: StorageBackend,
+    pub encrypt_thumbnails: bool,
+    pub media_identity: MediaIdentity,
+    pub similar_max_distance: u32,
+    pub hash_content: bool,
+    pub library_roots: LibraryRoots,
+}
+
+/// Named directories (e.g. the mount point of a shared drive) the locations of media are stored relative to,
+/// so that the library keeps working when a root is mounted at another path. In memory locations are absolute.
+#[derive(Debug, Clone, Default)]
+pub struct LibraryRoots {
+    roots: BTreeMap<String, PathBuf>,
+}
+
+impl LibraryRoots {
+    pub fn new(roots: BTreeMap<String, PathBuf>) -> Self {
+        Self { roots }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &PathBuf)> {
+        self.roots.iter()
+    }
+
+    pub fn get(&self, root_id: &str) -> Option<&PathBuf> {
+        self.roots.get(root_id)
+    }
+
+    /// The root an absolute location is under (the innermost one if roots are nested).
+    pub fn root_of(&self, location: &str) -> Option<(&String, &PathBuf)> {
+        self.roots.iter()
+            .filter(|(_, root)| Path::new(location).starts_with(root))
+            .max_by_key(|(_, root)| root.components().count())
+    }
+
+    /// Whether the location is under a root whose directory doesn't exist, e.g. an unplugged drive,
+    /// or is still relative to a root that was removed from the config (`resolve` left it unchanged).
+    pub fn is_offline(&self, location: &str) -> bool {
+        location.starts_with(ROOT_LOCATION_PREFIX) || self.root_of(location).is_some_and(|(_, root)| !root.is_dir())
+    }
+
+    /// The form a location is stored in: relative to its root (with `/` separators), or unchanged if it isn't under one.
+    pub fn to_stored(&self, location: &str) -> String {
+        let Some((root_id, root)) = self.root_of(location) else {
+            return location.to_string();
+        };
+        let relative_path = Path::new(location).strip_prefix(root).unwrap_or(Path::new(""))
+            .components()
+            .map(|x| x.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        format!("{}{}/{}", ROOT_LOCATION_PREFIX, root_id, relative_path)
+    }
+
+    /// The absolute location of a stored one. Locations under an unknown root are left unchanged.
+    pub fn resolve(&self, stored_location: &str) -> String {
+        let Some(root_location) = stored_location.strip_prefix(ROOT_LOCATION_PREFIX) else {
+            return stored_location.to_string();
+        };
+        let (root_id, relative_path) = root_location.split_once('/').unwrap_or((root_location, ""));
+        match self.roots.get(root_id) {
+            Some(root) => relative_path.split('/').filter(|x| !x.is_empty())
+                .fold(root.clone(), |path, x| path.join(x))
+                .to_string_lossy().to_string(),
+            None => stored_location.to_string(),
+        }
+    }
+}
+
+/// Optional user settings, read from `config.json` in the taganrog home dir.
+/// Every field may be omitted.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    /// Compact the DB on startup once it holds at least this many redundant operations.
+    auto_compact_threshold: Option<usize>,
+    /// Snapshot the DB state every this many operations, so that startup only replays the rest (0 disables).
+    snapshot_interval: Option<usize>,
+    /// `always` (default) or `never`.
+    fsync_policy: FsyncPolicy,
+    /// `strict` (default) refuses to open a DB with corrupted lines, `lenient` skips them.
+    replay_mode: ReplayMode,
+    /// `file` (default) or `sqlite`. Switching to `sqlite` imports the existing `taganrog.db.json` once.
+    storage_backend: StorageBackend,
+    /// Encrypt thumbnails too when the DB is encrypted.
+    encrypt_thumbnails: bool,
+    /// `path` (default), `sampled` or `blake3`. Run `rekey` after changing it.
+    media_identity: MediaIdentity,
+    /// How many of the 64 bits of their perceptual hashes two images may differ in to be similar.
+    similar_max_distance: Option<u32>,
+    /// Record the BLAKE3 of new files (default), so that `verify` can tell whether they rotted
+    /// and a rescan can tell a file that was only touched from one that was edited.
+    hash_content: Option<bool>,
+    /// Named library roots, `{"photos": "/mnt/share/photos"}`.
+    library_roots: BTreeMap<String, PathBuf>,
 }
 
 pub fn configure_console_logging(matches: &ArgMatches) {
@@ -107,12 +217,73 @@ pub fn get_app_config_or_exit() -> AppConfig {
         std::process::exit(1);
     }
 
+    let sqlite_db_filepath = tg_homedir.join("taganrog.db.sqlite");
+
+    let config_filepath = tg_homedir.join("config.json");
+    let config_file = if config_filepath.is_file() {
+        let config_str = std::fs::read_to_string(&config_filepath).expect("Failed to read config file");
+        match serde_json::from_str::<ConfigFile>(&config_str) {
+            Ok(config_file) => config_file,
+            Err(e) => {
+                error!("failed to parse config file {:?}: {}", config_filepath, e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        ConfigFile::default()
+    };
+
+    if let Some(root_id) = config_file.library_roots.keys().find(|x| x.is_empty() || x.contains('/')) {
+        error!("invalid library root id '{}': it must be non-empty and contain no '/'", root_id);
+        std::process::exit(1);
+    }
+    if let Some((root_id, _)) = config_file.library_roots.iter().find(|(_, x)| !x.is_absolute()) {
+        error!("library root '{}' is not an absolute path", root_id);
+        std::process::exit(1);
+    }
+
     let app_config = AppConfig {
         tg_homedir,
+        sqlite_db_filepath,
         db_filepath,
         thumbnails_dir,
+        auto_compact_threshold: config_file.auto_compact_threshold,
+        snapshot_interval: config_file.snapshot_interval.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL),
+        fsync_policy: config_file.fsync_policy,
+        replay_mode: config_file.replay_mode,
+        storage_backend: config_file.storage_backend,
+        encrypt_thumbnails: config_file.encrypt_thumbnails,
+        media_identity: config_file.media_identity,
+        similar_max_distance: config_file.similar_max_distance.unwrap_or(DEFAULT_SIMILAR_MAX_DISTANCE),
+        hash_content: config_file.hash_content.unwrap_or(DEFAULT_HASH_CONTENT),
+        library_roots: LibraryRoots::new(config_file.library_roots),
     };
     info!("config: {:?}", app_config);
 
     app_config
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_library_roots() {
+        let roots = LibraryRoots::new(BTreeMap::from([
+            ("share".to_string(), PathBuf::from("/mnt/share")),
+            ("photos".to_string(), PathBuf::from("/mnt/share/photos")),
+        ]));
+        assert_eq!(roots.to_stored("/mnt/share/docs/a.txt"), "root://share/docs/a.txt");
+        assert_eq!(roots.to_stored("/mnt/share/photos/2024/b.jpg"), "root://photos/2024/b.jpg");
+        assert_eq!(roots.to_stored("/mnt/shared/c.txt"), "/mnt/shared/c.txt");
+        assert_eq!(roots.resolve("root://photos/2024/b.jpg"), "/mnt/share/photos/2024/b.jpg");
+        assert_eq!(roots.resolve("root://unknown/d.txt"), "root://unknown/d.txt");
+        assert!(roots.is_offline("root://unknown/d.txt"));
+        assert_eq!(roots.resolve("/home/e.txt"), "/home/e.txt");
+
+        let moved_roots = LibraryRoots::new(BTreeMap::from([("photos".to_string(), PathBuf::from("/media/photos"))]));
+        assert_eq!(moved_roots.resolve(&roots.to_stored("/mnt/share/photos/2024/b.jpg")), "/media/photos/2024/b.jpg");
+        assert!(moved_roots.is_offline("/media/photos/2024/b.jpg"));
+        assert!(!moved_roots.is_offline("/mnt/share/photos/2024/b.jpg"));
+    }
+}
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use crate::storage::{ChangeKind, DbOperation, Origin};
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum InsertResult<T> {
@@ -18,9 +19,23 @@ impl<T> InsertResult<T> {
 pub type MediaId = String;
 pub type Tag = String;
 
+/// How `Media.id` is derived from a file.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaIdentity {
+    /// hash of the absolute path, moving the file makes it another media
+    #[default]
+    Path,
+    /// hash of the size and of samples of the content, fast even on big files
+    Sampled,
+    /// BLAKE3 of the whole content
+    Blake3,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct TagsAutocomplete {
-    pub head: Vec<Tag>,
+    /// The query typed before the completed tag, e.g. `(cat OR ` for `(cat OR d`.
+    pub head: String,
     pub last: Tag,
     pub media_count: usize,
 }
@@ -34,6 +49,53 @@ pub struct Media {
     pub size: i64,
     pub location: String,
     pub tags: Vec<Tag>,
+    /// dHash of an image, see `PerceptualHasher`
+    #[serde(default)]
+    pub perceptual_hash: Option<u64>,
+    /// Modification time of the file when it was added or last rescanned, `None` for media added by older versions
+    #[serde(default)]
+    pub modified_at: Option<DateTime<Utc>>,
+    /// BLAKE3 of the file, only recorded with `hash_content`
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// When the content of the file was last found to match `content_hash`
+    #[serde(default)]
+    pub verified_at: Option<DateTime<Utc>>,
+}
+
+impl Media {
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image")
+    }
+
+    pub fn file_info(&self) -> FileInfo {
+        FileInfo { size: self.size, modified_at: self.modified_at, content_hash: self.content_hash.clone() }
+    }
+
+    pub fn set_file_info(&mut self, file_info: FileInfo) {
+        self.size = file_info.size;
+        self.modified_at = file_info.modified_at;
+        self.content_hash = file_info.content_hash;
+    }
+
+    /// Moves the media to another file.
+    pub fn set_location(&mut self, location: String) {
+        self.filename = filename_of(&location);
+        self.location = location;
+    }
+}
+
+/// What is recorded about the file of a media to tell whether its content changed.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct FileInfo {
+    pub size: i64,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub content_hash: Option<String>,
+}
+
+/// The name of the file at `location`.
+pub fn filename_of(location: &str) -> String {
+    std::path::Path::new(location).file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default()
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
@@ -45,3 +107,224 @@ pub struct MediaPage {
     pub total_pages: usize,
     pub elapsed: u64,
 }
+
+/// How listed media are ordered. Ties are broken by id, so the pages of a listing don't change between requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MediaSort {
+    /// Latest added first.
+    #[default]
+    Newest,
+    Oldest,
+    /// Filename from A to Z, case-insensitive.
+    Name,
+    NameDesc,
+    Largest,
+    Smallest,
+    MostTags,
+    FewestTags,
+    /// Shuffled, the same seed gives the same order.
+    Random(u64),
+}
+
+impl MediaSort {
+    pub const NAMES: [&'static str; 9] = ["newest", "oldest", "name", "name-desc", "largest", "smallest", "most-tags", "fewest-tags", "random"];
+
+    /// What the media is ordered by, listings are sorted by `(key, id)`.
+    pub fn key(&self, media: &Media) -> MediaSortKey {
+        use std::cmp::Reverse;
+        match self {
+            MediaSort::Newest => MediaSortKey::Descending(Reverse(MediaSortValue::Time(media.created_at))),
+            MediaSort::Oldest => MediaSortKey::Ascending(MediaSortValue::Time(media.created_at)),
+            MediaSort::Name => MediaSortKey::Ascending(MediaSortValue::Text(media.filename.to_lowercase())),
+            MediaSort::NameDesc => MediaSortKey::Descending(Reverse(MediaSortValue::Text(media.filename.to_lowercase()))),
+            MediaSort::Largest => MediaSortKey::Descending(Reverse(MediaSortValue::Count(media.size))),
+            MediaSort::Smallest => MediaSortKey::Ascending(MediaSortValue::Count(media.size)),
+            MediaSort::MostTags => MediaSortKey::Descending(Reverse(MediaSortValue::Count(media.tags.len() as i64))),
+            MediaSort::FewestTags => MediaSortKey::Ascending(MediaSortValue::Count(media.tags.len() as i64)),
+            MediaSort::Random(seed) => MediaSortKey::Ascending(MediaSortValue::Hash(fastmurmur3::murmur3_x64_128(media.id.as_bytes(), *seed))),
+        }
+    }
+}
+
+/// See `MediaSort::key`, only keys of the same sort are compared.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MediaSortKey {
+    Ascending(MediaSortValue),
+    Descending(std::cmp::Reverse<MediaSortValue>),
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MediaSortValue {
+    Time(DateTime<Utc>),
+    Text(String),
+    Count(i64),
+    Hash(u128),
+}
+
+impl std::str::FromStr for MediaSort {
+    type Err = String;
+
+    /// One of `NAMES`, `random` gets a new seed unless it's given as `random:<seed>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid_sort = || format!("invalid sort '{}', expected one of {}", value, MediaSort::NAMES.join(", "));
+        match value.split_once(':') {
+            Some(("random", seed)) => return seed.parse().map(MediaSort::Random).map_err(|_| invalid_sort()),
+            Some(_) => return Err(invalid_sort()),
+            None => {},
+        }
+        match value {
+            "newest" => Ok(MediaSort::Newest),
+            "oldest" => Ok(MediaSort::Oldest),
+            "name" => Ok(MediaSort::Name),
+            "name-desc" => Ok(MediaSort::NameDesc),
+            "largest" => Ok(MediaSort::Largest),
+            "smallest" => Ok(MediaSort::Smallest),
+            "most-tags" => Ok(MediaSort::MostTags),
+            "fewest-tags" => Ok(MediaSort::FewestTags),
+            "random" => Ok(MediaSort::Random(rand::random())),
+            _ => Err(invalid_sort()),
+        }
+    }
+}
+
+impl std::fmt::Display for MediaSort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaSort::Newest => write!(f, "newest"),
+            MediaSort::Oldest => write!(f, "oldest"),
+            MediaSort::Name => write!(f, "name"),
+            MediaSort::NameDesc => write!(f, "name-desc"),
+            MediaSort::Largest => write!(f, "largest"),
+            MediaSort::Smallest => write!(f, "smallest"),
+            MediaSort::MostTags => write!(f, "most-tags"),
+            MediaSort::FewestTags => write!(f, "fewest-tags"),
+            MediaSort::Random(seed) => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct CompactionResult {
+    pub operations_before: usize,
+    pub operations_after: usize,
+}
+
+/// A past change, with the location of the media it was made to.
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    /// Number of the log entry the change belongs to, if known.
+    pub line: Option<usize>,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub origin: Origin,
+    pub kind: ChangeKind,
+    pub media_location: String,
+    pub operation: DbOperation,
+}
+
+/// How far back `restore` goes in the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Keep the changes made up to and including this time.
+    Timestamp(DateTime<Utc>),
+    /// Keep the first N changes, as numbered by the history.
+    Line(usize),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RestoreResult {
+    /// Number of changes kept from the log.
+    pub changes_kept: usize,
+    /// Number of changes dropped from the log.
+    pub changes_dropped: usize,
+    /// What restoring changes compared to the current state, one change per media.
+    pub diff: Vec<DbOperation>,
+}
+
+/// Media whose files have the same content.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// blake3 hash of the content
+    pub content_hash: String,
+    /// oldest first
+    pub media: Vec<Media>,
+}
+
+/// Why a file is thought to be the moved file of a media.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelinkMatch {
+    /// it has the content of the media, the one its id was derived from or its recorded content hash
+    Content,
+    /// it has the same filename and size, the content can't be compared with a path-based `MediaIdentity`
+    FilenameAndSize,
+}
+
+/// A media whose file is missing, with the files it might have been moved to.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RelinkCandidate {
+    pub media: Media,
+    pub locations: Vec<String>,
+    pub relink_match: RelinkMatch,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LibraryRootInfo {
+    pub id: String,
+    pub path: String,
+    /// False if the directory doesn't exist, e.g. the drive is unplugged.
+    pub is_online: bool,
+    pub media_count: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct MergeResult {
+    /// Tags the kept media got from the duplicates.
+    pub added_tags: Vec<Tag>,
+    /// Files (or thumbnails) of the merged duplicates that couldn't be deleted, with the reason.
+    pub undeleted_files: Vec<(String, String)>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct RebaseResult {
+    /// Media moved from the old directory under the root.
+    pub moved: usize,
+    /// Media stored relative to a root.
+    pub relative: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct RescanResult {
+    /// Media whose file content changed since it was recorded.
+    pub modified: Vec<Media>,
+    /// Media whose file info was recorded, because it had none yet or only the modification time changed.
+    pub recorded: usize,
+}
+
+/// What rehashing the file of a media found.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Ok,
+    /// No checksum was recorded yet, this is the current one.
+    Unchecked { content_hash: String },
+    /// The content changed (or rotted) since the checksum was recorded.
+    Mismatch { expected: String, actual: String },
+    Missing,
+    Unreadable(String),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub media: Media,
+    pub status: VerifyStatus,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct RekeyResult {
+    /// Media that got a new id.
+    pub rekeyed: usize,
+    /// Media merged into another one with the same content, or the same file reached through another path.
+    pub merged: usize,
+    /// Media whose location was replaced by the canonical path of its file (e.g. it was added through a symlink).
+    pub canonicalized: usize,
+    /// Media whose file is missing, they keep their id.
+    pub missing: usize,
+}
@@ -6,10 +6,40 @@ pub enum TaganrogError {
     DbIOError(std::io::Error),
     #[error("Failed to serialize/deserialize DB operation: {0}")]
     DbSerializationError(serde_json::Error),
+    #[error("SQLite DB error: {0}")]
+    DbSqliteError(rusqlite::Error),
+    #[error("DB format version {0} is too new, this version of taganrog supports up to version {1}. Please upgrade taganrog")]
+    DbVersionTooNew(u32, u32),
+    #[error("Invalid DB snapshot: {0}")]
+    DbSnapshotError(String),
+    #[error("DB line {0} is corrupted: {1}")]
+    DbCorruptedLine(usize, String),
+    #[error("DB is encrypted, a passphrase is needed to open it")]
+    DbLocked,
+    #[error("Wrong passphrase")]
+    DbWrongPassphrase,
+    #[error("DB encryption error: {0}")]
+    DbEncryptionError(String),
+    #[error("Cannot undo: {0}")]
+    CannotUndo(String),
+    #[error("Cannot restore: {0}")]
+    CannotRestore(String),
+    #[error("Media not found: {0}")]
+    MediaNotFound(String),
+    #[error("{0} is not a duplicate, its content is different")]
+    NotADuplicate(String),
+    #[error("{0} is not an image")]
+    NotAnImage(String),
+    #[error("Unknown library root '{0}'")]
+    UnknownLibraryRoot(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
     #[error("File not found")]
     FileNotFound,
     #[error("File read error: {0}")]
     FileReadError(std::io::Error),
+    #[error("File write error: {0}")]
+    FileWriteError(std::io::Error),
     #[error("File metadata error: {0}")]
     FileMetadataError(std::io::Error),
 }
@@ -5,6 +5,8 @@ pub mod web_ui;
 pub mod cli;
 pub mod utils;
 pub mod client;
+pub mod query;
+pub mod text_index;
 pub mod entities;
 pub mod config;
 pub mod storage;
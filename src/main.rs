@@ -1,9 +1,19 @@
+use std::collections::HashSet;
+use std::io::Write;
+use chrono::SecondsFormat;
 use clap::{Arg, Command};
-use log::{error, info};
+use itertools::Itertools;
+use log::{error, info, warn};
 use taganrog_lib::{cli, config, web_ui};
 use taganrog_lib::client::TaganrogClient;
 use taganrog_lib::config::AppConfig;
-use taganrog_lib::storage::FileStorage;
+use taganrog_lib::entities::{MediaSort, RelinkMatch, VerifyStatus};
+use taganrog_lib::error::TaganrogError;
+use taganrog_lib::query::QueryExpr;
+use taganrog_lib::storage::{AppStorage, ChangeKind, DbOperation, Origin, Storage};
+
+/// Passphrase of an encrypted DB, asked for interactively if not set.
+const PASSPHRASE_ENV: &str = "TAG_PASSPHRASE";
 
 #[tokio::main]
 async fn main() {
@@ -44,7 +54,91 @@ async fn main() {
                 .arg(Arg::new("page").required(false).help("Page number").long("page").short('p').default_value("1"))
                 .arg(Arg::new("page-size").required(false).help("Page size").long("page-size").short('s').default_value("10"))
                 .arg(Arg::new("all").required(false).help("List all media").long("all").short('a').action(clap::ArgAction::SetTrue))
-                .arg(Arg::new("tag").required(true).help("List of tags that is used for AND-matching media").num_args(1..).value_delimiter(' ')),
+                .arg(Arg::new("text").required(false).help("Search the words in the file names and paths, with typos allowed in long words").long("text").short('t').action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("sort").required(false).help("Order of the media: newest, oldest, name, name-desc, largest, smallest, most-tags, fewest-tags, random or random:<seed>").long("sort").default_value("newest"))
+                .arg(Arg::new("tag").required(true).help("Search query, e.g. `(cat OR dog) NOT black`").num_args(1..).value_delimiter(' ')),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("List past changes of a file, of a tag or of the whole database")
+                .arg(Arg::new("limit").required(false).help("Max number of changes to list").long("limit").short('n').default_value("50"))
+                .arg(Arg::new("all").required(false).help("List all changes").long("all").short('a').action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("target").required(false).help("Path of a file or a tag")),
+        )
+        .subcommand(
+            Command::new("undo")
+                .about("Undo the last change (made by the CLI or the desktop app) that wasn't undone yet"),
+        )
+        .subcommand(
+            Command::new("redo")
+                .about("Redo the last undone change"),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restore the database to an earlier point of its history. Rewrites the operations log like compact does.")
+                .arg(Arg::new("at").required(true).help("Change number (as listed by history) or date and time to restore to").long("at"))
+                .arg(Arg::new("dry-run").required(false).help("Only list what would change").long("dry-run").action(clap::ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("Compact the database. Rewrites the operations log from the current state, keeping a backup of the old one."),
+        )
+        .subcommand(
+            Command::new("rekey")
+                .about("Derive the ids of all media again from their files, as configured by media_identity. Media with the same content are merged."),
+        )
+        .subcommand(
+            Command::new("similar")
+                .about("List images that look like the given one, e.g. resized or re-encoded copies")
+                .arg(Arg::new("distance").required(false).help("Max number of bits (of 64) the perceptual hashes may differ in, similar_max_distance from the config by default").long("distance").short('d'))
+                .arg(Arg::new("filepath").required(true).help("Path of the image")),
+        )
+        .subcommand(
+            Command::new("dupes")
+                .about("List media whose files have the same content")
+                .arg(Arg::new("merge").required(false).help("Keep the oldest media of every group with the tags of all, and forget the others").long("merge").action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("delete").required(false).help("With --merge, also delete the files of the others").long("delete").requires("merge").action(clap::ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Delete media from the database")
+                .arg(Arg::new("missing").required(false).help("Delete the media whose file doesn't exist anymore").long("missing").action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("dry-run").required(false).help("Only list what would be deleted").long("dry-run").action(clap::ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("rescan")
+                .about("Find media whose file content changed since it was added, and offer to regenerate their thumbnails")
+                .arg(Arg::new("yes").required(false).help("Regenerate the thumbnails without asking").long("yes").short('y').action(clap::ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Rehash the files of the media matching the tags (all media by default) and report those that changed, are missing or can't be read")
+                .arg(Arg::new("record").required(false).help("Record the verification time of the intact files, and the checksum of those that had none").long("record").action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("tags").required(false).help("Tags to verify the media of").num_args(0..)),
+        )
+        .subcommand(
+            Command::new("relink")
+                .about("Find the files of missing media in directories (they were moved or renamed) and point the media to them, keeping their tags and thumbnails")
+                .arg(Arg::new("auto").required(false).help("Relink media with a single candidate without asking, skip the rest").long("auto").action(clap::ArgAction::SetTrue))
+                .arg(Arg::new("dir").required(true).help("Directories to search").num_args(1..)),
+        )
+        .subcommand(
+            Command::new("roots")
+                .about("List the library roots, whether they are online and how many media are under them"),
+        )
+        .subcommand(
+            Command::new("rebase")
+                .about("Store the locations under library roots relative to them. Rewrites the operations log like compact does.")
+                .arg(Arg::new("from").required(false).help("Directory the media were under before, e.g. where the drive was mounted").long("from").requires("root"))
+                .arg(Arg::new("root").required(false).help("Library root to move the media from --from under").long("root").requires("from")),
+        )
+        .subcommand(
+            Command::new("encrypt")
+                .about("Encrypt the database with a passphrase (read from TAG_PASSPHRASE or asked for), or change the passphrase of an encrypted one"),
+        )
+        .subcommand(
+            Command::new("decrypt")
+                .about("Decrypt the database, storing it in plaintext again"),
         );
 
     handle_command(command).await;
@@ -56,50 +150,46 @@ async fn handle_command(command: Command) {
         None => {
             config::configure_api_logging(&matches);
             let config = config::get_app_config_or_exit();
-            let client = create_taganrog_client(config.clone()).await;
+            let client = create_taganrog_client(config.clone(), Origin::Gui).await;
             web_ui::serve(config, client).await
         },
         Some(("tag", tag_matches)) => {
             config::configure_console_logging(&matches);
             let filepath: &String = tag_matches.get_one("filepath").unwrap();
-            let tags: Vec<&String> = tag_matches.get_many("tag").unwrap().collect();
-            let config = config::get_app_config_or_exit();
-            let mut client = create_taganrog_client(config).await;
-            for tag in tags {
-                match cli::tag_media(&mut client, filepath, tag).await {
-                    Ok(was_added) => {
-                        if was_added {
-                            info!("tagged media: {}", filepath);
-                        } else {
-                            info!("media already has tag: {}", filepath);
-                        }
-                    },
-                    Err(e) => {
-                        error!("failed to tag media: {}", e);
-                        std::process::exit(1);
+            let tags: Vec<String> = tag_matches.get_many::<String>("tag").unwrap().cloned().collect();
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::tag_media(&mut client, filepath, &tags).await {
+                Ok(added_tags) => {
+                    if added_tags.is_empty() {
+                        info!("media already has tags: {}", filepath);
+                    } else {
+                        info!("tagged media: {} ({})", filepath, added_tags.join(", "));
                     }
+                },
+                Err(e) => {
+                    error!("failed to tag media: {}", e);
+                    std::process::exit(1);
                 }
             }
         },
         Some(("untag", untag_matches)) => {
             config::configure_console_logging(&matches);
             let filepath: &String = untag_matches.get_one("filepath").unwrap();
-            let tags: Vec<&String> = untag_matches.get_many("tag").unwrap().collect();
-            let config = config::get_app_config_or_exit();
-            let mut client = create_taganrog_client(config).await;
-            for tag in tags {
-                match cli::untag_media(&mut client, filepath, tag).await {
-                    Ok(was_removed) => {
-                        if was_removed {
-                            info!("untagged media: {}", filepath);
-                        } else {
-                            info!("media does not have tag: {}", filepath);
-                        }
-                    },
-                    Err(e) => {
-                        error!("failed to untag media: {}", e);
-                        std::process::exit(1);
+            let tags: Vec<String> = untag_matches.get_many::<String>("tag").unwrap().cloned().collect();
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::untag_media(&mut client, filepath, &tags).await {
+                Ok(removed_tags) => {
+                    if removed_tags.is_empty() {
+                        info!("media does not have tags: {}", filepath);
+                    } else {
+                        info!("untagged media: {} ({})", filepath, removed_tags.join(", "));
                     }
+                },
+                Err(e) => {
+                    error!("failed to untag media: {}", e);
+                    std::process::exit(1);
                 }
             }
         },
@@ -109,7 +199,7 @@ async fn handle_command(command: Command) {
             let max_items = if all { usize::MAX } else { 10 };
             let tag_name: String = list_matches.get_one::<String>("tag").map(|x| x.to_owned()).unwrap_or_default();
             let config = config::get_app_config_or_exit();
-            let client = create_taganrog_client(config).await;
+            let client = create_taganrog_client(config, Origin::Cli).await;
             let tags_autocomplete = cli::list_tags(&client, tag_name, max_items).await;
             for tag_autocomplete in tags_autocomplete {
                 info!("[{}] {}", tag_autocomplete.media_count, tag_autocomplete.last);
@@ -121,18 +211,442 @@ async fn handle_command(command: Command) {
             let mut page_size: usize = search_matches.get_one::<String>("page-size").and_then(|x| x.parse::<usize>().ok()).unwrap_or(10);
             let all: bool = search_matches.get_flag("all");
             if all { page_size = usize::MAX; page = 1; }
-            let tags: Vec<String> = search_matches.get_many::<String>("tag").unwrap().map(|x| x.to_owned()).collect();
+            let mut tags: Vec<String> = search_matches.get_many::<String>("tag").unwrap().map(|x| x.to_owned()).collect();
+            if search_matches.get_flag("text") {
+                tags = QueryExpr::from_text(&tags.join(" ")).map(|x| vec![x.to_string()]).unwrap_or_default();
+            }
+            let sort = match search_matches.get_one::<String>("sort").unwrap().parse::<MediaSort>() {
+                Ok(sort) => sort,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
             let config = config::get_app_config_or_exit();
-            let client = create_taganrog_client(config).await;
+            let client = create_taganrog_client(config, Origin::Cli).await;
             let page_index = page - 1;
-            let media_page = cli::search_media(&client, tags, page_size, page_index).await;
+            let media_page = match cli::search_media(&client, tags.clone(), sort, page_size, page_index).await {
+                Ok(media_page) => media_page,
+                Err(e) => {
+                    error!("failed to search media: {}", e);
+                    std::process::exit(1);
+                }
+            };
 
+            if let MediaSort::Random(_) = sort {
+                // the next pages need the same seed
+                info!("sorted by {}", sort);
+            }
             info!("displaying page {}/{}", media_page.page_index + 1, media_page.total_pages);
             info!("total results: {}", media_page.total_count);
+            if media_page.total_count == 0 {
+                if let Some(suggestion) = cli::suggest_query(&client, tags).await {
+                    info!("did you mean: {}", suggestion);
+                }
+            }
             for media in media_page.media_vec {
                 info!("{}: {}", media.location, media.tags.join(", "));
             }
         },
+        Some(("history", history_matches)) => {
+            config::configure_console_logging(&matches);
+            let all: bool = history_matches.get_flag("all");
+            let limit: usize = history_matches.get_one::<String>("limit").and_then(|x| x.parse::<usize>().ok()).unwrap_or(50);
+            let max_items = if all { usize::MAX } else { limit };
+            let target: Option<&String> = history_matches.get_one("target");
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::get_history(&mut client, target.map(|x| x.as_str()), max_items).await {
+                Ok(entries) => {
+                    for entry in entries {
+                        let timestamp = entry.timestamp.map(|x| x.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or("unknown time".to_string());
+                        let kind = match entry.kind {
+                            ChangeKind::Edit => "",
+                            ChangeKind::Undo => " (undo)",
+                            ChangeKind::Redo => " (redo)",
+                            ChangeKind::Rewrite => " (rewritten)",
+                            ChangeKind::Background => " (background)",
+                        };
+                        let line = entry.line.map(|x| format!("#{} ", x)).unwrap_or_default();
+                        info!("{}{} [{}] {}: {}{}", line, timestamp, entry.origin, entry.media_location, describe_change(&entry.operation), kind);
+                    }
+                },
+                Err(e) => {
+                    error!("failed to read history: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("undo", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::undo(&mut client).await {
+                Ok(Some(entry)) => info!("undone: {}: {}", entry.media_location, describe_change(&entry.operation)),
+                Ok(None) => info!("nothing to undo"),
+                Err(e) => {
+                    error!("failed to undo: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("redo", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::redo(&mut client).await {
+                Ok(Some(entry)) => info!("redone: {}: {}", entry.media_location, describe_change(&entry.operation)),
+                Ok(None) => info!("nothing to redo"),
+                Err(e) => {
+                    error!("failed to redo: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("restore", restore_matches)) => {
+            config::configure_console_logging(&matches);
+            let at: &String = restore_matches.get_one("at").unwrap();
+            let dry_run: bool = restore_matches.get_flag("dry-run");
+            let point = match cli::parse_restore_point(at) {
+                Ok(point) => point,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::restore_db(&mut client, point, dry_run).await {
+                Ok((result, entries)) => {
+                    for entry in entries {
+                        info!("{}: {}", entry.media_location, describe_change(&entry.operation));
+                    }
+                    if dry_run {
+                        info!("would restore db: keep {} changes, drop {}", result.changes_kept, result.changes_dropped);
+                    } else {
+                        info!("restored db: kept {} changes, dropped {}", result.changes_kept, result.changes_dropped);
+                    }
+                },
+                Err(e) => {
+                    error!("failed to restore db: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("compact", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::compact_db(&mut client).await {
+                Ok(result) => {
+                    info!("compacted db: {} -> {} operations", result.operations_before, result.operations_after);
+                },
+                Err(e) => {
+                    error!("failed to compact db: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("rekey", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            info!("rekeying media by {:?}...", config.media_identity);
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::rekey_media(&mut client).await {
+                Ok(result) => {
+                    info!("rekeyed {} media, merged {} duplicates", result.rekeyed, result.merged);
+                    if result.canonicalized > 0 {
+                        info!("resolved the paths of {} media added through a symlink or a relative path", result.canonicalized);
+                    }
+                    if result.missing > 0 {
+                        warn!("{} media files are missing and kept their ids", result.missing);
+                    }
+                },
+                Err(e) => {
+                    error!("failed to rekey media: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("similar", similar_matches)) => {
+            config::configure_console_logging(&matches);
+            let filepath: &String = similar_matches.get_one("filepath").unwrap();
+            let config = config::get_app_config_or_exit();
+            let max_distance: u32 = similar_matches.get_one::<String>("distance").and_then(|x| x.parse::<u32>().ok()).unwrap_or(config.similar_max_distance);
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::find_similar_media(&mut client, filepath, max_distance).await {
+                Ok(similar_media) => {
+                    for (media, distance) in similar_media.iter() {
+                        info!("[{}] {}: {}", distance, media.location, media.tags.join(", "));
+                    }
+                    info!("found {} similar images", similar_media.len());
+                },
+                Err(e) => {
+                    error!("failed to find similar images: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("dupes", dupes_matches)) => {
+            config::configure_console_logging(&matches);
+            let merge: bool = dupes_matches.get_flag("merge");
+            let delete_files: bool = dupes_matches.get_flag("delete");
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            let groups = match cli::find_duplicates(&client).await {
+                Ok(groups) => groups,
+                Err(e) => {
+                    error!("failed to find duplicates: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            for group in groups.iter() {
+                info!("{} copies of {}:", group.media.len(), group.content_hash);
+                for media in group.media.iter() {
+                    info!("  {}: {}", media.location, media.tags.join(", "));
+                }
+                if !merge {
+                    continue;
+                }
+                match cli::merge_duplicates(&mut client, group, delete_files).await {
+                    Ok(result) => {
+                        info!("  kept {} (added tags: {})", group.media[0].location, result.added_tags.join(", "));
+                        for (path, reason) in result.undeleted_files.iter() {
+                            error!("  failed to delete {}: {}", path, reason);
+                        }
+                    },
+                    Err(e) => {
+                        error!("failed to merge duplicates: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            let duplicates_count = groups.iter().map(|x| x.media.len() - 1).sum::<usize>();
+            match (merge, delete_files) {
+                (false, _) => info!("found {} duplicates", duplicates_count),
+                (true, false) => info!("forgot {} duplicates", duplicates_count),
+                (true, true) => info!("deleted {} duplicates", duplicates_count),
+            }
+        },
+        Some(("prune", prune_matches)) => {
+            config::configure_console_logging(&matches);
+            if !prune_matches.get_flag("missing") {
+                error!("nothing to prune, use --missing");
+                std::process::exit(1);
+            }
+            let dry_run: bool = prune_matches.get_flag("dry-run");
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            match cli::prune_missing_media(&mut client, dry_run).await {
+                Ok(missing_media) => {
+                    for media in missing_media.iter() {
+                        info!("{}: {}", media.location, media.tags.join(", "));
+                    }
+                    if dry_run {
+                        info!("would delete {} missing media", missing_media.len());
+                    } else {
+                        info!("deleted {} missing media", missing_media.len());
+                    }
+                },
+                Err(e) => {
+                    error!("failed to prune missing media: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("rescan", rescan_matches)) => {
+            config::configure_console_logging(&matches);
+            let yes: bool = rescan_matches.get_flag("yes");
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            let result = match cli::rescan_media(&mut client).await {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("failed to rescan media: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if result.recorded > 0 {
+                info!("recorded the file info of {} media", result.recorded);
+            }
+            for media in result.modified.iter() {
+                info!("{}: {}", media.location, media.tags.join(", "));
+            }
+            info!("found {} modified media", result.modified.len());
+            if result.modified.is_empty() || !(yes || prompt_confirmation("regenerate their thumbnails?")) {
+                return;
+            }
+            for media in result.modified.iter() {
+                match cli::refresh_media(&mut client, &media.id).await {
+                    Ok(true) => info!("regenerated the thumbnail of {}", media.location),
+                    Ok(false) => info!("removed the stale thumbnail of {}, it's drawn again when the file is added", media.location),
+                    Err(e) => warn!("failed to regenerate the thumbnail of {}: {}", media.location, e),
+                }
+            }
+        },
+        Some(("verify", verify_matches)) => {
+            config::configure_console_logging(&matches);
+            let record: bool = verify_matches.get_flag("record");
+            let tags: Vec<String> = verify_matches.get_many::<String>("tags").map(|x| x.map(|x| x.to_owned()).collect()).unwrap_or_default();
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            let on_progress = |verified_count: usize, total_count: usize| {
+                eprint!("\rverified {}/{}", verified_count, total_count);
+                if verified_count == total_count {
+                    eprintln!();
+                }
+            };
+            let verifications = match cli::verify_media(&mut client, tags, record, on_progress).await {
+                Ok(verifications) => verifications,
+                Err(e) => {
+                    error!("failed to verify media: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let mut failed_count = 0;
+            for verification in verifications.iter() {
+                let location = &verification.media.location;
+                match &verification.status {
+                    VerifyStatus::Ok | VerifyStatus::Unchecked { .. } => continue,
+                    VerifyStatus::Mismatch { expected, actual } => warn!("{}: checksum mismatch, expected {} but got {}", location, expected, actual),
+                    VerifyStatus::Missing => warn!("{}: missing", location),
+                    VerifyStatus::Unreadable(e) => warn!("{}: unreadable: {}", location, e),
+                }
+                failed_count += 1;
+            }
+            let unchecked_count = verifications.iter().filter(|x| matches!(x.status, VerifyStatus::Unchecked { .. })).count();
+            info!("verified {} media: {} ok, {} failed, {} without checksum", verifications.len(), verifications.len() - failed_count - unchecked_count, failed_count, unchecked_count);
+            if unchecked_count > 0 && !record {
+                info!("use --record to record the checksum of the media without one");
+            }
+            if failed_count > 0 {
+                std::process::exit(1);
+            }
+        },
+        Some(("relink", relink_matches)) => {
+            config::configure_console_logging(&matches);
+            let auto: bool = relink_matches.get_flag("auto");
+            let dirs: Vec<String> = relink_matches.get_many::<String>("dir").unwrap().map(|x| x.to_owned()).collect();
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            let candidates = match cli::find_relink_candidates(&client, &dirs).await {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    error!("failed to search for moved files: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            // a file may match several missing media, only the first one gets it
+            let mut taken_locations = HashSet::<String>::new();
+            let mut relinked_count = 0;
+            for candidate in candidates {
+                let locations = candidate.locations.into_iter().filter(|x| !taken_locations.contains(x)).collect::<Vec<String>>();
+                if locations.is_empty() {
+                    continue;
+                }
+                let media = candidate.media;
+                let location = if auto {
+                    if locations.len() > 1 {
+                        warn!("skipped {}: {} candidates", media.location, locations.len());
+                        continue;
+                    }
+                    locations[0].clone()
+                } else {
+                    match prompt_relink_location(&media.location, &media.tags, &locations, candidate.relink_match) {
+                        Some(location) => location,
+                        None => continue,
+                    }
+                };
+                match cli::relink_media(&mut client, &media.id, &location).await {
+                    Ok(_) => {
+                        info!("relinked {} -> {}", media.location, location);
+                        taken_locations.insert(location);
+                        relinked_count += 1;
+                    },
+                    Err(e) => {
+                        error!("failed to relink {}: {}", media.location, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            info!("relinked {} media", relinked_count);
+        },
+        Some(("roots", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            let client = create_taganrog_client(config, Origin::Cli).await;
+            let roots = cli::get_library_roots(&client).await;
+            if roots.is_empty() {
+                info!("no library roots configured");
+            }
+            for root in roots {
+                let status = if root.is_online { "online" } else { "offline" };
+                info!("{}: {} ({}, {} media)", root.id, root.path, status, root.media_count);
+            }
+        },
+        Some(("rebase", rebase_matches)) => {
+            config::configure_console_logging(&matches);
+            let from: Option<&String> = rebase_matches.get_one("from");
+            let root: Option<&String> = rebase_matches.get_one("root");
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            let from = from.zip(root).map(|(old_dir, root_id)| (old_dir.as_str(), root_id.as_str()));
+            match cli::rebase_locations(&mut client, from).await {
+                Ok(result) => {
+                    if from.is_some() {
+                        info!("moved {} media", result.moved);
+                    }
+                    info!("{} media are stored relative to a library root", result.relative);
+                },
+                Err(e) => {
+                    error!("failed to rebase locations: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("encrypt", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            let passphrase = match std::env::var(PASSPHRASE_ENV) {
+                Ok(passphrase) => passphrase,
+                Err(_) => {
+                    let passphrase = prompt_passphrase("new passphrase: ");
+                    if prompt_passphrase("repeat passphrase: ") != passphrase {
+                        error!("passphrases do not match");
+                        std::process::exit(1);
+                    }
+                    passphrase
+                },
+            };
+            if passphrase.is_empty() {
+                error!("passphrase is empty");
+                std::process::exit(1);
+            }
+            match cli::set_encryption(&mut client, Some(&passphrase)).await {
+                Ok(thumbnails_count) => info!("encrypted db and {} thumbnails", thumbnails_count),
+                Err(e) => {
+                    error!("failed to encrypt db: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(("decrypt", _)) => {
+            config::configure_console_logging(&matches);
+            let config = config::get_app_config_or_exit();
+            let mut client = create_taganrog_client(config, Origin::Cli).await;
+            if !client.is_encrypted() {
+                info!("db is not encrypted");
+                return;
+            }
+            match cli::set_encryption(&mut client, None).await {
+                Ok(thumbnails_count) => info!("decrypted db and {} thumbnails", thumbnails_count),
+                Err(e) => {
+                    error!("failed to decrypt db: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
         _ => {
             error!("invalid subcommand");
             std::process::exit(1);
@@ -140,17 +654,105 @@ async fn handle_command(command: Command) {
     }
 }
 
-async fn create_taganrog_client(config: AppConfig) -> TaganrogClient<FileStorage> {
+/// Unlocks an encrypted DB with the passphrase from `TAG_PASSPHRASE`, or one typed in the terminal (CLI only).
+/// Returns whether the DB is still locked.
+async fn unlock_if_needed(storage: &mut AppStorage, origin: Origin) -> Result<bool, TaganrogError> {
+    if !storage.is_locked().await? {
+        return Ok(false);
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) if origin == Origin::Gui => return Ok(true),
+        Err(_) => prompt_passphrase("passphrase: "),
+    };
+    storage.unlock(&passphrase).await?;
+    Ok(false)
+}
+
+fn prompt_passphrase(prompt: &str) -> String {
+    match rpassword::prompt_password(prompt) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            error!("failed to read passphrase (set {} instead): {}", PASSPHRASE_ENV, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Asks a yes/no question, no by default.
+fn prompt_confirmation(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    if std::io::stdout().flush().is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+/// Asks which of `locations` a missing media was moved to, `None` to skip it.
+fn prompt_relink_location(missing_location: &str, tags: &[String], locations: &[String], relink_match: RelinkMatch) -> Option<String> {
+    let match_description = match relink_match {
+        RelinkMatch::Content => "same content",
+        RelinkMatch::FilenameAndSize => "same filename and size",
+    };
+    println!("{} [{}] is missing, candidates ({}):", missing_location, tags.join(", "), match_description);
+    for (i, location) in locations.iter().enumerate() {
+        println!("  {}) {}", i + 1, location);
+    }
+    loop {
+        print!("relink to [1-{}] or s to skip: ", locations.len());
+        std::io::stdout().flush().ok()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        let answer = answer.trim();
+        if answer.eq_ignore_ascii_case("s") {
+            return None;
+        }
+        if let Some(location) = answer.parse::<usize>().ok().and_then(|x| x.checked_sub(1)).and_then(|x| locations.get(x)) {
+            return Some(location.clone());
+        }
+    }
+}
+
+fn describe_change(operation: &DbOperation) -> String {
+    match operation {
+        DbOperation::CreateMedia { .. } => "added".to_string(),
+        DbOperation::DeleteMedia { .. } => "deleted".to_string(),
+        DbOperation::AddTag { tag, .. } => format!("tagged with '{}'", tag),
+        DbOperation::RemoveTag { tag, .. } => format!("untagged from '{}'", tag),
+        DbOperation::UpdateLocation { location, .. } => format!("moved to {}", location),
+        DbOperation::UpdatePerceptualHash { .. } => "hashed".to_string(),
+        DbOperation::UpdateFileInfo { .. } => "rescanned".to_string(),
+        DbOperation::UpdateVerifiedAt { .. } => "verified".to_string(),
+        DbOperation::Batch { operations } => operations.iter().map(describe_change).join(", "),
+    }
+}
+
+async fn create_taganrog_client(config: AppConfig, origin: Origin) -> TaganrogClient<AppStorage> {
     info!("initializing storage...");
-    let storage_result = FileStorage::new(config.db_filepath.clone());
+    let storage_result = AppStorage::open(&config).await;
     if storage_result.is_err() {
         error!("failed to initialize storage: {}", storage_result.err().unwrap());
         std::process::exit(1);
     }
-    let storage = storage_result.unwrap();
+    let mut storage = storage_result.unwrap();
     info!("storage initialized!");
 
-    let mut client = TaganrogClient::new(config, storage);
+    let is_locked = match unlock_if_needed(&mut storage, origin).await {
+        Ok(is_locked) => is_locked,
+        Err(e) => {
+            error!("failed to unlock db: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut client = TaganrogClient::new(config, storage).with_origin(origin);
+    if is_locked {
+        // the desktop app asks for the passphrase itself
+        info!("db is encrypted, waiting for the passphrase");
+        return client;
+    }
 
     info!("initializing db...");
     let init_result = client.init().await;
@@ -158,6 +760,9 @@ async fn create_taganrog_client(config: AppConfig) -> TaganrogClient<FileStorage
         error!("failed to initialize client: {}", init_result.err().unwrap());
         std::process::exit(1);
     }
+    for issue in client.get_replay_issues() {
+        warn!("recovered from a damaged db: {}", issue);
+    }
     info!("db initialized!");
 
     client
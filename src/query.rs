@@ -0,0 +1,661 @@
+use std::fmt::Display;
+use chrono::{Datelike, Local, NaiveDate};
+use crate::entities::{Media, Tag};
+use crate::error::TaganrogError;
+use crate::utils::str_utils::{fuzzy_find, max_typos, StringExtensions};
+
+/// A parsed search query: tags combined with `AND` (or just spaces), `OR`, `NOT` (or `-`), parentheses
+/// and quoted terms, e.g. `(cat OR dog) -"black and white"`. `NOT` binds tightest, then `AND`, then `OR`.
+/// Terms are slugified like tags are, so `"Black and White"` is the tag `black-and-white`.
+/// Terms can also be filters on the media themselves, see `Filter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryExpr {
+    Term(Tag),
+    Filter(Filter),
+    Not(Box<QueryExpr>),
+    And(Vec<QueryExpr>),
+    Or(Vec<QueryExpr>),
+}
+
+impl QueryExpr {
+    /// Parses the query, `None` if it's blank.
+    pub fn parse(query: &str) -> Result<Option<QueryExpr>, TaganrogError> {
+        let tokens = tokenize(query)?;
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        let mut parser = Parser { tokens, index: 0 };
+        let expr = parser.parse_or()?;
+        if let Some((position, _)) = parser.peek_with_position() {
+            return Err(invalid_query(position, "unexpected ')'"));
+        }
+        Ok(Some(expr))
+    }
+
+    /// The free-text query matching the media whose location contains every word, e.g. `invoice 2024`
+    /// is `path:invoice path:2024`. `None` if it's blank.
+    pub fn from_text(text: &str) -> Option<QueryExpr> {
+        let mut filters = text.split_whitespace()
+            .map(|x| x.replace('"', ""))
+            .filter(|x| !x.is_empty())
+            .map(|x| QueryExpr::Filter(Filter::Path(x.to_lowercase())))
+            .collect::<Vec<QueryExpr>>();
+        match filters.len() {
+            0 => None,
+            1 => filters.pop(),
+            _ => Some(QueryExpr::And(filters)),
+        }
+    }
+
+    /// Whether the query is only tags that must all be there, e.g. `invoice 2024` but not `invoice OR -2024`.
+    pub fn is_term_list(&self) -> bool {
+        match self {
+            QueryExpr::Term(_) => true,
+            QueryExpr::And(exprs) => exprs.iter().all(|x| matches!(x, QueryExpr::Term(_))),
+            _ => false,
+        }
+    }
+
+    /// Every filter of the query, negated ones included, in order.
+    pub fn filters(&self) -> Vec<&Filter> {
+        match self {
+            QueryExpr::Term(_) => vec![],
+            QueryExpr::Filter(filter) => vec![filter],
+            QueryExpr::Not(expr) => expr.filters(),
+            QueryExpr::And(exprs) | QueryExpr::Or(exprs) => exprs.iter().flat_map(|x| x.filters()).collect(),
+        }
+    }
+
+    /// Every term of the query, negated ones included, in order.
+    pub fn terms(&self) -> Vec<&Tag> {
+        match self {
+            QueryExpr::Term(tag) => vec![tag],
+            QueryExpr::Filter(_) => vec![],
+            QueryExpr::Not(expr) => expr.terms(),
+            QueryExpr::And(exprs) | QueryExpr::Or(exprs) => exprs.iter().flat_map(|x| x.terms()).collect(),
+        }
+    }
+
+    /// The query with every term replaced by what `f` returns for it.
+    pub fn map_terms(&self, f: &mut impl FnMut(&Tag) -> Tag) -> QueryExpr {
+        match self {
+            QueryExpr::Term(tag) => QueryExpr::Term(f(tag)),
+            QueryExpr::Filter(_) => self.clone(),
+            QueryExpr::Not(expr) => QueryExpr::Not(Box::new(expr.map_terms(f))),
+            QueryExpr::And(exprs) => QueryExpr::And(exprs.iter().map(|x| x.map_terms(f)).collect()),
+            QueryExpr::Or(exprs) => QueryExpr::Or(exprs.iter().map(|x| x.map_terms(f)).collect()),
+        }
+    }
+
+    /// The query with every occurrence of the term removed, `None` if nothing is left.
+    pub fn without_term(&self, tag: &str) -> Option<QueryExpr> {
+        let without_terms = |exprs: &[QueryExpr]| exprs.iter().filter_map(|x| x.without_term(tag)).collect::<Vec<QueryExpr>>();
+        match self {
+            QueryExpr::Term(x) if x == tag => None,
+            QueryExpr::Term(_) | QueryExpr::Filter(_) => Some(self.clone()),
+            QueryExpr::Not(expr) => expr.without_term(tag).map(|x| QueryExpr::Not(Box::new(x))),
+            QueryExpr::And(exprs) => match without_terms(exprs) {
+                mut exprs if exprs.len() <= 1 => exprs.pop(),
+                exprs => Some(QueryExpr::And(exprs)),
+            },
+            QueryExpr::Or(exprs) => match without_terms(exprs) {
+                mut exprs if exprs.len() <= 1 => exprs.pop(),
+                exprs => Some(QueryExpr::Or(exprs)),
+            },
+        }
+    }
+}
+
+impl Display for QueryExpr {
+    /// The canonical form of the query, parsing it gives back the same expression.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryExpr::Term(tag) => write!(f, "{}", tag),
+            QueryExpr::Filter(filter) => write!(f, "{}", filter),
+            QueryExpr::Not(expr) => match expr.as_ref() {
+                QueryExpr::Term(_) | QueryExpr::Filter(_) | QueryExpr::Not(_) => write!(f, "-{}", expr),
+                _ => write!(f, "-({})", expr),
+            },
+            QueryExpr::And(exprs) => {
+                let operands = exprs.iter().map(|x| match x {
+                    QueryExpr::Or(_) => format!("({})", x),
+                    _ => x.to_string(),
+                }).collect::<Vec<String>>();
+                write!(f, "{}", operands.join(" "))
+            },
+            QueryExpr::Or(exprs) => {
+                let operands = exprs.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+                write!(f, "{}", operands.join(" OR "))
+            },
+        }
+    }
+}
+
+/// A condition on the media rather than on its tags, written `field:value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// `type:video`, the start of the content type, or the whole of it (`type:image/png`)
+    Type(String),
+    /// `ext:png`, the extension of the filename, case-insensitive
+    Ext(String),
+    /// `size:>10MB`, `size:<=500KB`... in decimal units like the sizes shown by the desktop app
+    Size(Comparison, u64),
+    /// `added:2024-01..2024-06`, `added:2024`, `added:2024-03-15..`: the local date the media was added on,
+    /// both ends are included
+    Added(Option<DateSpec>, Option<DateSpec>),
+    /// `name:foo`, a part of the filename, case-insensitive and with a typo or two for long terms (see `max_typos`)
+    Name(String),
+    /// `path:foo`, like `name:` but anywhere in the location
+    Path(String),
+}
+
+const FILTER_FIELDS: [&str; 6] = ["type", "ext", "size", "added", "name", "path"];
+
+const SIZE_UNITS: [(&str, u64); 5] = [("TB", 1_000_000_000_000), ("GB", 1_000_000_000), ("MB", 1_000_000), ("KB", 1_000), ("B", 1)];
+
+impl Filter {
+    /// Parses the value of one of the `FILTER_FIELDS`.
+    fn parse(field: &str, value: &str) -> Result<Filter, String> {
+        if value.is_empty() {
+            return Err(format!("missing value for '{}:'", field));
+        }
+        match field {
+            "type" => Ok(Filter::Type(value.to_lowercase())),
+            "ext" => match value.trim_start_matches('.').to_lowercase() {
+                ext if ext.is_empty() => Err(format!("invalid extension '{}'", value)),
+                ext => Ok(Filter::Ext(ext)),
+            },
+            "size" => {
+                let (comparison, size) = Comparison::split(value);
+                let bytes = parse_size(size).ok_or_else(|| format!("invalid size '{}', expected e.g. '>10MB'", value))?;
+                Ok(Filter::Size(comparison, bytes))
+            },
+            "added" => {
+                let invalid_date = || format!("invalid date '{}', expected e.g. '2024-01..2024-06'", value);
+                let parse_date = |x: &str| match x {
+                    "" => Ok(None),
+                    _ => DateSpec::parse(x).map(Some).ok_or_else(invalid_date),
+                };
+                let (from, to) = match value.split_once("..") {
+                    Some((from, to)) => (parse_date(from)?, parse_date(to)?),
+                    None => (parse_date(value)?, parse_date(value)?),
+                };
+                match (from, to) {
+                    (None, None) => Err(invalid_date()),
+                    (Some(from), Some(to)) if from.first_day() > to.last_day() => Err(format!("'{}' ends before it starts", value)),
+                    _ => Ok(Filter::Added(from, to)),
+                }
+            },
+            "name" => Ok(Filter::Name(value.to_lowercase())),
+            "path" => Ok(Filter::Path(value.to_lowercase())),
+            _ => Err(format!("unknown field '{}'", field)),
+        }
+    }
+
+    pub fn matches(&self, media: &Media) -> bool {
+        match self {
+            Filter::Type(content_type) => media.content_type == *content_type
+                || media.content_type.strip_prefix(content_type.as_str()).is_some_and(|x| x.starts_with('/')),
+            Filter::Ext(ext) => std::path::Path::new(&media.filename).extension()
+                .is_some_and(|x| x.to_string_lossy().to_lowercase() == *ext),
+            Filter::Size(comparison, bytes) => comparison.compare(media.size.max(0) as u64, *bytes),
+            Filter::Added(from, to) => {
+                let added_on = media.created_at.with_timezone(&Local).date_naive();
+                from.is_none_or(|x| added_on >= x.first_day()) && to.is_none_or(|x| added_on <= x.last_day())
+            },
+            Filter::Name(term) => fuzzy_find(&media.filename, term, max_typos(term)).is_some(),
+            Filter::Path(term) => fuzzy_find(&media.location, term, max_typos(term)).is_some(),
+        }
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::Type(content_type) => write!(f, "type:{}", quote_if_needed(content_type)),
+            Filter::Ext(ext) => write!(f, "ext:{}", quote_if_needed(ext)),
+            Filter::Size(comparison, bytes) => {
+                let (unit, unit_bytes) = SIZE_UNITS.iter().find(|(_, x)| bytes % x == 0).unwrap();
+                write!(f, "size:{}{}{}", comparison, bytes / unit_bytes, unit)
+            },
+            Filter::Added(from, to) => match (from, to) {
+                (Some(from), Some(to)) if from == to => write!(f, "added:{}", from),
+                _ => write!(
+                    f, "added:{}..{}",
+                    from.map(|x| x.to_string()).unwrap_or_default(),
+                    to.map(|x| x.to_string()).unwrap_or_default(),
+                ),
+            },
+            Filter::Name(term) => write!(f, "name:{}", quote_if_needed(term)),
+            Filter::Path(term) => write!(f, "path:{}", quote_if_needed(term)),
+        }
+    }
+}
+
+fn quote_if_needed(value: &str) -> String {
+    match value.chars().any(|c| c.is_whitespace() || ['(', ')', '"'].contains(&c)) {
+        true => format!("\"{}\"", value),
+        false => value.to_string(),
+    }
+}
+
+/// `10MB`, `1.5GB`, `300` (bytes)...
+fn parse_size(value: &str) -> Option<u64> {
+    let number_length = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(number_length);
+    let number = number.parse::<f64>().ok()?;
+    let unit_bytes = match unit.to_uppercase().as_str() {
+        "" => 1,
+        unit => SIZE_UNITS.iter().find(|(x, _)| *x == unit)?.1,
+    };
+    Some((number * unit_bytes as f64).round() as u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// Splits the comparison operator off the start of the value, no operator is `Equal`.
+    fn split(value: &str) -> (Comparison, &str) {
+        [(">=", Comparison::GreaterOrEqual), ("<=", Comparison::LessOrEqual), (">", Comparison::Greater), ("<", Comparison::Less), ("=", Comparison::Equal)]
+            .into_iter()
+            .find_map(|(operator, comparison)| value.strip_prefix(operator).map(|x| (comparison, x)))
+            .unwrap_or((Comparison::Equal, value))
+    }
+
+    fn compare(&self, left: u64, right: u64) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Comparison::Equal => Ok(()),
+            Comparison::Less => write!(f, "<"),
+            Comparison::LessOrEqual => write!(f, "<="),
+            Comparison::Greater => write!(f, ">"),
+            Comparison::GreaterOrEqual => write!(f, ">="),
+        }
+    }
+}
+
+/// A year, a month or a day: `2024`, `2024-01` or `2024-01-15`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateSpec {
+    year: i32,
+    month: Option<u32>,
+    day: Option<u32>,
+}
+
+impl DateSpec {
+    fn parse(value: &str) -> Option<DateSpec> {
+        let parts = value.split('-').collect::<Vec<&str>>();
+        if parts.len() > 3 || parts[0].len() != 4 {
+            return None;
+        }
+        let date = DateSpec {
+            year: parts[0].parse().ok()?,
+            month: parts.get(1).map(|x| x.parse()).transpose().ok()?,
+            day: parts.get(2).map(|x| x.parse()).transpose().ok()?,
+        };
+        // checks the month and the day
+        NaiveDate::from_ymd_opt(date.year, date.month.unwrap_or(1), date.day.unwrap_or(1))?;
+        Some(date)
+    }
+
+    fn first_day(&self) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.year, self.month.unwrap_or(1), self.day.unwrap_or(1)).unwrap()
+    }
+
+    fn last_day(&self) -> NaiveDate {
+        match (self.month, self.day) {
+            (Some(_), Some(_)) => self.first_day(),
+            (Some(month), None) => match month {
+                12 => NaiveDate::from_ymd_opt(self.year, 12, 31).unwrap(),
+                _ => NaiveDate::from_ymd_opt(self.year, month + 1, 1).unwrap().pred_opt().unwrap(),
+            },
+            _ => NaiveDate::from_ymd_opt(self.year, 12, 31).unwrap(),
+        }
+    }
+}
+
+impl Display for DateSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let first_day = self.first_day();
+        match (self.month, self.day) {
+            (Some(_), Some(_)) => write!(f, "{:04}-{:02}-{:02}", first_day.year(), first_day.month(), first_day.day()),
+            (Some(_), None) => write!(f, "{:04}-{:02}", first_day.year(), first_day.month()),
+            _ => write!(f, "{:04}", first_day.year()),
+        }
+    }
+}
+
+/// The canonical form of a search query, or the query as typed (trimmed) if it can't be parsed,
+/// so that the user can fix it.
+pub fn normalize_search_query(query: &str) -> String {
+    match QueryExpr::parse(query) {
+        Ok(Some(expr)) => expr.to_string(),
+        Ok(None) => String::new(),
+        Err(_) => query.trim().to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Term(Tag),
+    Filter(Filter),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Term(tag) => write!(f, "'{}'", tag),
+            Token::Filter(filter) => write!(f, "'{}'", filter),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+/// `position` is the 0-based index of the char the error is at, it's reported 1-based.
+fn invalid_query(position: usize, reason: &str) -> TaganrogError {
+    TaganrogError::InvalidQuery(format!("{} at position {}", reason, position + 1))
+}
+
+/// Splits the query into tokens with the char index they start at.
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, TaganrogError> {
+    let chars = query.chars().collect::<Vec<char>>();
+    let is_delimiter = |c: char| c.is_whitespace() || ['(', ')', '"'].contains(&c);
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let start = index;
+        let token = match chars[index] {
+            c if c.is_whitespace() => {
+                index += 1;
+                continue;
+            },
+            '(' => {
+                index += 1;
+                Token::Open
+            },
+            ')' => {
+                index += 1;
+                Token::Close
+            },
+            '-' => {
+                index += 1;
+                Token::Not
+            },
+            '"' => {
+                let length = chars[index + 1..].iter().position(|x| *x == '"')
+                    .ok_or_else(|| invalid_query(start, "unterminated quote"))?;
+                let term = chars[index + 1..index + 1 + length].iter().collect::<String>();
+                index += length + 2;
+                match term.slugify() {
+                    tag if tag.is_empty() => return Err(invalid_query(start, "empty quoted term")),
+                    tag => Token::Term(tag),
+                }
+            },
+            _ => {
+                while index < chars.len() && !is_delimiter(chars[index]) {
+                    index += 1;
+                }
+                let word = chars[start..index].iter().collect::<String>();
+                let maybe_field = word.split_once(':').map(|(field, _)| field.to_lowercase())
+                    .filter(|x| FILTER_FIELDS.contains(&x.as_str()));
+                if let Some(field) = maybe_field {
+                    let mut value = word[field.len() + 1..].to_string();
+                    // `name:"my file"`
+                    if value.is_empty() && chars.get(index) == Some(&'"') {
+                        let length = chars[index + 1..].iter().position(|x| *x == '"')
+                            .ok_or_else(|| invalid_query(index, "unterminated quote"))?;
+                        value = chars[index + 1..index + 1 + length].iter().collect::<String>();
+                        index += length + 2;
+                    }
+                    let filter = Filter::parse(&field, &value).map_err(|e| invalid_query(start, &e))?;
+                    tokens.push((start, Token::Filter(filter)));
+                    continue;
+                }
+                match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => match word.slugify() {
+                        // only punctuation, e.g. a stray comma
+                        tag if tag.is_empty() => continue,
+                        tag => Token::Term(tag),
+                    },
+                }
+            },
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(_, x)| x)
+    }
+
+    fn peek_with_position(&self) -> Option<(usize, &Token)> {
+        self.tokens.get(self.index).map(|(position, x)| (*position, x))
+    }
+
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<QueryExpr, TaganrogError> {
+        let mut operands = Vec::new();
+        loop {
+            // `(a OR b) OR c` is `a OR b OR c`
+            match self.parse_and()? {
+                QueryExpr::Or(exprs) => operands.extend(exprs),
+                expr => operands.push(expr),
+            }
+            if self.peek() != Some(&Token::Or) {
+                break;
+            }
+            self.next();
+        }
+        Ok(match operands.len() {
+            1 => operands.pop().unwrap(),
+            _ => QueryExpr::Or(operands),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<QueryExpr, TaganrogError> {
+        let mut operands = Vec::new();
+        loop {
+            match self.parse_not()? {
+                QueryExpr::And(exprs) => operands.extend(exprs),
+                expr => operands.push(expr),
+            }
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                },
+                Some(Token::Term(_) | Token::Filter(_) | Token::Not | Token::Open) => {},
+                _ => break,
+            }
+        }
+        Ok(match operands.len() {
+            1 => operands.pop().unwrap(),
+            _ => QueryExpr::And(operands),
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<QueryExpr, TaganrogError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(QueryExpr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_term()
+    }
+
+    fn parse_term(&mut self) -> Result<QueryExpr, TaganrogError> {
+        match self.next() {
+            Some((_, Token::Term(tag))) => Ok(QueryExpr::Term(tag)),
+            Some((_, Token::Filter(filter))) => Ok(QueryExpr::Filter(filter)),
+            Some((position, Token::Open)) => {
+                if self.peek() == Some(&Token::Close) {
+                    return Err(invalid_query(position, "empty parentheses"));
+                }
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some((_, Token::Close)) => Ok(expr),
+                    _ => Err(invalid_query(position, "unclosed '('")),
+                }
+            },
+            Some((position, token)) => Err(invalid_query(position, &format!("expected a term but found {}", token))),
+            None => Err(TaganrogError::InvalidQuery("expected a term at the end of the query".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    fn term(tag: &str) -> QueryExpr {
+        QueryExpr::Term(tag.to_string())
+    }
+
+    fn not(expr: QueryExpr) -> QueryExpr {
+        QueryExpr::Not(Box::new(expr))
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(QueryExpr::parse("  ").unwrap(), None);
+        assert_eq!(QueryExpr::parse("tag1").unwrap(), Some(term("tag1")));
+        assert_eq!(QueryExpr::parse("tag1 AND tag2").unwrap(), QueryExpr::parse("tag1  tag2").unwrap());
+        assert_eq!(QueryExpr::parse("tag1 -tag2 NOT tag3").unwrap(), Some(QueryExpr::And(vec![term("tag1"), not(term("tag2")), not(term("tag3"))])));
+        assert_eq!(
+            QueryExpr::parse("tag1 tag2 OR tag3").unwrap(),
+            Some(QueryExpr::Or(vec![QueryExpr::And(vec![term("tag1"), term("tag2")]), term("tag3")])),
+        );
+        assert_eq!(
+            QueryExpr::parse("(cat OR dog) -\"Black and White\"").unwrap(),
+            Some(QueryExpr::And(vec![QueryExpr::Or(vec![term("cat"), term("dog")]), not(term("black-and-white"))])),
+        );
+        assert_eq!(QueryExpr::parse("summer-beach or").unwrap(), Some(QueryExpr::And(vec![term("summer-beach"), term("or")])));
+        assert_eq!(QueryExpr::parse("\"OR\"").unwrap(), Some(term("or")));
+    }
+
+    #[test]
+    fn test_parse_query_errors() {
+        let error = |query: &str| QueryExpr::parse(query).unwrap_err().to_string();
+        assert_eq!(error("(tag1 OR tag2"), "Invalid query: unclosed '(' at position 1");
+        assert_eq!(error("tag1)"), "Invalid query: unexpected ')' at position 5");
+        assert_eq!(error("tag1 OR"), "Invalid query: expected a term at the end of the query");
+        assert_eq!(error("OR tag1"), "Invalid query: expected a term but found OR at position 1");
+        assert_eq!(error("tag1 \"tag2"), "Invalid query: unterminated quote at position 6");
+        assert_eq!(error("()"), "Invalid query: empty parentheses at position 1");
+    }
+
+    #[test]
+    fn test_display_query() {
+        for query in ["tag1", "tag1 tag2", "tag1 OR tag2", "(tag1 OR tag2) -tag3", "-(tag1 OR tag2)", "tag1 -(tag2 tag3)"] {
+            assert_eq!(QueryExpr::parse(query).unwrap().unwrap().to_string(), query);
+        }
+        assert_eq!(normalize_search_query(" tag1   AND  \"Tag 2\" "), "tag1 tag-2");
+        assert_eq!(normalize_search_query("(tag1 tag2) tag3 OR (tag4 OR tag5)"), "tag1 tag2 tag3 OR tag4 OR tag5");
+        assert_eq!(normalize_search_query(" (tag1 "), "(tag1");
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(
+            QueryExpr::parse("trip type:video size:>100MB").unwrap(),
+            Some(QueryExpr::And(vec![term("trip"), QueryExpr::Filter(Filter::Type("video".to_string())), QueryExpr::Filter(Filter::Size(Comparison::Greater, 100_000_000))])),
+        );
+        assert_eq!(QueryExpr::parse("ext:.PNG").unwrap(), Some(QueryExpr::Filter(Filter::Ext("png".to_string()))));
+        assert_eq!(QueryExpr::parse("Name:\"My File\"").unwrap(), Some(QueryExpr::Filter(Filter::Name("my file".to_string()))));
+        assert_eq!(QueryExpr::parse("foo:bar").unwrap(), Some(term("foo-bar")));
+        for query in ["size:<=1.5MB", "added:2024-01..2024-06", "added:2024", "added:..2024-02-29", "-name:\"my file\"", "(type:image OR ext:mp4) cat"] {
+            let expr = QueryExpr::parse(query).unwrap().unwrap();
+            assert_eq!(QueryExpr::parse(&expr.to_string()).unwrap().unwrap(), expr);
+        }
+        assert_eq!(normalize_search_query("size:>=1500KB added:2024-03..2024-03"), "size:>=1500KB added:2024-03");
+
+        let error = |query: &str| QueryExpr::parse(query).unwrap_err().to_string();
+        assert_eq!(error("cat size:>10XB"), "Invalid query: invalid size '>10XB', expected e.g. '>10MB' at position 5");
+        assert_eq!(error("added:2024-13"), "Invalid query: invalid date '2024-13', expected e.g. '2024-01..2024-06' at position 1");
+        assert_eq!(error("added:2024-06..2024-01"), "Invalid query: '2024-06..2024-01' ends before it starts at position 1");
+        assert_eq!(error("name:"), "Invalid query: missing value for 'name:' at position 1");
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let media = Media {
+            filename: "Trip.MP4".to_string(),
+            content_type: "video/mp4".to_string(),
+            size: 150_000_000,
+            created_at: Local.with_ymd_and_hms(2024, 3, 31, 23, 0, 0).unwrap().with_timezone(&chrono::Utc),
+            ..Media::default()
+        };
+        let matches = |filter: &str| match QueryExpr::parse(filter).unwrap().unwrap() {
+            QueryExpr::Filter(filter) => filter.matches(&media),
+            _ => panic!("not a filter: {}", filter),
+        };
+        assert!(matches("type:video") && matches("type:video/mp4") && !matches("type:vid"));
+        assert!(matches("ext:mp4") && !matches("ext:png"));
+        assert!(matches("size:>100MB") && matches("size:<=150MB") && !matches("size:<150MB"));
+        assert!(matches("added:2024-01..2024-03") && matches("added:2024-03-31") && !matches("added:2024-04.."));
+        assert!(matches("name:trip") && matches("name:.mp4") && !matches("name:beach"));
+    }
+
+    #[test]
+    fn test_text_query() {
+        assert_eq!(QueryExpr::from_text(" 2024 \"Invoice\" ").unwrap().to_string(), "path:2024 path:invoice");
+        assert_eq!(QueryExpr::from_text("my (draft)").unwrap().to_string(), "path:my path:\"(draft)\"");
+        assert_eq!(QueryExpr::from_text("  "), None);
+        let expr = QueryExpr::from_text("my (draft)").unwrap();
+        assert_eq!(QueryExpr::parse(&expr.to_string()).unwrap().unwrap(), expr);
+
+        let is_term_list = |query: &str| QueryExpr::parse(query).unwrap().unwrap().is_term_list();
+        assert!(is_term_list("invoice") && is_term_list("invoice 2024"));
+        assert!(!is_term_list("invoice OR 2024") && !is_term_list("invoice -2024"));
+        assert!(!is_term_list("(invoice OR bill) 2024") && !is_term_list("invoice ext:pdf"));
+    }
+
+    #[test]
+    fn test_query_without_term() {
+        let query = QueryExpr::parse("(tag1 OR tag2) tag3 -tag1").unwrap().unwrap();
+        assert_eq!(query.without_term("tag1").unwrap().to_string(), "tag2 tag3");
+        assert_eq!(query.without_term("tag3").unwrap().to_string(), "(tag1 OR tag2) -tag1");
+        assert_eq!(QueryExpr::parse("tag1").unwrap().unwrap().without_term("tag1"), None);
+    }
+}
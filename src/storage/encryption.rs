@@ -0,0 +1,145 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng};
+use serde::{Deserialize, Serialize};
+use crate::error::TaganrogError;
+
+const KDF_NAME: &str = "argon2id";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Encrypted into the header, tells a wrong passphrase apart from a corrupted DB.
+const CHECK_PLAINTEXT: &[u8] = b"taganrog";
+
+/// How the key of an encrypted DB is derived from the passphrase. Stored in the DB header, none of it is secret.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionHeader {
+    pub kdf: String,
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// `CHECK_PLAINTEXT` encrypted with the key.
+    pub check: String,
+}
+
+/// A passphrase-derived key, every record is sealed with ChaCha20-Poly1305 under its own random nonce.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    header: EncryptionHeader,
+}
+
+impl Cipher {
+    /// Derives a new key from the passphrase with a random salt.
+    pub fn create(passphrase: &str) -> Result<Self, TaganrogError> {
+        Self::create_with_params(passphrase, Params::default())
+    }
+
+    fn create_with_params(passphrase: &str, params: Params) -> Result<Self, TaganrogError> {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|e| TaganrogError::DbEncryptionError(e.to_string()))?;
+        let mut header = EncryptionHeader {
+            kdf: KDF_NAME.to_string(),
+            salt: BASE64.encode(salt),
+            memory_kib: params.m_cost(),
+            iterations: params.t_cost(),
+            parallelism: params.p_cost(),
+            check: String::new(),
+        };
+        let aead = derive_key(passphrase, &header)?;
+        let mut cipher = Self { aead, header: header.clone() };
+        header.check = BASE64.encode(cipher.encrypt(CHECK_PLAINTEXT));
+        cipher.header = header;
+        Ok(cipher)
+    }
+
+    /// Derives the key of an existing DB, fails with `DbWrongPassphrase` if it isn't the one the DB was encrypted with.
+    pub fn unlock(passphrase: &str, header: &EncryptionHeader) -> Result<Self, TaganrogError> {
+        if header.kdf != KDF_NAME {
+            return Err(TaganrogError::DbEncryptionError(format!("unknown key derivation '{}'", header.kdf)));
+        }
+        let cipher = Self { aead: derive_key(passphrase, header)?, header: header.clone() };
+        let check = BASE64.decode(&header.check).map_err(|e| TaganrogError::DbEncryptionError(e.to_string()))?;
+        match cipher.decrypt(&check) {
+            Ok(plaintext) if plaintext == CHECK_PLAINTEXT => Ok(cipher),
+            _ => Err(TaganrogError::DbWrongPassphrase),
+        }
+    }
+
+    pub fn header(&self) -> &EncryptionHeader {
+        &self.header
+    }
+
+    /// Returns the nonce followed by the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.aead.encrypt(&nonce, plaintext).expect("plaintext is too long");
+        let mut bytes = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        bytes
+    }
+
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        if bytes.len() < NONCE_LEN {
+            return Err("missing nonce".to_string());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        self.aead.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| "cannot decrypt".to_string())
+    }
+
+    /// Encrypts a DB line payload, the result is base64 so that the log stays line-based text.
+    pub fn encrypt_str(&self, plaintext: &str) -> String {
+        BASE64.encode(self.encrypt(plaintext.as_bytes()))
+    }
+
+    pub fn decrypt_str(&self, payload: &str) -> Result<String, String> {
+        let bytes = BASE64.decode(payload).map_err(|e| e.to_string())?;
+        String::from_utf8(self.decrypt(&bytes)?).map_err(|e| e.to_string())
+    }
+}
+
+fn derive_key(passphrase: &str, header: &EncryptionHeader) -> Result<ChaCha20Poly1305, TaganrogError> {
+    let encryption_error = |e: String| TaganrogError::DbEncryptionError(e);
+    let salt = BASE64.decode(&header.salt).map_err(|e| encryption_error(e.to_string()))?;
+    let params = Params::new(header.memory_kib, header.iterations, header.parallelism, None)
+        .map_err(|e| encryption_error(e.to_string()))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| encryption_error(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// A cheap key derivation, so that tests don't spend their time in argon2.
+#[cfg(test)]
+pub(crate) fn create_test_cipher(passphrase: &str) -> Cipher {
+    Cipher::create_with_params(passphrase, Params::new(Params::MIN_M_COST, 1, 1, None).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_roundtrip() {
+        let cipher = create_test_cipher("secret");
+        let payload = cipher.encrypt_str("{\"a\":1}");
+        assert_ne!(payload, cipher.encrypt_str("{\"a\":1}"));
+        assert_eq!(cipher.decrypt_str(&payload).unwrap(), "{\"a\":1}");
+
+        let mut bytes = BASE64.decode(&payload).unwrap();
+        bytes[NONCE_LEN] ^= 1;
+        assert!(cipher.decrypt(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_unlock() {
+        let cipher = create_test_cipher("secret");
+        let unlocked = Cipher::unlock("secret", cipher.header()).unwrap();
+        assert_eq!(unlocked.decrypt(&cipher.encrypt(b"data")).unwrap(), b"data");
+        assert!(matches!(Cipher::unlock("wrong", cipher.header()), Err(TaganrogError::DbWrongPassphrase)));
+    }
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::error::TaganrogError;
+use crate::storage::encryption::EncryptionHeader;
+
+/// Version of the DB file format written by this build.
+pub const CURRENT_VERSION: u32 = 10;
+/// Files without a header are version 1.
+pub const LEGACY_VERSION: u32 = 1;
+
+const FORMAT_NAME: &str = "taganrog-db";
+const HEADER_PREFIX: char = '#';
+
+/// The first line of the DB file: `#{"format":"taganrog-db","version":8}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbHeader {
+    pub format: String,
+    pub version: u32,
+    /// Set if every line of the DB is encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionHeader>,
+}
+
+impl Default for DbHeader {
+    fn default() -> Self {
+        Self { format: FORMAT_NAME.to_string(), version: CURRENT_VERSION, encryption: None }
+    }
+}
+
+impl DbHeader {
+    pub fn is_header_line(line: &[u8]) -> bool {
+        line.first() == Some(&(HEADER_PREFIX as u8))
+    }
+
+    pub fn serialize(&self) -> Result<String, TaganrogError> {
+        let header = serde_json::to_string(self).map_err(TaganrogError::DbSerializationError)?;
+        Ok(format!("{}{}\n", HEADER_PREFIX, header))
+    }
+
+    pub fn deserialize(line: &[u8]) -> Result<Self, TaganrogError> {
+        let invalid_header = |reason: String| TaganrogError::DbCorruptedLine(1, format!("invalid header: {}", reason));
+        let line = std::str::from_utf8(line).map_err(|e| invalid_header(e.to_string()))?;
+        let header = line.strip_prefix(HEADER_PREFIX).ok_or_else(|| invalid_header("missing prefix".to_string()))?;
+        let header: DbHeader = serde_json::from_str(header).map_err(|e| invalid_header(e.to_string()))?;
+        if header.format != FORMAT_NAME {
+            return Err(invalid_header(format!("unknown format '{}'", header.format)));
+        }
+        if header.version > CURRENT_VERSION {
+            return Err(TaganrogError::DbVersionTooNew(header.version, CURRENT_VERSION));
+        }
+        Ok(header)
+    }
+}
+
+/// Upgrades a single serialized operation from `from_version` to `from_version + 1`.
+/// May split an operation into several or drop it.
+struct Migration {
+    from_version: u32,
+    migrate: fn(Value) -> Result<Vec<Value>, String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // v2 only adds the header, operations are unchanged
+    Migration { from_version: 1, migrate: keep_operation },
+    // v3 adds batches, which older versions can't read
+    Migration { from_version: 2, migrate: keep_operation },
+    // v4 adds the timestamp and origin next to the operation, older lines have none
+    Migration { from_version: 3, migrate: keep_operation },
+    // v5 adds encrypted DBs, plain lines are unchanged
+    Migration { from_version: 4, migrate: keep_operation },
+    // v6 adds location updates
+    Migration { from_version: 5, migrate: keep_operation },
+    // v7 adds perceptual hashes of images
+    Migration { from_version: 6, migrate: keep_operation },
+    // v8 adds locations relative to a library root, older lines have absolute ones
+    Migration { from_version: 7, migrate: keep_operation },
+    // v9 adds the modification time and content hash of files, older media have none
+    Migration { from_version: 8, migrate: keep_operation },
+    // v10 adds the time files were last verified against their checksum
+    Migration { from_version: 9, migrate: keep_operation },
+];
+
+fn keep_operation(operation: Value) -> Result<Vec<Value>, String> {
+    Ok(vec![operation])
+}
+
+/// Brings a serialized operation written in `version` up to `CURRENT_VERSION`.
+pub fn upgrade_operation(operation: Value, version: u32) -> Result<Vec<Value>, String> {
+    let mut operations = vec![operation];
+    for migration in MIGRATIONS.iter().filter(|x| x.from_version >= version) {
+        operations = operations.into_iter()
+            .map(migration.migrate)
+            .collect::<Result<Vec<Vec<Value>>, String>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+    }
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let line = DbHeader::default().serialize().unwrap();
+        assert!(DbHeader::is_header_line(line.as_bytes()));
+        let header = DbHeader::deserialize(line.trim_end().as_bytes()).unwrap();
+        assert_eq!(header, DbHeader::default());
+    }
+
+    #[test]
+    fn test_header_too_new() {
+        let line = format!("#{{\"format\":\"taganrog-db\",\"version\":{}}}", CURRENT_VERSION + 1);
+        let result = DbHeader::deserialize(line.as_bytes());
+        assert!(matches!(result, Err(TaganrogError::DbVersionTooNew(found, CURRENT_VERSION)) if found == CURRENT_VERSION + 1));
+    }
+
+    #[test]
+    fn test_header_ignores_unknown_fields() {
+        let line = "#{\"format\":\"taganrog-db\",\"version\":2,\"something\":\"new\"}";
+        assert!(DbHeader::deserialize(line.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_every_version_has_migration() {
+        for version in LEGACY_VERSION..CURRENT_VERSION {
+            assert!(MIGRATIONS.iter().any(|x| x.from_version == version));
+        }
+    }
+}
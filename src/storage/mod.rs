@@ -0,0 +1,1468 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use crate::entities::{FileInfo, Media, MediaId, Tag};
+use crate::config::AppConfig;
+use crate::error::TaganrogError;
+use crate::storage::encryption::{Cipher, EncryptionHeader};
+use crate::storage::migration::DbHeader;
+use crate::storage::snapshot::{Snapshot, SnapshotFile};
+use crate::storage::sqlite::SqliteStorage;
+
+pub mod encryption;
+pub mod migration;
+pub mod snapshot;
+pub mod sqlite;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DbOperation {
+    CreateMedia { media: Media },
+    /// `previous` is the deleted media with its tags, so that the deletion can be undone.
+    DeleteMedia {
+        media_id: MediaId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous: Option<Media>,
+    },
+    AddTag { media_id: MediaId, tag: Tag },
+    RemoveTag { media_id: MediaId, tag: Tag },
+    /// The file of the media was moved, `previous` is its old location so that the move can be undone.
+    UpdateLocation {
+        media_id: MediaId,
+        location: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous: Option<String>,
+    },
+    /// The perceptual hash of an image was computed, `previous` is the one it had before.
+    UpdatePerceptualHash {
+        media_id: MediaId,
+        perceptual_hash: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous: Option<u64>,
+    },
+    /// The size, modification time or content hash of the file was recorded again, `previous` is what it was before.
+    UpdateFileInfo {
+        media_id: MediaId,
+        file_info: FileInfo,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous: Option<FileInfo>,
+    },
+    /// The file was verified against its checksum, `previous` is when it was verified before.
+    UpdateVerifiedAt {
+        media_id: MediaId,
+        verified_at: Option<DateTime<Utc>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous: Option<DateTime<Utc>>,
+    },
+    /// Operations that are applied all together or not at all.
+    Batch { operations: Vec<DbOperation> },
+}
+
+impl DbOperation {
+    /// The operations as they are written together: a single one as is, several as a `Batch`.
+    pub fn batch(mut operations: Vec<DbOperation>) -> Option<DbOperation> {
+        match operations.len() {
+            0 => None,
+            1 => Some(operations.remove(0)),
+            _ => Some(DbOperation::Batch { operations }),
+        }
+    }
+
+    /// The operation that reverts this one, `None` for a deletion, a move or a file info update that didn't record the previous state.
+    pub fn inverse(&self) -> Option<DbOperation> {
+        let inverse = match self {
+            DbOperation::CreateMedia { media } => DbOperation::DeleteMedia { media_id: media.id.clone(), previous: Some(media.clone()) },
+            DbOperation::DeleteMedia { previous, .. } => DbOperation::CreateMedia { media: previous.clone()? },
+            DbOperation::AddTag { media_id, tag } => DbOperation::RemoveTag { media_id: media_id.clone(), tag: tag.clone() },
+            DbOperation::RemoveTag { media_id, tag } => DbOperation::AddTag { media_id: media_id.clone(), tag: tag.clone() },
+            DbOperation::UpdateLocation { media_id, location, previous } => DbOperation::UpdateLocation {
+                media_id: media_id.clone(),
+                location: previous.clone()?,
+                previous: Some(location.clone()),
+            },
+            DbOperation::UpdatePerceptualHash { media_id, perceptual_hash, previous } => DbOperation::UpdatePerceptualHash {
+                media_id: media_id.clone(),
+                perceptual_hash: *previous,
+                previous: *perceptual_hash,
+            },
+            DbOperation::UpdateFileInfo { media_id, file_info, previous } => DbOperation::UpdateFileInfo {
+                media_id: media_id.clone(),
+                file_info: previous.clone()?,
+                previous: Some(file_info.clone()),
+            },
+            DbOperation::UpdateVerifiedAt { media_id, verified_at, previous } => DbOperation::UpdateVerifiedAt {
+                media_id: media_id.clone(),
+                verified_at: *previous,
+                previous: *verified_at,
+            },
+            DbOperation::Batch { operations } => {
+                let operations = operations.iter().rev().map(|x| x.inverse()).collect::<Option<Vec<DbOperation>>>()?;
+                DbOperation::Batch { operations }
+            },
+        };
+        Some(inverse)
+    }
+
+    /// The media this operation changes, for a batch the one of its first operation.
+    pub fn media_id(&self) -> Option<&MediaId> {
+        match self {
+            DbOperation::CreateMedia { media } => Some(&media.id),
+            DbOperation::DeleteMedia { media_id, .. } => Some(media_id),
+            DbOperation::AddTag { media_id, .. } => Some(media_id),
+            DbOperation::RemoveTag { media_id, .. } => Some(media_id),
+            DbOperation::UpdateLocation { media_id, .. } => Some(media_id),
+            DbOperation::UpdatePerceptualHash { media_id, .. } => Some(media_id),
+            DbOperation::UpdateFileInfo { media_id, .. } => Some(media_id),
+            DbOperation::UpdateVerifiedAt { media_id, .. } => Some(media_id),
+            DbOperation::Batch { operations } => operations.first().and_then(|x| x.media_id()),
+        }
+    }
+
+    /// The operation with every location in it (including those of recorded media) replaced by `f` of it.
+    pub fn map_locations(self, f: &impl Fn(&str) -> String) -> DbOperation {
+        let map_media = |media: Media| Media { location: f(&media.location), ..media };
+        match self {
+            DbOperation::CreateMedia { media } => DbOperation::CreateMedia { media: map_media(media) },
+            DbOperation::DeleteMedia { media_id, previous } => DbOperation::DeleteMedia { media_id, previous: previous.map(map_media) },
+            DbOperation::UpdateLocation { media_id, location, previous } => DbOperation::UpdateLocation {
+                media_id,
+                location: f(&location),
+                previous: previous.map(|x| f(&x)),
+            },
+            DbOperation::Batch { operations } => DbOperation::Batch {
+                operations: operations.into_iter().map(|x| x.map_locations(f)).collect(),
+            },
+            operation => operation,
+        }
+    }
+
+    /// How many plain operations this is, a batch counts its content.
+    pub fn operations_count(&self) -> usize {
+        match self {
+            DbOperation::Batch { operations } => operations.iter().map(|x| x.operations_count()).sum(),
+            _ => 1,
+        }
+    }
+}
+
+/// Where a change was made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// written before origins were recorded
+    #[default]
+    Unknown,
+    Cli,
+    Gui,
+    /// applied automatically by a tagging rule (reserved, no rule writes yet)
+    Rule,
+    /// copied from another DB (e.g. the JSON log into SQLite) without a recorded origin
+    Import,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Origin::Unknown => "unknown",
+            Origin::Cli => "cli",
+            Origin::Gui => "gui",
+            Origin::Rule => "rule",
+            Origin::Import => "import",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Whether an operation is a change of its own or reverts/repeats an earlier one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    #[default]
+    Edit,
+    /// compensates the latest edit (or redo) that wasn't undone yet
+    Undo,
+    /// repeats the latest undone edit
+    Redo,
+    /// an edit kept by a rewrite (compaction, restore...), part of the base state that can't be undone
+    Rewrite,
+    /// bookkeeping of a background scan (file info, perceptual hashes), not a change the user made to undo
+    Background,
+}
+
+impl ChangeKind {
+    fn is_edit(&self) -> bool {
+        *self == ChangeKind::Edit
+    }
+
+    /// Whether the operation sets the state, rather than reverting or repeating another one.
+    fn is_change_of_its_own(&self) -> bool {
+        matches!(self, ChangeKind::Edit | ChangeKind::Rewrite | ChangeKind::Background)
+    }
+}
+
+/// When and where an operation was made.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub origin: Origin,
+    #[serde(default, skip_serializing_if = "ChangeKind::is_edit")]
+    pub kind: ChangeKind,
+}
+
+impl OperationMeta {
+    pub fn now(origin: Origin) -> Self {
+        Self { timestamp: Some(Utc::now()), origin, kind: ChangeKind::Edit }
+    }
+}
+
+/// An operation as it's kept in the log: `{"AddTag":{...},"timestamp":"...","origin":"cli"}`.
+/// Lines written before the meta was recorded get the default one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedOperation {
+    #[serde(flatten)]
+    pub operation: DbOperation,
+    #[serde(flatten)]
+    pub meta: OperationMeta,
+}
+
+impl From<DbOperation> for LoggedOperation {
+    fn from(operation: DbOperation) -> Self {
+        Self { operation, meta: OperationMeta::default() }
+    }
+}
+
+/// Converts a state into the operations that recreate it: every media (tagged or not)
+/// followed by its tags, in the given order.
+pub(crate) fn media_to_operations(media_vec: impl IntoIterator<Item = Media>) -> Vec<DbOperation> {
+    let mut operations = Vec::new();
+    for mut media in media_vec {
+        let media_tags = std::mem::take(&mut media.tags);
+        let media_id = media.id.clone();
+        operations.push(DbOperation::CreateMedia { media });
+        for tag in media_tags {
+            operations.push(DbOperation::AddTag { media_id: media_id.clone(), tag });
+        }
+    }
+    operations
+}
+
+/// When and where the current state was made: the meta of the latest edit that created each media
+/// and of the latest one that added each of its tags. An undo or redo only counts when there's none,
+/// so that undoing the removal of a tag keeps when it was first added.
+/// Lets a rewrite of the storage keep the audit history of what survives it.
+#[derive(Debug, Default)]
+pub(crate) struct StateMeta {
+    created: HashMap<MediaId, OperationMeta>,
+    tagged: HashMap<(MediaId, Tag), OperationMeta>,
+}
+
+impl StateMeta {
+    pub fn from_history(history: &[LoggedOperation]) -> Self {
+        let mut state_meta = Self::default();
+        for logged_operation in history.iter() {
+            state_meta.record(&logged_operation.operation, &logged_operation.meta);
+        }
+        state_meta
+    }
+
+    pub fn record(&mut self, operation: &DbOperation, meta: &OperationMeta) {
+        match operation {
+            DbOperation::CreateMedia { media } => {
+                record_meta(&mut self.created, media.id.clone(), meta);
+                for tag in media.tags.iter() {
+                    record_meta(&mut self.tagged, (media.id.clone(), tag.clone()), meta);
+                }
+            },
+            DbOperation::AddTag { media_id, tag } => record_meta(&mut self.tagged, (media_id.clone(), tag.clone()), meta),
+            DbOperation::Batch { operations } => {
+                for operation in operations.iter() {
+                    self.record(operation, meta);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// The meta with the media renamed (e.g. by a rekey), a media that several were merged into keeps the earliest meta.
+    pub fn renamed(&self, renamed_ids: &HashMap<MediaId, MediaId>) -> Self {
+        let rename = |x: &MediaId| renamed_ids.get(x).unwrap_or(x).clone();
+        let mut state_meta = Self::default();
+        for (media_id, meta) in self.created.iter() {
+            keep_earliest_meta(&mut state_meta.created, rename(media_id), meta);
+        }
+        for ((media_id, tag), meta) in self.tagged.iter() {
+            keep_earliest_meta(&mut state_meta.tagged, (rename(media_id), tag.clone()), meta);
+        }
+        state_meta
+    }
+
+    /// Like `media_to_operations`, each operation with the meta of the change it stands for, the default one if it's unknown.
+    pub fn media_to_operations(&self, media_vec: impl IntoIterator<Item = Media>) -> Vec<LoggedOperation> {
+        media_to_operations(media_vec).into_iter()
+            .map(|operation| {
+                let meta = match &operation {
+                    DbOperation::CreateMedia { media } => self.created.get(&media.id),
+                    DbOperation::AddTag { media_id, tag } => self.tagged.get(&(media_id.clone(), tag.clone())),
+                    _ => None,
+                };
+                LoggedOperation { meta: meta.cloned().unwrap_or_default(), operation }
+            })
+            .collect()
+    }
+}
+
+fn record_meta<K: Eq + std::hash::Hash>(metas: &mut HashMap<K, OperationMeta>, key: K, meta: &OperationMeta) {
+    if meta.kind.is_change_of_its_own() || !metas.contains_key(&key) {
+        metas.insert(key, OperationMeta { kind: ChangeKind::Rewrite, ..meta.clone() });
+    }
+}
+
+fn keep_earliest_meta<K: Eq + std::hash::Hash>(metas: &mut HashMap<K, OperationMeta>, key: K, meta: &OperationMeta) {
+    let is_earlier = |x: &OperationMeta| match (meta.timestamp, x.timestamp) {
+        (Some(timestamp), Some(other_timestamp)) => timestamp < other_timestamp,
+        (timestamp, other_timestamp) => timestamp.is_some() && other_timestamp.is_none(),
+    };
+    if metas.get(&key).is_none_or(is_earlier) {
+        metas.insert(key, meta.clone());
+    }
+}
+
+/// Changes made to the DB by other processes (e.g. `tgk tag` while the desktop app is open).
+#[derive(Debug)]
+pub enum LogChanges {
+    /// These operations were appended after the ones this process has seen.
+    Appended(Vec<LoggedOperation>),
+    /// The DB was rewritten (e.g. compacted) and has to be read again from scratch.
+    Replaced,
+}
+
+pub trait Storage {
+    async fn read_all(&mut self) -> Result<Vec<DbOperation>, TaganrogError>;
+    async fn write(&mut self, operation: LoggedOperation) -> Result<(), TaganrogError>;
+
+    /// Writes the operations so that after a crash either all or none of them are there.
+    /// A file log gets them as a single (checksummed) `DbOperation::Batch` line.
+    async fn write_batch(&mut self, operations: Vec<DbOperation>, meta: OperationMeta) -> Result<(), TaganrogError> {
+        match DbOperation::batch(operations) {
+            Some(operation) => self.write(LoggedOperation { operation, meta }).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns every operation still in the storage (i.e. since the last rewrite) with its meta, oldest first.
+    async fn read_history(&mut self) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        let operations = self.read_all().await?;
+        Ok(operations.into_iter().map(LoggedOperation::from).collect())
+    }
+
+    /// Atomically replaces the whole log with the given operations, which keep their meta.
+    async fn rewrite(&mut self, operations: Vec<LoggedOperation>) -> Result<(), TaganrogError>;
+
+    /// Returns (and forgets) the problems that were recovered from during the last `read_all`.
+    fn take_replay_issues(&mut self) -> Vec<TaganrogError> {
+        vec![]
+    }
+
+    /// Returns the latest snapshot of the state (if the storage keeps any)
+    /// and the operations that were written after it.
+    async fn read_latest(&mut self) -> Result<(Option<Snapshot>, Vec<DbOperation>), TaganrogError> {
+        let operations = self.read_all().await?;
+        Ok((None, operations))
+    }
+
+    /// Returns what other processes changed since this storage was last read or written.
+    async fn read_changes(&mut self) -> Result<LogChanges, TaganrogError> {
+        Ok(LogChanges::Appended(vec![]))
+    }
+
+    /// Whether `read_changes` may return anything, checked without taking any lock so that it can be polled.
+    async fn has_changes(&self) -> Result<bool, TaganrogError> {
+        Ok(false)
+    }
+
+    /// Persists the state as of everything read or written so far, if the storage supports snapshots.
+    async fn save_snapshot(&mut self, _snapshot: Snapshot) -> Result<(), TaganrogError> {
+        Ok(())
+    }
+
+    /// Whether the storage is encrypted and can't be read until it's unlocked with the passphrase.
+    async fn is_locked(&mut self) -> Result<bool, TaganrogError> {
+        Ok(false)
+    }
+
+    /// Sets the passphrase an encrypted storage is read with, fails if it's the wrong one.
+    async fn unlock(&mut self, _passphrase: &str) -> Result<(), TaganrogError> {
+        Ok(())
+    }
+
+    /// Rewrites the whole storage encrypted with `cipher`, a key derived from `passphrase`, or decrypted if `None`.
+    async fn set_encryption(&mut self, _passphrase: Option<&str>, _cipher: Option<Cipher>) -> Result<(), TaganrogError> {
+        Err(TaganrogError::DbEncryptionError("encryption is only supported by the file storage".to_string()))
+    }
+
+    /// The key the storage is encrypted with, `None` if it isn't.
+    fn cipher(&self) -> Option<&Cipher> {
+        None
+    }
+}
+
+/// Which storage the app keeps its DB in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// append-only JSON lines file (`taganrog.db.json`)
+    #[default]
+    File,
+    /// SQLite database (`taganrog.db.sqlite`)
+    Sqlite,
+}
+
+/// When the DB file is flushed to the disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// fsync after every written operation
+    #[default]
+    Always,
+    /// leave it to the OS
+    Never,
+}
+
+/// What to do with a corrupted line in the middle of the DB file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayMode {
+    /// refuse to open the DB
+    #[default]
+    Strict,
+    /// skip the line and report it
+    Lenient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+pub struct FileStorage {
+    db_path: PathBuf,
+    fsync_policy: FsyncPolicy,
+    replay_mode: ReplayMode,
+    replay_issues: Vec<TaganrogError>,
+    // how far this process has read or written the log
+    log_offset: u64,
+    log_lines: usize,
+    log_version: u32,
+    // hash of the log right before `log_offset`, tells whether another process replaced the log
+    log_anchor: u64,
+    // operations of other processes found while writing, not yet returned by `read_changes`
+    pending_operations: Vec<LoggedOperation>,
+    log_replaced: bool,
+    passphrase: Option<String>,
+    // key of the log as of its header, `None` if the log isn't encrypted
+    cipher: Option<Cipher>,
+}
+
+impl FileStorage {
+    pub fn new(db_path: PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            db_path,
+            fsync_policy: FsyncPolicy::default(),
+            replay_mode: ReplayMode::default(),
+            replay_issues: vec![],
+            log_offset: 0,
+            log_lines: 0,
+            log_version: migration::CURRENT_VERSION,
+            log_anchor: 0,
+            pending_operations: vec![],
+            log_replaced: false,
+            passphrase: None,
+            cipher: None,
+        })
+    }
+
+    pub fn with_fsync_policy(mut self, fsync_policy: FsyncPolicy) -> Self {
+        self.fsync_policy = fsync_policy;
+        self
+    }
+
+    pub fn with_replay_mode(mut self, replay_mode: ReplayMode) -> Self {
+        self.replay_mode = replay_mode;
+        self
+    }
+
+    fn db_header(&self) -> DbHeader {
+        DbHeader { encryption: self.cipher.as_ref().map(|x| x.header().clone()), ..Default::default() }
+    }
+
+    /// Picks the key for a log with the given encryption, deriving it again if the log was (re-)encrypted.
+    fn update_cipher(&mut self, encryption: Option<&EncryptionHeader>) -> Result<(), TaganrogError> {
+        self.cipher = match encryption {
+            None => None,
+            Some(header) if self.cipher.as_ref().is_some_and(|x| x.header() == header) => return Ok(()),
+            Some(header) => {
+                let passphrase = self.passphrase.as_deref().ok_or(TaganrogError::DbLocked)?;
+                Some(Cipher::unlock(passphrase, header)?)
+            },
+        };
+        Ok(())
+    }
+
+    /// Encrypts the bytes of a snapshot if the log is encrypted.
+    fn seal(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&bytes),
+            None => bytes,
+        }
+    }
+
+    fn open_sealed(&self, bytes: Vec<u8>) -> Result<Vec<u8>, TaganrogError> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&bytes).map_err(TaganrogError::DbSnapshotError),
+            None => Ok(bytes),
+        }
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        append_extension(&self.db_path, "lock")
+    }
+
+    /// Takes an advisory lock shared by all processes using this DB, released when the file is dropped.
+    /// The DB itself is not locked as `rewrite` replaces it with another file.
+    /// Waiting for the lock blocks, so it's done on a blocking thread.
+    async fn lock(&self, mode: LockMode) -> Result<std::fs::File, TaganrogError> {
+        let lock_path = self.lock_path();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+            match mode {
+                LockMode::Shared => file.lock_shared(),
+                LockMode::Exclusive => file.lock(),
+            }?;
+            Ok(file)
+        }).await
+            .map_err(|e| TaganrogError::DbIOError(std::io::Error::other(e)))?
+            .map_err(TaganrogError::DbIOError)
+    }
+
+    /// Takes the lock to read the log with: the shared one, or the exclusive one if `read_log` is going to repair
+    /// the last line. Writers hold the exclusive lock, so the last line can't get torn while the shared one is held.
+    async fn lock_to_read(&self) -> Result<std::fs::File, TaganrogError> {
+        let lock = self.lock(LockMode::Shared).await?;
+        if !self.has_incomplete_last_line()? {
+            return Ok(lock);
+        }
+        drop(lock);
+        self.lock(LockMode::Exclusive).await
+    }
+
+    fn has_incomplete_last_line(&self) -> Result<bool, TaganrogError> {
+        let mut file = match std::fs::File::open(&self.db_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(TaganrogError::DbIOError(e)),
+        };
+        if file.metadata().map_err(TaganrogError::DbIOError)?.len() == 0 {
+            return Ok(false);
+        }
+        let mut last_byte = [0u8];
+        file.seek(SeekFrom::End(-1)).map_err(TaganrogError::DbIOError)?;
+        file.read_exact(&mut last_byte).map_err(TaganrogError::DbIOError)?;
+        Ok(last_byte[0] != b'\n')
+    }
+
+    /// Reads the operations appended by other processes into `pending_operations`,
+    /// or sets `log_replaced` if the log is not the one this process has read. Expects the lock to be held.
+    async fn collect_changes(&mut self) -> Result<(), TaganrogError> {
+        if self.log_replaced {
+            return Ok(());
+        }
+        let log_len = match tokio::fs::metadata(&self.db_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(TaganrogError::DbIOError(e)),
+        };
+        if self.log_offset > 0 && (log_len < self.log_offset || snapshot::log_anchor(&self.db_path, self.log_offset)?  != self.log_anchor) {
+            self.log_replaced = true;
+            return Ok(());
+        }
+        if log_len > self.log_offset {
+            let operations = self.read_log(self.log_offset, self.log_lines).await?;
+            self.pending_operations.extend(operations);
+        }
+        Ok(())
+    }
+
+    pub fn backup_path(&self) -> PathBuf {
+        append_extension(&self.db_path, "bak")
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        append_extension(&self.db_path, "tmp")
+    }
+
+    /// Reads the operations written after the given offset. `lines` is the number of lines before it.
+    /// An incomplete last line gets repaired, so the exclusive lock has to be held if there's one, see `lock_to_read`.
+    async fn read_log(&mut self, offset: u64, lines: usize) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        let mut file = tokio::fs::File::open(&self.db_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        tokio::io::AsyncSeekExt::seek(&mut file, SeekFrom::Start(offset)).await
+            .map_err(TaganrogError::DbIOError)?;
+        let mut file_bytes = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut file, &mut file_bytes).await
+            .map_err(TaganrogError::DbIOError)?;
+        drop(file);
+
+        let mut operations = Vec::new();
+        let mut line_start = 0;
+        let mut line_number = lines;
+        if offset == 0 {
+            self.pending_operations.clear();
+            self.log_replaced = false;
+            self.log_version = migration::CURRENT_VERSION;
+            if !file_bytes.is_empty() {
+                self.log_version = migration::LEGACY_VERSION;
+            }
+            let mut encryption = None;
+            if DbHeader::is_header_line(&file_bytes) {
+                let header_end = file_bytes.iter().position(|x| *x == b'\n').unwrap_or(file_bytes.len());
+                let header = DbHeader::deserialize(&file_bytes[..header_end])?;
+                self.log_version = header.version;
+                encryption = header.encryption;
+                line_start = header_end + 1;
+                line_number += 1;
+            }
+            self.update_cipher(encryption.as_ref())?;
+        }
+        while line_start < file_bytes.len() {
+            let line_end = match file_bytes[line_start..].iter().position(|x| *x == b'\n') {
+                Some(pos) => line_start + pos,
+                None => {
+                    // a complete line that only lacks its newline is kept, anything else is torn
+                    let line = &file_bytes[line_start..];
+                    match deserialize_operations(line, self.log_version, self.cipher.as_ref()) {
+                        Ok(line_operations) => {
+                            operations.extend(line_operations);
+                            self.terminate_last_line().await?;
+                            file_bytes.push(b'\n');
+                            line_number += 1;
+                            line_start = file_bytes.len();
+                        },
+                        Err(_) => self.truncate_torn_tail(offset + line_start as u64, offset + file_bytes.len() as u64, line_number + 1).await?,
+                    }
+                    break;
+                },
+            };
+            line_number += 1;
+            let line = &file_bytes[line_start..line_end];
+            line_start = line_end + 1;
+            if line.is_empty() {
+                continue;
+            }
+            match deserialize_operations(line, self.log_version, self.cipher.as_ref()) {
+                Ok(line_operations) => operations.extend(line_operations),
+                Err(reason) => {
+                    let issue = TaganrogError::DbCorruptedLine(line_number, reason);
+                    if self.replay_mode == ReplayMode::Strict {
+                        return Err(issue);
+                    }
+                    self.replay_issues.push(issue);
+                },
+            }
+        }
+        self.log_offset = (offset + line_start as u64).min(offset + file_bytes.len() as u64);
+        self.log_lines = line_number;
+        self.log_anchor = snapshot::log_anchor(&self.db_path, self.log_offset)?;
+        Ok(operations)
+    }
+
+    /// Reads the whole log with its meta. Expects the lock to be held, see `lock_to_read`.
+    async fn read_whole_log(&mut self) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        // keep what other processes appended for `read_changes`
+        self.collect_changes().await?;
+        let pending_operations = std::mem::take(&mut self.pending_operations);
+        let log_replaced = self.log_replaced;
+        let history = self.read_log(0, 0).await?;
+        self.pending_operations = pending_operations;
+        self.log_replaced = log_replaced;
+        Ok(history)
+    }
+
+    /// Atomically replaces the log, keeping a backup of the old one. Expects the exclusive lock to be held.
+    async fn rewrite_log(&mut self, operations: &[LoggedOperation]) -> Result<(), TaganrogError> {
+        let mut content = self.db_header().serialize()?;
+        for operation in operations.iter() {
+            content.push_str(&serialize_operation(operation, self.cipher.as_ref())?);
+        }
+
+        // write the new log next to the old one and make sure it hits the disk
+        let temp_path = self.temp_path();
+        let mut file = tokio::fs::File::create(&temp_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, content.as_bytes()).await
+            .map_err(TaganrogError::DbIOError)?;
+        file.sync_all().await.map_err(TaganrogError::DbIOError)?;
+        drop(file);
+
+        // keep the previous log around, then atomically swap the new one in
+        if self.db_path.exists() {
+            tokio::fs::copy(&self.db_path, self.backup_path()).await
+                .map_err(TaganrogError::DbIOError)?;
+        }
+        tokio::fs::rename(&temp_path, &self.db_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        sync_parent_dir(&self.db_path)?;
+        self.log_offset = content.len() as u64;
+        self.log_lines = operations.len() + 1;
+        self.log_version = migration::CURRENT_VERSION;
+        self.log_anchor = snapshot::log_anchor(&self.db_path, self.log_offset)?;
+        self.pending_operations.clear();
+        self.log_replaced = false;
+
+        // snapshots point into the old log
+        for (_, snapshot_path) in snapshot::list_snapshots(&self.db_path) {
+            tokio::fs::remove_file(&snapshot_path).await.map_err(TaganrogError::DbIOError)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header without reading the rest of the file.
+    async fn read_header(&self) -> Result<DbHeader, TaganrogError> {
+        let file = tokio::fs::File::open(&self.db_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        let mut first_line = Vec::new();
+        let mut reader = tokio::io::BufReader::new(file);
+        tokio::io::AsyncBufReadExt::read_until(&mut reader, b'\n', &mut first_line).await
+            .map_err(TaganrogError::DbIOError)?;
+        if first_line.is_empty() {
+            return Ok(DbHeader::default());
+        }
+        if !DbHeader::is_header_line(&first_line) {
+            return Ok(DbHeader { version: migration::LEGACY_VERSION, ..Default::default() });
+        }
+        DbHeader::deserialize(first_line.trim_ascii_end())
+    }
+
+    async fn load_snapshot(&self, snapshot_path: &Path, log_offset: u64) -> Result<SnapshotFile, TaganrogError> {
+        let bytes = tokio::fs::read(snapshot_path).await.map_err(TaganrogError::DbIOError)?;
+        let snapshot_file = snapshot::decode(&self.open_sealed(bytes)?)?;
+        if snapshot_file.log_offset != log_offset {
+            return Err(TaganrogError::DbSnapshotError("offset mismatch".to_string()));
+        }
+        if snapshot::log_anchor(&self.db_path, log_offset)? != snapshot_file.log_anchor {
+            return Err(TaganrogError::DbSnapshotError("log was replaced".to_string()));
+        }
+        Ok(snapshot_file)
+    }
+
+    /// Appends the newline missing after a last line that was written completely. Expects the exclusive lock to be held.
+    async fn terminate_last_line(&self) -> Result<(), TaganrogError> {
+        let mut file = tokio::fs::OpenOptions::new().append(true).open(&self.db_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, b"\n").await.map_err(TaganrogError::DbIOError)?;
+        file.sync_all().await.map_err(TaganrogError::DbIOError)?;
+        Ok(())
+    }
+
+    /// Cuts off an incomplete last line, which is what a crash or a full disk leaves behind, and reports it as a replay issue.
+    /// Expects the exclusive lock to be held.
+    async fn truncate_torn_tail(&mut self, valid_len: u64, total_len: u64, line_number: usize) -> Result<(), TaganrogError> {
+        let file = tokio::fs::OpenOptions::new().write(true).open(&self.db_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        file.set_len(valid_len).await.map_err(TaganrogError::DbIOError)?;
+        file.sync_all().await.map_err(TaganrogError::DbIOError)?;
+        let reason = format!("incomplete last line, truncated {} bytes", total_len - valid_len);
+        self.replay_issues.push(TaganrogError::DbCorruptedLine(line_number, reason));
+        Ok(())
+    }
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path_str = path.as_os_str().to_owned();
+    path_str.push(".");
+    path_str.push(extension);
+    PathBuf::from(path_str)
+}
+
+fn checksum(payload: &str) -> u32 {
+    let mut hasher = twox_hash::XxHash32::with_seed(0);
+    hasher.write(payload.as_bytes());
+    hasher.finish() as u32
+}
+
+/// Every line is framed as `<xxhash32 of the payload, 8 hex digits> <json payload>\n`.
+/// In an encrypted log the payload is the base64 of the encrypted json.
+fn serialize_operation(operation: &LoggedOperation, cipher: Option<&Cipher>) -> Result<String, TaganrogError> {
+    let mut serialized_operation = serde_json::to_string(operation)
+        .map_err(TaganrogError::DbSerializationError)?;
+    if let Some(cipher) = cipher {
+        serialized_operation = cipher.encrypt_str(&serialized_operation);
+    }
+    Ok(format!("{:08x} {}\n", checksum(&serialized_operation), serialized_operation))
+}
+
+fn deserialize_payload(line: &[u8]) -> Result<&str, String> {
+    let line = std::str::from_utf8(line).map_err(|e| e.to_string())?;
+    // lines written before checksums were introduced are plain json
+    if line.starts_with('{') {
+        return Ok(line);
+    }
+    let (expected_checksum, payload) = line.split_once(' ').ok_or("missing checksum")?;
+    let expected_checksum = u32::from_str_radix(expected_checksum, 16).map_err(|_| "invalid checksum")?;
+    if checksum(payload) != expected_checksum {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok(payload)
+}
+
+/// Parses a line written in the given format version, upgrading it to the current one if needed.
+fn deserialize_operations(line: &[u8], version: u32, cipher: Option<&Cipher>) -> Result<Vec<LoggedOperation>, String> {
+    let payload = deserialize_payload(line)?;
+    let decrypted_payload;
+    let payload = match cipher {
+        Some(cipher) => {
+            decrypted_payload = cipher.decrypt_str(payload)?;
+            decrypted_payload.as_str()
+        },
+        None => payload,
+    };
+    if version == migration::CURRENT_VERSION {
+        let operation = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+        return Ok(vec![operation]);
+    }
+    let value = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    migration::upgrade_operation(value, version)?
+        .into_iter()
+        .map(|x| serde_json::from_value(x).map_err(|e| e.to_string()))
+        .collect()
+}
+
+impl Storage for FileStorage {
+    async fn read_all(&mut self) -> Result<Vec<DbOperation>, TaganrogError> {
+        let lock = self.lock_to_read().await?;
+        let operations = self.read_log(0, 0).await?;
+        drop(lock);
+        if self.log_version < migration::CURRENT_VERSION {
+            info!("upgrading db from version {} to {}", self.log_version, migration::CURRENT_VERSION);
+            let _lock = self.lock(LockMode::Exclusive).await?;
+            self.rewrite_log(&operations).await?;
+        }
+        Ok(operations.into_iter().map(|x| x.operation).collect())
+    }
+
+    async fn write(&mut self, operation: LoggedOperation) -> Result<(), TaganrogError> {
+        let _lock = self.lock(LockMode::Exclusive).await?;
+        self.collect_changes().await?;
+        if self.log_replaced {
+            // the line has to be encrypted with the key of the log it's appended to
+            let header = self.read_header().await?;
+            self.update_cipher(header.encryption.as_ref())?;
+        }
+        let mut line = serialize_operation(&operation, self.cipher.as_ref())?;
+        let mut file = tokio::fs::OpenOptions::new().append(true).open(&self.db_path).await
+            .map_err(TaganrogError::DbIOError)?;
+        let is_empty = file.metadata().await.map_err(TaganrogError::DbIOError)?.len() == 0;
+        if is_empty {
+            line.insert_str(0, &self.db_header().serialize()?);
+            self.log_lines += 1;
+        }
+        tokio::io::AsyncWriteExt::write_all(&mut file, line.as_bytes()).await
+            .map_err(TaganrogError::DbIOError)?;
+        if self.fsync_policy == FsyncPolicy::Always {
+            file.sync_data().await.map_err(TaganrogError::DbIOError)?;
+        }
+        self.log_offset += line.len() as u64;
+        self.log_lines += 1;
+        if !self.log_replaced {
+            self.log_anchor = snapshot::log_anchor(&self.db_path, self.log_offset)?;
+        }
+        Ok(())
+    }
+
+    async fn rewrite(&mut self, operations: Vec<LoggedOperation>) -> Result<(), TaganrogError> {
+        let _lock = self.lock(LockMode::Exclusive).await?;
+        self.rewrite_log(&operations).await
+    }
+
+    fn take_replay_issues(&mut self) -> Vec<TaganrogError> {
+        std::mem::take(&mut self.replay_issues)
+    }
+
+    async fn read_history(&mut self) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        let _lock = self.lock_to_read().await?;
+        self.read_whole_log().await
+    }
+
+    async fn read_latest(&mut self) -> Result<(Option<Snapshot>, Vec<DbOperation>), TaganrogError> {
+        // an outdated log gets upgraded and rewritten by `read_all`, which also drops its snapshots
+        let lock = self.lock_to_read().await?;
+        let header = self.read_header().await?;
+        self.log_version = header.version;
+        self.update_cipher(header.encryption.as_ref())?;
+        let snapshots = match self.log_version {
+            migration::CURRENT_VERSION => snapshot::list_snapshots(&self.db_path),
+            _ => vec![],
+        };
+        for (log_offset, snapshot_path) in snapshots {
+            match self.load_snapshot(&snapshot_path, log_offset).await {
+                Ok(snapshot_file) => {
+                    let operations = self.read_log(snapshot_file.log_offset, snapshot_file.log_lines as usize).await?
+                        .into_iter().map(|x| x.operation).collect();
+                    let snapshot = Snapshot { operations: snapshot_file.operations as usize, media: snapshot_file.media };
+                    return Ok((Some(snapshot), operations));
+                },
+                Err(e) => warn!("ignoring snapshot {:?}: {}", snapshot_path, e),
+            }
+        }
+        drop(lock);
+        let operations = self.read_all().await?;
+        Ok((None, operations))
+    }
+
+    async fn read_changes(&mut self) -> Result<LogChanges, TaganrogError> {
+        let _lock = self.lock_to_read().await?;
+        self.collect_changes().await?;
+        if self.log_replaced {
+            return Ok(LogChanges::Replaced);
+        }
+        Ok(LogChanges::Appended(std::mem::take(&mut self.pending_operations)))
+    }
+
+    /// The log changed if its length differs from what this process has read, or the bytes before that did.
+    async fn has_changes(&self) -> Result<bool, TaganrogError> {
+        if self.log_replaced || !self.pending_operations.is_empty() {
+            return Ok(true);
+        }
+        let log_len = match tokio::fs::metadata(&self.db_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(TaganrogError::DbIOError(e)),
+        };
+        Ok(log_len != self.log_offset || (self.log_offset > 0 && snapshot::log_anchor(&self.db_path, self.log_offset)? != self.log_anchor))
+    }
+
+    async fn save_snapshot(&mut self, snapshot: Snapshot) -> Result<(), TaganrogError> {
+        let _lock = self.lock(LockMode::Shared).await?;
+        let snapshot_file = SnapshotFile {
+            log_offset: self.log_offset,
+            log_lines: self.log_lines as u64,
+            log_anchor: snapshot::log_anchor(&self.db_path, self.log_offset)?,
+            operations: snapshot.operations as u64,
+            media: snapshot.media,
+        };
+        let bytes = self.seal(snapshot::encode(&snapshot_file)?);
+        let snapshot_path = snapshot::snapshot_path(&self.db_path, snapshot_file.log_offset);
+        let temp_path = append_extension(&snapshot_path, "tmp");
+        tokio::fs::write(&temp_path, bytes).await.map_err(TaganrogError::DbIOError)?;
+        tokio::fs::rename(&temp_path, &snapshot_path).await.map_err(TaganrogError::DbIOError)?;
+
+        for (_, old_snapshot_path) in snapshot::list_snapshots(&self.db_path).into_iter().skip(snapshot::KEPT_SNAPSHOTS) {
+            tokio::fs::remove_file(&old_snapshot_path).await.map_err(TaganrogError::DbIOError)?;
+        }
+        Ok(())
+    }
+
+    async fn is_locked(&mut self) -> Result<bool, TaganrogError> {
+        let _lock = self.lock(LockMode::Shared).await?;
+        let header = self.read_header().await?;
+        match self.update_cipher(header.encryption.as_ref()) {
+            Ok(()) => Ok(false),
+            Err(TaganrogError::DbLocked) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn unlock(&mut self, passphrase: &str) -> Result<(), TaganrogError> {
+        let _lock = self.lock(LockMode::Shared).await?;
+        let header = self.read_header().await?;
+        let previous_passphrase = self.passphrase.replace(passphrase.to_string());
+        self.cipher = None;
+        let result = self.update_cipher(header.encryption.as_ref());
+        if result.is_err() {
+            self.passphrase = previous_passphrase;
+        }
+        result
+    }
+
+    async fn set_encryption(&mut self, passphrase: Option<&str>, cipher: Option<Cipher>) -> Result<(), TaganrogError> {
+        // nothing may be appended between reading the log and rewriting it
+        let _lock = self.lock(LockMode::Exclusive).await?;
+        let history = self.read_whole_log().await?;
+        self.cipher = cipher;
+        self.passphrase = passphrase.map(|x| x.to_string());
+        self.rewrite_log(&history).await?;
+        // the backup still has the content the way it was stored before
+        let backup_path = self.backup_path();
+        if backup_path.exists() {
+            tokio::fs::remove_file(&backup_path).await.map_err(TaganrogError::DbIOError)?;
+        }
+        Ok(())
+    }
+
+    fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<(), TaganrogError> {
+    if let Some(parent) = path.parent() {
+        std::fs::File::open(parent).and_then(|dir| dir.sync_all())
+            .map_err(TaganrogError::DbIOError)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<(), TaganrogError> {
+    Ok(())
+}
+
+/// The storage selected by `AppConfig::storage_backend`.
+pub enum AppStorage {
+    File(FileStorage),
+    Sqlite(SqliteStorage),
+}
+
+impl AppStorage {
+    pub async fn open(config: &AppConfig) -> Result<Self, TaganrogError> {
+        let file_storage = FileStorage::new(config.db_filepath.clone())
+            .map_err(|e| TaganrogError::DbIOError(std::io::Error::other(e)))?
+            .with_fsync_policy(config.fsync_policy)
+            .with_replay_mode(config.replay_mode);
+        match config.storage_backend {
+            StorageBackend::File => Ok(AppStorage::File(file_storage)),
+            StorageBackend::Sqlite => {
+                let mut sqlite_storage = SqliteStorage::new(&config.sqlite_db_filepath)?;
+                if sqlite_storage.get_imported_from()?.is_none() {
+                    let mut file_storage = file_storage;
+                    let operations = file_storage.read_history().await?;
+                    info!("importing {} operations from {:?} into {:?}", operations.len(), config.db_filepath, config.sqlite_db_filepath);
+                    sqlite_storage.import(operations, &config.db_filepath.to_string_lossy())?;
+                }
+                Ok(AppStorage::Sqlite(sqlite_storage))
+            },
+        }
+    }
+}
+
+impl Storage for AppStorage {
+    async fn read_all(&mut self) -> Result<Vec<DbOperation>, TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.read_all().await,
+            AppStorage::Sqlite(storage) => storage.read_all().await,
+        }
+    }
+
+    async fn write(&mut self, operation: LoggedOperation) -> Result<(), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.write(operation).await,
+            AppStorage::Sqlite(storage) => storage.write(operation).await,
+        }
+    }
+
+    async fn rewrite(&mut self, operations: Vec<LoggedOperation>) -> Result<(), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.rewrite(operations).await,
+            AppStorage::Sqlite(storage) => storage.rewrite(operations).await,
+        }
+    }
+
+    fn take_replay_issues(&mut self) -> Vec<TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.take_replay_issues(),
+            AppStorage::Sqlite(storage) => storage.take_replay_issues(),
+        }
+    }
+
+    async fn read_latest(&mut self) -> Result<(Option<Snapshot>, Vec<DbOperation>), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.read_latest().await,
+            AppStorage::Sqlite(storage) => storage.read_latest().await,
+        }
+    }
+
+    async fn write_batch(&mut self, operations: Vec<DbOperation>, meta: OperationMeta) -> Result<(), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.write_batch(operations, meta).await,
+            AppStorage::Sqlite(storage) => storage.write_batch(operations, meta).await,
+        }
+    }
+
+    async fn read_history(&mut self) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.read_history().await,
+            AppStorage::Sqlite(storage) => storage.read_history().await,
+        }
+    }
+
+    async fn read_changes(&mut self) -> Result<LogChanges, TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.read_changes().await,
+            AppStorage::Sqlite(storage) => storage.read_changes().await,
+        }
+    }
+
+    async fn has_changes(&self) -> Result<bool, TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.has_changes().await,
+            AppStorage::Sqlite(storage) => storage.has_changes().await,
+        }
+    }
+
+    async fn save_snapshot(&mut self, snapshot: Snapshot) -> Result<(), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.save_snapshot(snapshot).await,
+            AppStorage::Sqlite(storage) => storage.save_snapshot(snapshot).await,
+        }
+    }
+
+    async fn is_locked(&mut self) -> Result<bool, TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.is_locked().await,
+            AppStorage::Sqlite(storage) => storage.is_locked().await,
+        }
+    }
+
+    async fn unlock(&mut self, passphrase: &str) -> Result<(), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.unlock(passphrase).await,
+            AppStorage::Sqlite(storage) => storage.unlock(passphrase).await,
+        }
+    }
+
+    async fn set_encryption(&mut self, passphrase: Option<&str>, cipher: Option<Cipher>) -> Result<(), TaganrogError> {
+        match self {
+            AppStorage::File(storage) => storage.set_encryption(passphrase, cipher).await,
+            AppStorage::Sqlite(storage) => storage.set_encryption(passphrase, cipher).await,
+        }
+    }
+
+    fn cipher(&self) -> Option<&Cipher> {
+        match self {
+            AppStorage::File(storage) => storage.cipher(),
+            AppStorage::Sqlite(storage) => storage.cipher(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryStorage {
+    operations: Vec<LoggedOperation>,
+}
+
+impl Storage for InMemoryStorage {
+    async fn read_all(&mut self) -> Result<Vec<DbOperation>, TaganrogError> {
+        Ok(self.operations.iter().map(|x| x.operation.clone()).collect())
+    }
+
+    async fn write(&mut self, operation: LoggedOperation) -> Result<(), TaganrogError> {
+        self.operations.push(operation);
+        Ok(())
+    }
+
+    async fn rewrite(&mut self, operations: Vec<LoggedOperation>) -> Result<(), TaganrogError> {
+        self.operations = operations;
+        Ok(())
+    }
+
+    async fn read_history(&mut self) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        Ok(self.operations.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn create_media(id: &str) -> Media {
+        Media { id: id.to_string(), filename: format!("{}.txt", id), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_write_and_read() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_write_batch() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write_batch(vec![
+            DbOperation::CreateMedia { media: create_media("m1") },
+            DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag1".to_string() },
+        ], OperationMeta::now(Origin::Cli)).await.unwrap();
+        let valid_len = std::fs::metadata(&db_path).unwrap().len();
+
+        // a batch torn by a crash is dropped as a whole
+        let torn_line = serialize_operation(&DbOperation::Batch { operations: vec![
+            DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag2".to_string() },
+            DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag3".to_string() },
+        ] }.into(), None).unwrap();
+        let mut content = std::fs::read(&db_path).unwrap();
+        content.extend_from_slice(&torn_line.as_bytes()[..torn_line.len() - 10]);
+        std::fs::write(&db_path, content).unwrap();
+
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(std::fs::metadata(&db_path).unwrap().len(), valid_len);
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].operations_count(), 2);
+        let history = storage.read_history().await.unwrap();
+        assert_eq!(history[0].meta.origin, Origin::Cli);
+        assert!(history[0].meta.timestamp.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_rewrite() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage.write(DbOperation::DeleteMedia { media_id: "m1".to_string(), previous: None }.into()).await.unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m2") }.into()).await.unwrap();
+        let old_content = std::fs::read_to_string(&db_path).unwrap();
+
+        storage.rewrite(vec![DbOperation::CreateMedia { media: create_media("m2") }.into()]).await.unwrap();
+
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 1);
+        assert!(matches!(&operations[0], DbOperation::CreateMedia { media } if media.id == "m2"));
+        assert_eq!(std::fs::read_to_string(storage.backup_path()).unwrap(), old_content);
+        assert!(!storage.temp_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_reads_legacy_lines() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        let legacy_line = serde_json::to_string(&DbOperation::CreateMedia { media: create_media("m1") }).unwrap();
+        std::fs::write(&db_path, format!("{}\n", legacy_line)).unwrap();
+        let mut storage = FileStorage::new(db_path).unwrap();
+        storage.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 2);
+        let history = storage.read_history().await.unwrap();
+        assert_eq!(history[0].meta, OperationMeta::default());
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_upgrades_legacy_db() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        let line = serialize_operation(&DbOperation::CreateMedia { media: create_media("m1") }.into(), None).unwrap();
+        std::fs::write(&db_path, &line).unwrap();
+
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 1);
+        let content = std::fs::read_to_string(&db_path).unwrap();
+        assert_eq!(content, format!("{}{}", DbHeader::default().serialize().unwrap(), line));
+        assert_eq!(std::fs::read_to_string(storage.backup_path()).unwrap(), line);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_rejects_newer_db() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        let header = DbHeader { version: migration::CURRENT_VERSION + 1, ..Default::default() };
+        std::fs::write(&db_path, header.serialize().unwrap()).unwrap();
+
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        let result = storage.read_latest().await;
+        assert!(matches!(result, Err(TaganrogError::DbVersionTooNew(_, migration::CURRENT_VERSION))));
+        let result = storage.read_all().await;
+        assert!(matches!(result, Err(TaganrogError::DbVersionTooNew(_, migration::CURRENT_VERSION))));
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_truncates_torn_tail() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        let valid_len = std::fs::metadata(&db_path).unwrap().len();
+        let torn_line = serialize_operation(&DbOperation::CreateMedia { media: create_media("m2") }.into(), None).unwrap();
+        let mut content = std::fs::read(&db_path).unwrap();
+        content.extend_from_slice(&torn_line.as_bytes()[..torn_line.len() / 2]);
+        std::fs::write(&db_path, content).unwrap();
+        assert!(storage.has_incomplete_last_line().unwrap());
+
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(std::fs::metadata(&db_path).unwrap().len(), valid_len);
+        assert!(!storage.has_incomplete_last_line().unwrap());
+        let issues = storage.take_replay_issues();
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(issues[0], TaganrogError::DbCorruptedLine(3, _)));
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_keeps_unterminated_last_line() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        let last_line = serialize_operation(&DbOperation::CreateMedia { media: create_media("m2") }.into(), None).unwrap();
+        let mut content = std::fs::read(&db_path).unwrap();
+        content.extend_from_slice(last_line.trim_end().as_bytes());
+        std::fs::write(&db_path, &content).unwrap();
+
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 2);
+        assert!(storage.take_replay_issues().is_empty());
+        assert_eq!(std::fs::metadata(&db_path).unwrap().len(), content.len() as u64 + 1);
+
+        // the next write starts on its own line
+        storage.write(DbOperation::CreateMedia { media: create_media("m3") }.into()).await.unwrap();
+        let mut storage = FileStorage::new(db_path).unwrap();
+        assert_eq!(storage.read_all().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_corrupted_line() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        let line1 = serialize_operation(&DbOperation::CreateMedia { media: create_media("m1") }.into(), None).unwrap();
+        let line2 = serialize_operation(&DbOperation::CreateMedia { media: create_media("m2") }.into(), None).unwrap().replace("m2", "m3");
+        let line3 = serialize_operation(&DbOperation::CreateMedia { media: create_media("m4") }.into(), None).unwrap();
+        std::fs::write(&db_path, format!("{}{}{}", line1, line2, line3)).unwrap();
+
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        let result = storage.read_all().await;
+        assert!(matches!(result, Err(TaganrogError::DbCorruptedLine(2, _))));
+
+        let mut storage = FileStorage::new(db_path).unwrap().with_replay_mode(ReplayMode::Lenient);
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 2);
+        let issues = storage.take_replay_issues();
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(issues[0], TaganrogError::DbCorruptedLine(2, _)));
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m2") }.into()).await.unwrap();
+        storage.save_snapshot(Snapshot { operations: 2, media: vec![create_media("m1"), create_media("m2")] }).await.unwrap();
+        storage.write(DbOperation::DeleteMedia { media_id: "m1".to_string(), previous: None }.into()).await.unwrap();
+
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        let (maybe_snapshot, operations) = storage.read_latest().await.unwrap();
+        let snapshot = maybe_snapshot.unwrap();
+        assert_eq!(snapshot.operations, 2);
+        assert_eq!(snapshot.media.len(), 2);
+        assert_eq!(operations.len(), 1);
+        assert!(matches!(&operations[0], DbOperation::DeleteMedia { media_id, .. } if media_id == "m1"));
+
+        // a rewritten log invalidates the snapshots
+        storage.rewrite(vec![DbOperation::CreateMedia { media: create_media("m2") }.into()]).await.unwrap();
+        assert!(snapshot::list_snapshots(&db_path).is_empty());
+        let (maybe_snapshot, operations) = storage.read_latest().await.unwrap();
+        assert!(maybe_snapshot.is_none());
+        assert_eq!(operations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_ignores_stale_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage.save_snapshot(Snapshot { operations: 1, media: vec![create_media("m1")] }).await.unwrap();
+
+        // the log is replaced behind our back with one of the same length
+        let other_line = serialize_operation(&DbOperation::CreateMedia { media: create_media("m9") }.into(), None).unwrap();
+        std::fs::write(&db_path, other_line).unwrap();
+
+        let mut storage = FileStorage::new(db_path).unwrap();
+        let (maybe_snapshot, operations) = storage.read_latest().await.unwrap();
+        assert!(maybe_snapshot.is_none());
+        assert!(matches!(&operations[0], DbOperation::CreateMedia { media } if media.id == "m9"));
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_encryption() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage.set_encryption(Some("secret"), Some(Cipher::create("secret").unwrap())).await.unwrap();
+        storage.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "client-name".to_string() }.into()).await.unwrap();
+        storage.save_snapshot(Snapshot { operations: 2, media: vec![create_media("m1")] }).await.unwrap();
+        let content = std::fs::read_to_string(&db_path).unwrap();
+        assert!(!content.contains("m1.txt") && !content.contains("client-name"));
+        assert!(!storage.backup_path().exists());
+
+        let mut storage = FileStorage::new(db_path.clone()).unwrap();
+        assert!(storage.is_locked().await.unwrap());
+        assert!(matches!(storage.read_all().await, Err(TaganrogError::DbLocked)));
+        assert!(matches!(storage.unlock("wrong").await, Err(TaganrogError::DbWrongPassphrase)));
+        storage.unlock("secret").await.unwrap();
+        assert!(!storage.is_locked().await.unwrap());
+        let (maybe_snapshot, operations) = storage.read_latest().await.unwrap();
+        assert_eq!(maybe_snapshot.unwrap().media.len(), 1);
+        assert!(operations.is_empty());
+        assert_eq!(storage.read_history().await.unwrap().len(), 2);
+
+        storage.set_encryption(None, None).await.unwrap();
+        assert!(std::fs::read_to_string(&db_path).unwrap().contains("client-name"));
+        let mut storage = FileStorage::new(db_path).unwrap();
+        assert!(!storage.is_locked().await.unwrap());
+        assert_eq!(storage.read_all().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_reads_changes_of_other_processes() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.json");
+        std::fs::write(&db_path, "").unwrap();
+        let mut storage1 = FileStorage::new(db_path.clone()).unwrap();
+        let mut storage2 = FileStorage::new(db_path.clone()).unwrap();
+        storage1.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage2.read_all().await.unwrap();
+        assert!(matches!(storage2.read_changes().await.unwrap(), LogChanges::Appended(x) if x.is_empty()));
+
+        // operations appended by the other process show up, including the ones written before our own write
+        storage1.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        storage2.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag2".to_string() }.into()).await.unwrap();
+        storage1.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag3".to_string() }.into()).await.unwrap();
+        let LogChanges::Appended(operations) = storage2.read_changes().await.unwrap() else { panic!("log was not replaced") };
+        assert_eq!(operations.len(), 2);
+        assert!(matches!(&operations[0].operation, DbOperation::AddTag { tag, .. } if tag == "tag1"));
+        assert!(matches!(&operations[1].operation, DbOperation::AddTag { tag, .. } if tag == "tag3"));
+        let LogChanges::Appended(operations) = storage1.read_changes().await.unwrap() else { panic!("log was not replaced") };
+        assert_eq!(operations.len(), 1);
+        assert_eq!(storage1.read_all().await.unwrap().len(), 4);
+
+        // a rewrite by the other process has to be read from scratch
+        storage1.rewrite(vec![DbOperation::CreateMedia { media: create_media("m2") }.into()]).await.unwrap();
+        assert!(matches!(storage2.read_changes().await.unwrap(), LogChanges::Replaced));
+        assert_eq!(storage2.read_all().await.unwrap().len(), 1);
+        assert!(matches!(storage2.read_changes().await.unwrap(), LogChanges::Appended(x) if x.is_empty()));
+    }
+}
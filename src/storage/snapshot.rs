@@ -0,0 +1,101 @@
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::entities::Media;
+use crate::error::TaganrogError;
+
+/// Snapshot file layout: `MAGIC`, bincode-encoded `SnapshotFile`, xxhash64 of the encoded part (LE).
+const MAGIC: &[u8; 8] = b"TGSNAP04";
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+/// How many bytes of the log right before the snapshot offset are hashed into the anchor.
+const ANCHOR_LEN: u64 = 4096;
+/// How many snapshot files are kept around.
+pub const KEPT_SNAPSHOTS: usize = 2;
+
+/// The state of the DB after the first `operations` operations of the log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub operations: usize,
+    pub media: Vec<Media>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SnapshotFile {
+    /// byte offset in the log this snapshot covers
+    pub log_offset: u64,
+    /// number of log lines before `log_offset`
+    pub log_lines: u64,
+    /// hash of the log bytes right before `log_offset`, detects a replaced log
+    pub log_anchor: u64,
+    pub operations: u64,
+    pub media: Vec<Media>,
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+pub(crate) fn encode(snapshot_file: &SnapshotFile) -> Result<Vec<u8>, TaganrogError> {
+    let payload = bincode::serialize(snapshot_file)
+        .map_err(|e| TaganrogError::DbSnapshotError(e.to_string()))?;
+    let mut bytes = Vec::with_capacity(MAGIC.len() + payload.len() + 8);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&payload);
+    bytes.extend_from_slice(&hash_bytes(&payload).to_le_bytes());
+    Ok(bytes)
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<SnapshotFile, TaganrogError> {
+    if bytes.len() < MAGIC.len() + 8 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(TaganrogError::DbSnapshotError("unknown snapshot format".to_string()));
+    }
+    let (payload, checksum) = bytes[MAGIC.len()..].split_at(bytes.len() - MAGIC.len() - 8);
+    if hash_bytes(payload).to_le_bytes() != checksum {
+        return Err(TaganrogError::DbSnapshotError("checksum mismatch".to_string()));
+    }
+    bincode::deserialize(payload).map_err(|e| TaganrogError::DbSnapshotError(e.to_string()))
+}
+
+/// Hashes the last `ANCHOR_LEN` bytes of the log before `offset`.
+pub(crate) fn log_anchor(db_path: &Path, offset: u64) -> Result<u64, TaganrogError> {
+    let mut file = std::fs::File::open(db_path).map_err(TaganrogError::DbIOError)?;
+    let start = offset.saturating_sub(ANCHOR_LEN);
+    file.seek(SeekFrom::Start(start)).map_err(TaganrogError::DbIOError)?;
+    let mut bytes = Vec::with_capacity((offset - start) as usize);
+    file.take(offset - start).read_to_end(&mut bytes).map_err(TaganrogError::DbIOError)?;
+    if (bytes.len() as u64) < offset - start {
+        return Err(TaganrogError::DbSnapshotError("log is shorter than the snapshot".to_string()));
+    }
+    Ok(hash_bytes(&bytes))
+}
+
+pub(crate) fn snapshot_path(db_path: &Path, log_offset: u64) -> PathBuf {
+    let mut path_str = db_path.as_os_str().to_owned();
+    path_str.push(format!(".{}.{}", log_offset, SNAPSHOT_EXTENSION));
+    PathBuf::from(path_str)
+}
+
+/// Lists the snapshots of the given log, the newest (biggest offset) first.
+pub(crate) fn list_snapshots(db_path: &Path) -> Vec<(u64, PathBuf)> {
+    let (Some(dir), Some(db_filename)) = (db_path.parent(), db_path.file_name()) else {
+        return vec![];
+    };
+    let prefix = format!("{}.", db_filename.to_string_lossy());
+    let suffix = format!(".{}", SNAPSHOT_EXTENSION);
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut snapshots = entries
+        .filter_map(|x| x.ok())
+        .filter_map(|x| {
+            let filename = x.file_name().to_string_lossy().to_string();
+            let log_offset = filename.strip_prefix(&prefix)?.strip_suffix(&suffix)?.parse::<u64>().ok()?;
+            Some((log_offset, x.path()))
+        })
+        .collect::<Vec<(u64, PathBuf)>>();
+    snapshots.sort_by_key(|x| std::cmp::Reverse(x.0));
+    snapshots
+}
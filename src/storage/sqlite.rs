@@ -0,0 +1,373 @@
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use crate::entities::{filename_of, Media, MediaId, Tag};
+use crate::error::TaganrogError;
+use crate::storage::{media_to_operations, DbOperation, LogChanges, LoggedOperation, Origin, Storage};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS media (
+        id TEXT PRIMARY KEY NOT NULL,
+        filename TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        location TEXT NOT NULL,
+        perceptual_hash INTEGER,
+        modified_at TEXT,
+        content_hash TEXT,
+        verified_at TEXT
+    );
+    CREATE TABLE IF NOT EXISTS media_tags (
+        media_id TEXT NOT NULL REFERENCES media(id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (media_id, tag)
+    );
+    CREATE INDEX IF NOT EXISTS media_tags_tag_idx ON media_tags(tag);
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        operation TEXT NOT NULL
+    );
+";
+
+/// Schema upgrades, `SCHEMA_MIGRATIONS[i]` brings `user_version` from `i + 1` to `i + 2`.
+/// Version 0 is a fresh file, `SCHEMA` creates the latest tables directly.
+const SCHEMA_MIGRATIONS: &[&str] = &[
+    // 2: the operations that led to the current state, as json `LoggedOperation`s
+    "CREATE TABLE history (id INTEGER PRIMARY KEY AUTOINCREMENT, operation TEXT NOT NULL);",
+    // 3: dHash of images, stored as the i64 with the same bits
+    "ALTER TABLE media ADD COLUMN perceptual_hash INTEGER;",
+    // 4: modification time (rfc3339) and BLAKE3 of the file
+    "ALTER TABLE media ADD COLUMN modified_at TEXT; ALTER TABLE media ADD COLUMN content_hash TEXT;",
+    // 5: when the file was last verified against content_hash (rfc3339)
+    "ALTER TABLE media ADD COLUMN verified_at TEXT;",
+];
+const SCHEMA_VERSION: u32 = SCHEMA_MIGRATIONS.len() as u32 + 1;
+
+const IMPORTED_FROM_KEY: &str = "imported_from";
+
+/// Keeps the current state (not the history) in normalized tables,
+/// so opening the DB costs as much as the library is big, not as long as it was edited.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    // changes whenever another connection commits, see `read_changes`
+    data_version: i64,
+}
+
+impl SqliteStorage {
+    pub fn new(db_path: &Path) -> Result<Self, TaganrogError> {
+        let mut conn = Connection::open(db_path).map_err(TaganrogError::DbSqliteError)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")
+            .map_err(TaganrogError::DbSqliteError)?;
+        Self::migrate(&mut conn)?;
+        let data_version = Self::read_data_version(&conn)?;
+        Ok(Self { conn: Mutex::new(conn), data_version })
+    }
+
+    fn read_data_version(conn: &Connection) -> Result<i64, TaganrogError> {
+        conn.query_row("PRAGMA data_version", [], |row| row.get(0))
+            .map_err(TaganrogError::DbSqliteError)
+    }
+
+    fn migrate(conn: &mut Connection) -> Result<(), TaganrogError> {
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(TaganrogError::DbSqliteError)?;
+        if version > SCHEMA_VERSION {
+            return Err(TaganrogError::DbVersionTooNew(version, SCHEMA_VERSION));
+        }
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        let tx = conn.transaction().map_err(TaganrogError::DbSqliteError)?;
+        if version == 0 {
+            tx.execute_batch(SCHEMA).map_err(TaganrogError::DbSqliteError)?;
+        } else {
+            for migration in SCHEMA_MIGRATIONS.iter().skip(version as usize - 1) {
+                tx.execute_batch(migration).map_err(TaganrogError::DbSqliteError)?;
+            }
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION).map_err(TaganrogError::DbSqliteError)?;
+        tx.commit().map_err(TaganrogError::DbSqliteError)?;
+        Ok(())
+    }
+
+    /// Returns where the data was imported from, if an import has already happened.
+    pub fn get_imported_from(&self) -> Result<Option<String>, TaganrogError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT value FROM meta WHERE key = ?1", params![IMPORTED_FROM_KEY], |row| row.get(0))
+            .optional()
+            .map_err(TaganrogError::DbSqliteError)
+    }
+
+    /// Replaces the content of the DB with the given operations (e.g. read from a `taganrog.db.json`)
+    /// and remembers where they came from, all in one transaction.
+    /// Operations keep their meta, those without a recorded origin get `Origin::Import`.
+    pub fn import(&mut self, operations: Vec<LoggedOperation>, source: &str) -> Result<(), TaganrogError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(TaganrogError::DbSqliteError)?;
+        clear(&tx)?;
+        for mut operation in operations {
+            if operation.meta.origin == Origin::Unknown {
+                operation.meta.origin = Origin::Import;
+            }
+            apply_operation(&tx, &operation.operation)?;
+            append_history(&tx, &operation)?;
+        }
+        tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)", params![IMPORTED_FROM_KEY, source])
+            .map_err(TaganrogError::DbSqliteError)?;
+        tx.commit().map_err(TaganrogError::DbSqliteError)?;
+        Ok(())
+    }
+
+    fn read_media(conn: &Connection) -> Result<Vec<Media>, TaganrogError> {
+        let mut media_stmt = conn.prepare("SELECT id, filename, content_type, created_at, size, location, perceptual_hash, modified_at, content_hash, verified_at FROM media ORDER BY created_at, id")
+            .map_err(TaganrogError::DbSqliteError)?;
+        let mut media_vec = media_stmt.query_map([], |row| {
+            let parse_date = |index: usize, date: String| DateTime::parse_from_rfc3339(&date)
+                .map(|x| x.with_timezone(&Utc))
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)));
+            let created_at = parse_date(3, row.get(3)?)?;
+            let modified_at = row.get::<_, Option<String>>(7)?.map(|x| parse_date(7, x)).transpose()?;
+            let verified_at = row.get::<_, Option<String>>(9)?.map(|x| parse_date(9, x)).transpose()?;
+            Ok(Media {
+                id: row.get(0)?,
+                filename: row.get(1)?,
+                content_type: row.get(2)?,
+                created_at,
+                size: row.get(4)?,
+                location: row.get(5)?,
+                tags: vec![],
+                perceptual_hash: row.get::<_, Option<i64>>(6)?.map(|x| x as u64),
+                modified_at,
+                content_hash: row.get(8)?,
+                verified_at,
+            })
+        }).and_then(|rows| rows.collect::<Result<Vec<Media>, rusqlite::Error>>())
+            .map_err(TaganrogError::DbSqliteError)?;
+
+        let mut tags_stmt = conn.prepare("SELECT media_id, tag FROM media_tags ORDER BY media_id, position")
+            .map_err(TaganrogError::DbSqliteError)?;
+        let tags = tags_stmt.query_map([], |row| Ok((row.get::<_, MediaId>(0)?, row.get::<_, Tag>(1)?)))
+            .and_then(|rows| rows.collect::<Result<Vec<(MediaId, Tag)>, rusqlite::Error>>())
+            .map_err(TaganrogError::DbSqliteError)?;
+        let mut tags_by_media = std::collections::HashMap::<MediaId, Vec<Tag>>::new();
+        for (media_id, tag) in tags {
+            tags_by_media.entry(media_id).or_default().push(tag);
+        }
+        for media in media_vec.iter_mut() {
+            media.tags = tags_by_media.remove(&media.id).unwrap_or_default();
+        }
+        Ok(media_vec)
+    }
+}
+
+fn clear(tx: &Transaction) -> Result<(), TaganrogError> {
+    tx.execute_batch("DELETE FROM media_tags; DELETE FROM media; DELETE FROM history;")
+        .map_err(TaganrogError::DbSqliteError)
+}
+
+fn append_history(tx: &Transaction, operation: &LoggedOperation) -> Result<(), TaganrogError> {
+    let operation = serde_json::to_string(operation).map_err(TaganrogError::DbSerializationError)?;
+    tx.execute("INSERT INTO history (operation) VALUES (?1)", params![operation])
+        .map_err(TaganrogError::DbSqliteError)?;
+    Ok(())
+}
+
+fn apply_operation(tx: &Transaction, operation: &DbOperation) -> Result<(), TaganrogError> {
+    match operation {
+        DbOperation::CreateMedia { media } => {
+            tx.execute(
+                "INSERT OR IGNORE INTO media (id, filename, content_type, created_at, size, location, perceptual_hash, modified_at, content_hash, verified_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![media.id, media.filename, media.content_type, media.created_at.to_rfc3339(), media.size, media.location, media.perceptual_hash.map(|x| x as i64),
+                    media.modified_at.map(|x| x.to_rfc3339()), media.content_hash, media.verified_at.map(|x| x.to_rfc3339())],
+            ).map_err(TaganrogError::DbSqliteError)?;
+            for tag in media.tags.iter() {
+                add_tag(tx, &media.id, tag)?;
+            }
+        },
+        DbOperation::DeleteMedia { media_id, .. } => {
+            tx.execute("DELETE FROM media WHERE id = ?1", params![media_id])
+                .map_err(TaganrogError::DbSqliteError)?;
+        },
+        DbOperation::AddTag { media_id, tag } => add_tag(tx, media_id, tag)?,
+        DbOperation::RemoveTag { media_id, tag } => {
+            tx.execute("DELETE FROM media_tags WHERE media_id = ?1 AND tag = ?2", params![media_id, tag])
+                .map_err(TaganrogError::DbSqliteError)?;
+        },
+        DbOperation::UpdateLocation { media_id, location, .. } => {
+            tx.execute("UPDATE media SET location = ?2, filename = ?3 WHERE id = ?1", params![media_id, location, filename_of(location)])
+                .map_err(TaganrogError::DbSqliteError)?;
+        },
+        DbOperation::UpdatePerceptualHash { media_id, perceptual_hash, .. } => {
+            tx.execute("UPDATE media SET perceptual_hash = ?2 WHERE id = ?1", params![media_id, perceptual_hash.map(|x| x as i64)])
+                .map_err(TaganrogError::DbSqliteError)?;
+        },
+        DbOperation::UpdateFileInfo { media_id, file_info, .. } => {
+            tx.execute(
+                "UPDATE media SET size = ?2, modified_at = ?3, content_hash = ?4 WHERE id = ?1",
+                params![media_id, file_info.size, file_info.modified_at.map(|x| x.to_rfc3339()), file_info.content_hash],
+            ).map_err(TaganrogError::DbSqliteError)?;
+        },
+        DbOperation::UpdateVerifiedAt { media_id, verified_at, .. } => {
+            tx.execute("UPDATE media SET verified_at = ?2 WHERE id = ?1", params![media_id, verified_at.map(|x| x.to_rfc3339())])
+                .map_err(TaganrogError::DbSqliteError)?;
+        },
+        DbOperation::Batch { operations } => {
+            for operation in operations.iter() {
+                apply_operation(tx, operation)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn add_tag(tx: &Transaction, media_id: &MediaId, tag: &Tag) -> Result<(), TaganrogError> {
+    tx.execute(
+        "INSERT OR IGNORE INTO media_tags (media_id, tag, position)
+         SELECT ?1, ?2, (SELECT COALESCE(MAX(position), -1) + 1 FROM media_tags WHERE media_id = ?1)
+         WHERE EXISTS (SELECT 1 FROM media WHERE id = ?1)",
+        params![media_id, tag],
+    ).map_err(TaganrogError::DbSqliteError)?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    /// Returns the minimal list of operations that recreates the current state.
+    async fn read_all(&mut self) -> Result<Vec<DbOperation>, TaganrogError> {
+        let conn = self.conn.lock().unwrap();
+        let media_vec = Self::read_media(&conn)?;
+        Ok(media_to_operations(media_vec))
+    }
+
+    async fn write(&mut self, operation: LoggedOperation) -> Result<(), TaganrogError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(TaganrogError::DbSqliteError)?;
+        apply_operation(&tx, &operation.operation)?;
+        append_history(&tx, &operation)?;
+        tx.commit().map_err(TaganrogError::DbSqliteError)?;
+        Ok(())
+    }
+
+    async fn rewrite(&mut self, operations: Vec<LoggedOperation>) -> Result<(), TaganrogError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(TaganrogError::DbSqliteError)?;
+        clear(&tx)?;
+        for operation in operations.iter() {
+            apply_operation(&tx, &operation.operation)?;
+            append_history(&tx, operation)?;
+        }
+        tx.commit().map_err(TaganrogError::DbSqliteError)?;
+        Ok(())
+    }
+
+    async fn read_history(&mut self) -> Result<Vec<LoggedOperation>, TaganrogError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT operation FROM history ORDER BY id")
+            .map_err(TaganrogError::DbSqliteError)?;
+        let operations = stmt.query_map([], |row| row.get::<_, String>(0))
+            .and_then(|rows| rows.collect::<Result<Vec<String>, rusqlite::Error>>())
+            .map_err(TaganrogError::DbSqliteError)?;
+        operations.iter()
+            .map(|x| serde_json::from_str(x).map_err(TaganrogError::DbSerializationError))
+            .collect()
+    }
+
+    /// SQLite does its own locking, so other processes can only be detected, not merged:
+    /// any commit of theirs means the state has to be read again.
+    async fn read_changes(&mut self) -> Result<LogChanges, TaganrogError> {
+        let data_version = Self::read_data_version(&self.conn.lock().unwrap())?;
+        if data_version == self.data_version {
+            return Ok(LogChanges::Appended(vec![]));
+        }
+        self.data_version = data_version;
+        Ok(LogChanges::Replaced)
+    }
+
+    async fn has_changes(&self) -> Result<bool, TaganrogError> {
+        Ok(Self::read_data_version(&self.conn.lock().unwrap())? != self.data_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn create_media(id: &str) -> Media {
+        Media { id: id.to_string(), filename: format!("{}.txt", id), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_write_and_read() {
+        let temp_dir = tempdir().unwrap();
+        let mut storage = SqliteStorage::new(&temp_dir.path().join("taganrog.db.sqlite")).unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        storage.write(DbOperation::CreateMedia { media: create_media("m2") }.into()).await.unwrap();
+        storage.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag2".to_string() }.into()).await.unwrap();
+        storage.write(DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        storage.write(DbOperation::AddTag { media_id: "m2".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        storage.write(DbOperation::AddTag { media_id: "unknown".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        storage.write(DbOperation::RemoveTag { media_id: "m2".to_string(), tag: "tag1".to_string() }.into()).await.unwrap();
+        drop(storage);
+
+        let mut storage = SqliteStorage::new(&temp_dir.path().join("taganrog.db.sqlite")).unwrap();
+        let media_vec = SqliteStorage::read_media(&storage.conn.lock().unwrap()).unwrap();
+        assert_eq!(media_vec.len(), 2);
+        assert_eq!(media_vec.iter().find(|x| x.id == "m1").unwrap().tags, vec!["tag2".to_string(), "tag1".to_string()]);
+        assert!(media_vec.iter().find(|x| x.id == "m2").unwrap().tags.is_empty());
+
+        storage.write(DbOperation::DeleteMedia { media_id: "m1".to_string(), previous: None }.into()).await.unwrap();
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_storage_rejects_newer_schema() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.sqlite");
+        drop(SqliteStorage::new(&db_path).unwrap());
+        let conn = Connection::open(&db_path).unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        drop(conn);
+        let result = SqliteStorage::new(&db_path);
+        assert!(matches!(result, Err(TaganrogError::DbVersionTooNew(_, SCHEMA_VERSION))));
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_detects_other_connections() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("taganrog.db.sqlite");
+        let mut storage1 = SqliteStorage::new(&db_path).unwrap();
+        let mut storage2 = SqliteStorage::new(&db_path).unwrap();
+        storage1.write(DbOperation::CreateMedia { media: create_media("m1") }.into()).await.unwrap();
+        assert!(matches!(storage1.read_changes().await.unwrap(), LogChanges::Appended(x) if x.is_empty()));
+        assert!(matches!(storage2.read_changes().await.unwrap(), LogChanges::Replaced));
+        assert!(matches!(storage2.read_changes().await.unwrap(), LogChanges::Appended(x) if x.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_import() {
+        let temp_dir = tempdir().unwrap();
+        let mut storage = SqliteStorage::new(&temp_dir.path().join("taganrog.db.sqlite")).unwrap();
+        assert_eq!(storage.get_imported_from().unwrap(), None);
+        let operations = vec![
+            DbOperation::CreateMedia { media: create_media("m1") },
+            DbOperation::AddTag { media_id: "m1".to_string(), tag: "tag1".to_string() },
+            DbOperation::CreateMedia { media: create_media("m2") },
+            DbOperation::DeleteMedia { media_id: "m2".to_string(), previous: None },
+        ];
+        storage.import(operations.into_iter().map(LoggedOperation::from).collect(), "taganrog.db.json").unwrap();
+        assert_eq!(storage.get_imported_from().unwrap(), Some("taganrog.db.json".to_string()));
+        let operations = storage.read_all().await.unwrap();
+        assert_eq!(operations.len(), 2);
+        let history = storage.read_history().await.unwrap();
+        assert_eq!(history.len(), 4);
+        assert!(history.iter().all(|x| x.meta.origin == Origin::Import));
+    }
+}
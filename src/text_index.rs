@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU32, Ordering};
+use dashmap::DashMap;
+use crate::entities::MediaId;
+
+/// Trigram index over texts of the media (their locations), to find the media whose text contains a term,
+/// maybe with typos, without checking every one of them. Texts are indexed lowercase.
+/// Media ids are interned as numbers, so that the postings don't hold a copy of every id per trigram.
+#[derive(Debug, Default)]
+pub struct TrigramIndex {
+    postings: DashMap<String, HashSet<u32>>,
+    numbers: DashMap<MediaId, u32>,
+    media_ids: DashMap<u32, MediaId>,
+    next_number: AtomicU32,
+}
+
+impl TrigramIndex {
+    pub fn insert(&self, media_id: &MediaId, text: &str) {
+        let number = *self.numbers.entry(media_id.clone()).or_insert_with(|| {
+            let number = self.next_number.fetch_add(1, Ordering::Relaxed);
+            self.media_ids.insert(number, media_id.clone());
+            number
+        });
+        for trigram in trigrams(text) {
+            self.postings.entry(trigram).or_default().insert(number);
+        }
+    }
+
+    /// `text` is the one the media was inserted with.
+    pub fn remove(&self, media_id: &MediaId, text: &str) {
+        let Some((_, number)) = self.numbers.remove(media_id) else {
+            return;
+        };
+        self.media_ids.remove(&number);
+        for trigram in trigrams(text) {
+            if let Some(mut numbers) = self.postings.get_mut(&trigram) {
+                numbers.remove(&number);
+            }
+            self.postings.remove_if(&trigram, |_, x| x.is_empty());
+        }
+    }
+
+    pub fn clear(&self) {
+        self.postings.clear();
+        self.numbers.clear();
+        self.media_ids.clear();
+    }
+
+    /// The media whose text might contain the term with at most `max_typos` edits, they still have to be checked.
+    /// `None` if the term is too short for the index to rule any media out.
+    pub fn candidates(&self, term: &str, max_typos: usize) -> Option<HashSet<MediaId>> {
+        let term_trigrams = trigrams(term);
+        // an edit changes at most 3 trigrams of the term
+        let min_shared_trigrams = term_trigrams.len().saturating_sub(3 * max_typos);
+        if min_shared_trigrams == 0 {
+            return None;
+        }
+        let mut shared_trigrams = HashMap::<u32, usize>::new();
+        for trigram in term_trigrams {
+            let Some(numbers) = self.postings.get(&trigram) else {
+                continue;
+            };
+            for number in numbers.iter() {
+                *shared_trigrams.entry(*number).or_default() += 1;
+            }
+        }
+        let candidates = shared_trigrams.into_iter()
+            .filter(|(_, x)| *x >= min_shared_trigrams)
+            .filter_map(|(number, _)| self.media_ids.get(&number).map(|x| x.value().clone()))
+            .collect();
+        Some(candidates)
+    }
+}
+
+fn trigrams(text: &str) -> HashSet<String> {
+    let chars = text.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect::<Vec<char>>();
+    chars.windows(3).map(|x| x.iter().collect()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trigram_index() {
+        let index = TrigramIndex::default();
+        let (media1, media2) = ("media1".to_string(), "media2".to_string());
+        index.insert(&media1, "/docs/2024_Invoice.pdf");
+        index.insert(&media2, "/photos/beach.png");
+        assert_eq!(index.candidates("invoice", 0), Some(HashSet::from([media1.clone()])));
+        assert_eq!(index.candidates("invoce", 1), Some(HashSet::from([media1.clone()])));
+        assert_eq!(index.candidates("pdf", 1), None);
+        assert_eq!(index.candidates("photos/beach", 1), Some(HashSet::from([media2.clone()])));
+        assert_eq!(index.candidates("receipts", 1), Some(HashSet::new()));
+
+        index.remove(&media1, "/docs/2024_Invoice.pdf");
+        assert_eq!(index.candidates("invoice", 0), Some(HashSet::new()));
+        let number2 = *index.numbers.get(&media2).unwrap();
+        assert!(index.postings.iter().all(|x| x.value().contains(&number2)));
+        assert_eq!((index.numbers.len(), index.media_ids.len()), (1, 1));
+
+        index.insert(&media1, "/docs/2025_Invoice.pdf");
+        assert_eq!(index.candidates("2025_invoice", 0), Some(HashSet::from([media1.clone()])));
+    }
+}
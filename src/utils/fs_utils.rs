@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+use log::warn;
+use crate::config::LibraryRoots;
+
+/// Resolves symlinks, `.` and `..` in the path of a file or directory, so that every path to it gives the same location.
+/// A missing file (e.g. a deleted one) gets the resolved path of its directory.
+/// Files under a library root get the root as configured, even if the root is a symlink, so that their location
+/// can be stored relative to it. A symlink out of a root resolves to its target like any other, so that its file
+/// has a single location.
+pub fn canonicalize_file(path: &Path, library_roots: &LibraryRoots) -> std::io::Result<PathBuf> {
+    let canonical_path = canonicalize_existing(path)?;
+    let rerooted_path = library_roots.iter()
+        .filter_map(|(_, root)| relative_to_root(&canonical_path, root).map(|x| (root, x)))
+        .min_by_key(|(_, relative_path)| relative_path.components().count())
+        .map(|(root, relative_path)| root.join(relative_path));
+    Ok(rerooted_path.unwrap_or(canonical_path))
+}
+
+fn relative_to_root(canonical_path: &Path, root: &Path) -> Option<PathBuf> {
+    let canonical_root = dunce::canonicalize(root).ok()?;
+    canonical_path.strip_prefix(canonical_root).ok().map(Path::to_path_buf)
+}
+
+/// `dunce` gives `C:\...` instead of `\\?\C:\...` on Windows.
+fn canonicalize_existing(path: &Path) -> std::io::Result<PathBuf> {
+    match dunce::canonicalize(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let abs_path = std::path::absolute(path)?;
+            let (Some(dir), Some(filename)) = (abs_path.parent(), abs_path.file_name()) else {
+                return Err(e);
+            };
+            Ok(dunce::canonicalize(dir)?.join(filename))
+        },
+        result => result,
+    }
+}
+
+/// Lists the files in the directories and all their subdirectories. Symlinked directories are not followed.
+/// Entries that can't be read (e.g. without permission) are skipped with a warning.
+pub fn walk_files(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending_dirs = dirs.to_vec();
+    while let Some(dir) = pending_dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("skipping directory {:?}: {}", dir, e);
+                continue;
+            },
+        };
+        for entry in entries {
+            let (entry, file_type) = match entry.and_then(|x| x.file_type().map(|file_type| (x, file_type))) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("skipping an entry of {:?}: {}", dir, e);
+                    continue;
+                },
+            };
+            if file_type.is_dir() {
+                pending_dirs.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sub_dir = temp_dir.path().join("sub");
+        std::fs::create_dir(&sub_dir).unwrap();
+        std::fs::write(temp_dir.path().join("a.txt"), b"a").unwrap();
+        std::fs::write(sub_dir.join("b.txt"), b"b").unwrap();
+        let files = walk_files(&[temp_dir.path().to_path_buf()]);
+        assert_eq!(files, vec![temp_dir.path().join("a.txt"), sub_dir.join("b.txt")]);
+    }
+
+    #[test]
+    fn test_canonicalize_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = std::fs::canonicalize(temp_dir.path()).unwrap();
+        let sub_dir = dir.join("sub");
+        std::fs::create_dir(&sub_dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"a").unwrap();
+        let no_roots = LibraryRoots::default();
+        assert_eq!(canonicalize_file(&sub_dir.join("..").join("a.txt"), &no_roots).unwrap(), dir.join("a.txt"));
+        assert_eq!(canonicalize_file(&sub_dir.join("..").join("deleted.txt"), &no_roots).unwrap(), dir.join("deleted.txt"));
+        assert!(canonicalize_file(&dir.join("unknown").join("a.txt"), &no_roots).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_canonicalize_file_under_library_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = std::fs::canonicalize(temp_dir.path()).unwrap();
+        let drive_dir = dir.join("drive");
+        let outside_dir = dir.join("outside");
+        std::fs::create_dir(&drive_dir).unwrap();
+        std::fs::create_dir(&outside_dir).unwrap();
+        std::fs::write(drive_dir.join("a.txt"), b"a").unwrap();
+        std::fs::write(outside_dir.join("b.txt"), b"b").unwrap();
+        let root_dir = dir.join("mnt");
+        std::os::unix::fs::symlink(&drive_dir, &root_dir).unwrap();
+        std::os::unix::fs::symlink(outside_dir.join("b.txt"), drive_dir.join("b.txt")).unwrap();
+        let library_roots = LibraryRoots::new([("drive".to_string(), root_dir.clone())].into());
+
+        // the root is a symlink
+        assert_eq!(canonicalize_file(&root_dir.join(".").join("a.txt"), &library_roots).unwrap(), root_dir.join("a.txt"));
+        assert_eq!(canonicalize_file(&drive_dir.join("a.txt"), &library_roots).unwrap(), root_dir.join("a.txt"));
+        // the file links out of the root, it's the same file as its target
+        assert_eq!(canonicalize_file(&root_dir.join("b.txt"), &library_roots).unwrap(), outside_dir.join("b.txt"));
+        assert_eq!(canonicalize_file(&outside_dir.join("b.txt"), &library_roots).unwrap(), outside_dir.join("b.txt"));
+        assert_eq!(canonicalize_file(&root_dir.join("deleted.txt"), &library_roots).unwrap(), root_dir.join("deleted.txt"));
+    }
+}
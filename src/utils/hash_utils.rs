@@ -1,5 +1,12 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use image::ImageReader;
+use image::imageops::FilterType;
 use uuid::Uuid;
 
+/// Bytes read from each of the start, middle and end of a file by `ContentHasher::sampled_hash`.
+const SAMPLE_LEN: u64 = 64 * 1024;
+
 pub struct MurMurHasher;
 
 impl MurMurHasher {
@@ -14,6 +21,62 @@ impl MurMurHasher {
     }
 }
 
+pub struct ContentHasher;
+
+impl ContentHasher {
+    /// Hashes the size and three samples of the file (the whole file if it's small).
+    /// Fast on big files, but blind to changes outside of the samples.
+    pub fn sampled_hash(path: &Path) -> std::io::Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut bytes = size.to_le_bytes().to_vec();
+        if size <= 3 * SAMPLE_LEN {
+            file.read_to_end(&mut bytes)?;
+        } else {
+            for offset in [0, size / 2 - SAMPLE_LEN / 2, size - SAMPLE_LEN] {
+                file.seek(SeekFrom::Start(offset))?;
+                (&mut file).take(SAMPLE_LEN).read_to_end(&mut bytes)?;
+            }
+        }
+        Ok(MurMurHasher::hash_bytes(&bytes))
+    }
+
+    /// BLAKE3 of the whole file.
+    pub fn blake3_hash(path: &Path) -> std::io::Result<String> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(path)?)?;
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+}
+
+pub struct PerceptualHasher;
+
+impl PerceptualHasher {
+    /// dHash of the image: it's shrunk to 9x8 grayscale pixels, every bit tells whether a pixel is brighter
+    /// than its right neighbour. Resized or re-encoded copies get the same or a close hash.
+    pub fn dhash(path: &Path) -> Result<u64, String> {
+        let image = ImageReader::open(path).map_err(|e| e.to_string())?
+            .with_guessed_format().map_err(|e| e.to_string())?
+            .decode().map_err(|e| e.to_string())?;
+        let pixels = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+        let mut hash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                hash <<= 1;
+                if pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0] {
+                    hash |= 1;
+                }
+            }
+        }
+        Ok(hash)
+    }
+
+    /// How many bits of the hashes differ, 0 for the same image.
+    pub fn distance(hash1: u64, hash2: u64) -> u32 {
+        (hash1 ^ hash2).count_ones()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -31,4 +94,41 @@ mod tests {
         assert_eq!(MurMurHasher::hash_bytes(b"world"), "ea84fbf00a79c5713a8e3571c3ece4c4");
         assert_eq!(MurMurHasher::hash_bytes(b"hello world"), "0e617feb46603f53b163eb607d4697ab");
     }
+
+    #[test]
+    fn test_content_hashes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let small = temp_dir.path().join("small.txt");
+        let small_copy = temp_dir.path().join("copy.txt");
+        std::fs::write(&small, b"hello").unwrap();
+        std::fs::write(&small_copy, b"hello").unwrap();
+        assert_eq!(ContentHasher::sampled_hash(&small).unwrap(), ContentHasher::sampled_hash(&small_copy).unwrap());
+        assert_eq!(ContentHasher::blake3_hash(&small).unwrap(), "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f");
+
+        // a change inside a sample is seen, even in a big file
+        let big = temp_dir.path().join("big.bin");
+        let mut content = vec![0u8; 4 * SAMPLE_LEN as usize];
+        std::fs::write(&big, &content).unwrap();
+        let hash = ContentHasher::sampled_hash(&big).unwrap();
+        content[2 * SAMPLE_LEN as usize] = 1;
+        std::fs::write(&big, &content).unwrap();
+        assert_ne!(ContentHasher::sampled_hash(&big).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_dhash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let gradient = image::RgbImage::from_fn(300, 200, |x, y| image::Rgb([(x * 255 / 300) as u8, (y * 255 / 200) as u8, 128]));
+        let original = temp_dir.path().join("original.png");
+        let resized = temp_dir.path().join("resized.jpg");
+        let flipped = temp_dir.path().join("flipped.png");
+        gradient.save(&original).unwrap();
+        image::imageops::resize(&gradient, 150, 100, FilterType::Triangle).save(&resized).unwrap();
+        image::imageops::flip_horizontal(&gradient).save(&flipped).unwrap();
+
+        let hash = PerceptualHasher::dhash(&original).unwrap();
+        assert!(PerceptualHasher::distance(hash, PerceptualHasher::dhash(&resized).unwrap()) <= 4);
+        assert!(PerceptualHasher::distance(hash, PerceptualHasher::dhash(&flipped).unwrap()) > 32);
+        assert!(PerceptualHasher::dhash(&temp_dir.path().join("missing.png")).is_err());
+    }
 }
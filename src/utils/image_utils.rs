@@ -0,0 +1,37 @@
+use std::io::Cursor;
+use std::path::Path;
+use image::{ImageFormat, ImageReader};
+
+/// Thumbnails fit in a square of this size.
+const THUMBNAIL_SIZE: u32 = 512;
+
+/// The PNG thumbnail of an image: the whole image, scaled down to fit in `THUMBNAIL_SIZE`.
+pub fn png_thumbnail(path: &Path) -> Result<Vec<u8>, String> {
+    let mut image = ImageReader::open(path).map_err(|e| e.to_string())?
+        .with_guessed_format().map_err(|e| e.to_string())?
+        .decode().map_err(|e| e.to_string())?;
+    if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+        image = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    }
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_thumbnail() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("a.png");
+        image::RgbImage::new(2048, 1024).save(&path).unwrap();
+        let thumbnail = image::load_from_memory(&png_thumbnail(&path).unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+
+        image::RgbImage::new(20, 10).save(&path).unwrap();
+        let thumbnail = image::load_from_memory(&png_thumbnail(&path).unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (20, 10));
+    }
+}
@@ -1,9 +1,18 @@
 use itertools::Itertools;
 use crate::utils::str_utils::StringExtensions;
 
+pub mod fs_utils;
 pub mod hash_utils;
+pub mod image_utils;
 pub mod str_utils;
 
+/// Keywords that search for something other than a tag, they can't be used as tags.
+pub const VIRTUAL_TAGS: [&str; 4] = ["all", "no-thumbnail", "missing", "modified"];
+
+pub fn is_virtual_tag(tag: &str) -> bool {
+    VIRTUAL_TAGS.contains(&tag)
+}
+
 pub fn normalize_query(query: &str) -> String {
     let initial_tags = query.split(' ')
         .map(|x| x.trim()) // remove leading and trailing whitespaces
@@ -21,8 +30,8 @@ pub fn normalize_query(query: &str) -> String {
         .unique() // filter out duplicates
         .collect::<Vec<String>>();
 
-    if final_tags.len() > 1 && final_tags.iter().any(|x| x == "all" || x == "no-thumbnail") {
-        final_tags.retain(|x| x != "all" && x != "no-thumbnail");
+    if final_tags.len() > 1 && final_tags.iter().any(|x| is_virtual_tag(x)) {
+        final_tags.retain(|x| !is_virtual_tag(x));
     }
 
     let mut normalized_query = final_tags.join(" ");
@@ -50,5 +59,8 @@ mod tests {
         assert_eq!(normalize_query("tag1   tag2"), "tag1 tag2");
         assert_eq!(normalize_query("tag1   tag2 "), "tag1 tag2 ");
         assert_eq!(normalize_query("tag1   tag2   "), "tag1 tag2 ");
+        assert_eq!(normalize_query("missing"), "missing");
+        assert_eq!(normalize_query("tag1 missing"), "tag1");
+        assert_eq!(normalize_query("modified tag1"), "tag1");
     }
 }
@@ -1,3 +1,4 @@
+use std::ops::Range;
 use itertools::Itertools;
 
 pub trait StringExtensions<'a, T: Into<&'a str>> {
@@ -37,6 +38,72 @@ impl<'a, T: Into<&'a str>> StringExtensions<'a, T> for T {
     }
 }
 
+/// The typos allowed when fuzzy matching a term, none for short terms so that `cat` doesn't match `car`.
+pub fn max_typos(term: &str) -> usize {
+    match term.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Finds the part of `text` closest to `term`, case-insensitive, with at most `max_typos` edits
+/// (inserted, deleted or replaced chars). Returns its byte range in `text` and the number of edits, the first
+/// and longest part if several are as close.
+pub fn fuzzy_find(text: &str, term: &str, max_typos: usize) -> Option<(Range<usize>, usize)> {
+    let lowercase = |c: char| c.to_lowercase().next().unwrap_or(c);
+    let term = term.chars().map(lowercase).collect::<Vec<char>>();
+    let text = text.char_indices().map(|(i, c)| (i, lowercase(c))).collect::<Vec<(usize, char)>>();
+    if term.is_empty() {
+        return None;
+    }
+    // edit distance between the first chars of `term` and the best part of `text` ending at each char,
+    // with the char index the part starts at
+    let mut previous_row = (0..=text.len()).map(|x| (0, x)).collect::<Vec<(usize, usize)>>();
+    for (i, term_char) in term.iter().enumerate() {
+        let mut row = vec![(i + 1, 0)];
+        for (j, (_, text_char)) in text.iter().enumerate() {
+            let (replaced, replaced_start) = previous_row[j];
+            let substitution = (replaced + usize::from(term_char != text_char), replaced_start);
+            let deletion = (previous_row[j + 1].0 + 1, previous_row[j + 1].1);
+            let insertion = (row[j].0 + 1, row[j].1);
+            row.push(*[substitution, deletion, insertion].iter().min_by_key(|x| x.0).unwrap());
+        }
+        previous_row = row;
+    }
+    let (end, (typos, start)) = previous_row.into_iter().enumerate().skip(1)
+        .min_by_key(|(end, (typos, start))| (*typos, *start, std::cmp::Reverse(*end)))?;
+    if typos > max_typos || start >= end {
+        return None;
+    }
+    let byte_end = text.get(end).map(|x| x.0).unwrap_or_else(|| text.last().map(|(i, c)| i + c.len_utf8()).unwrap_or(0));
+    Some((text[start].0..byte_end, typos))
+}
+
+/// Every non-overlapping part of `text` matching `term` as `fuzzy_find` does, in order.
+pub fn fuzzy_find_all(text: &str, term: &str, max_typos: usize) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while let Some((range, _)) = fuzzy_find(&text[offset..], term, max_typos) {
+        ranges.push(offset + range.start..offset + range.end);
+        offset += range.end;
+    }
+    ranges
+}
+
+#[test]
+fn test_fuzzy_find() {
+    assert_eq!(fuzzy_find("2024_Invoice.pdf", "invoice", 0), Some((5..12, 0)));
+    assert_eq!(fuzzy_find("2024_Invoice.pdf", "invocie", 2).map(|x| x.1), Some(2));
+    assert_eq!(fuzzy_find("2024_Invoice.pdf", "invoce", 1), Some((5..12, 1)));
+    assert_eq!(fuzzy_find("2024_Invoice.pdf", "receipt", 2), None);
+    assert_eq!(fuzzy_find("Café Menu", "cafe", 1), Some((0..5, 1)));
+    assert_eq!(fuzzy_find("abc", "", 1), None);
+    assert_eq!(fuzzy_find_all("cat_and_cat.png", "cat", 0), vec![0..3, 8..11]);
+    assert_eq!(max_typos("cat"), 0);
+    assert_eq!(max_typos("invoice"), 1);
+}
+
 #[test]
 fn test_slugify() {
     assert_eq!(
@@ -1,14 +1,21 @@
 use std::fs::File;
 use std::io::Write;
-use std::iter::once;
+use std::sync::atomic::Ordering;
 use base64::decode;
-use itertools::Itertools;
 use tauri::State;
 use tauri_plugin_dialog::{DialogExt, MessageDialogButtons, MessageDialogKind};
 use crate::entities::{Media, MediaId};
 use crate::utils::normalize_query;
 use crate::web_ui::{extract_tags, get_bg_color, get_fg_color, AppState, AutocompleteObject, ExtendedMedia, ExtendedTag, DEFAULT_AUTOCOMPLETE_PAGE_SIZE};
 
+/// Anything written while the DB is locked would be stored unencrypted.
+fn ensure_unlocked(app_state: &AppState) -> Result<(), String> {
+    match app_state.is_locked.load(Ordering::SeqCst) {
+        true => Err("The database is locked".to_string()),
+        false => Ok(()),
+    }
+}
+
 #[tauri::command(rename_all = "snake_case")]
 pub async fn choose_files(app_handle: tauri::AppHandle) -> Result<Vec<String>, String> {
     let file_paths = app_handle.dialog().file().blocking_pick_files().ok_or("No files selected")?;
@@ -28,14 +35,17 @@ pub async fn load_media_from_file(path_str: &str, app_state: State<'_, AppState>
 }
 
 #[tauri::command(rename_all = "snake_case")]
-pub fn has_thumbnail(media_id: &str, app_state: State<'_, AppState>) -> Result<bool, String> {
-    let filepath = app_state.config.thumbnails_dir.join(format!("{}.png", media_id));
-    Ok(filepath.exists())
+pub async fn has_thumbnail(media_id: &str, app_state: State<'_, AppState>) -> Result<bool, String> {
+    let client = app_state.client.read().await;
+    Ok(client.has_thumbnail(&media_id.to_string()))
 }
 
 #[tauri::command(rename_all = "snake_case")]
-pub fn save_thumbnail(media_id: &str, thumbnail: &str, app_state: State<'_, AppState>) -> Result<(), String> {
-    let filepath = app_state.config.thumbnails_dir.join(format!("{}.png", media_id));
+pub async fn save_thumbnail(media_id: &str, thumbnail: &str, app_state: State<'_, AppState>) -> Result<(), String> {
+    ensure_unlocked(&app_state)?;
+    let media_id: MediaId = media_id.to_string();
+    let client = app_state.client.read().await;
+    let filepath = client.get_thumbnail_path(&media_id);
     if filepath.exists() {
         if filepath.is_dir() {
             return Err("Thumbnail path is a directory".to_string());
@@ -44,29 +54,23 @@ pub fn save_thumbnail(media_id: &str, thumbnail: &str, app_state: State<'_, AppS
     }
     let data = thumbnail.split(",").nth(1).ok_or("Invalid data URL")?;
     let bytes = decode(data).map_err(|e| e.to_string())?;
-    let mut file = File::create(filepath).map_err(|e| e.to_string())?;
-    file.write_all(&bytes).map_err(|e| e.to_string())?;
+    client.save_thumbnail(&media_id, &bytes).await.map_err(|e| e.to_string())?;
     Ok(())
 }
 
 #[tauri::command(rename_all = "snake_case")]
 pub async fn add_tag_to_media(media_id: &str, tags: &str, path: Option<&str>, app_state: State<'_, AppState>) -> Result<Vec<ExtendedTag>, String> {
+    ensure_unlocked(&app_state)?;
     let media_id: MediaId = media_id.to_string();
     let tags_str = normalize_query(tags);
     let tags_str = tags_str.trim_end();
     if tags_str.is_empty() {
         return Ok(vec![]);
     }
-    let media = get_or_create_media(&media_id, path, &app_state).await?;
+    let media = get_or_load_media(&media_id, path, &app_state).await?;
     let tags = extract_tags(tags_str);
-    let new_tags = tags.iter().filter(|x| !media.tags.contains(x)).cloned().collect::<Vec<String>>();
-    if new_tags.is_empty() {
-        return Ok(vec![]);
-    }
     let mut client = app_state.client.write().await;
-    for tag in &new_tags {
-        client.add_tag_to_media(&media_id, tag).await.unwrap();
-    }
+    let new_tags = client.tag_media(media, &tags).await.map_err(|e| e.to_string())?;
     drop(client);
     let added_tags = new_tags.iter().map(|x| {
         let bg_color = get_bg_color(x);
@@ -78,6 +82,7 @@ pub async fn add_tag_to_media(media_id: &str, tags: &str, path: Option<&str>, ap
 
 #[tauri::command(rename_all = "snake_case")]
 pub async fn remove_tag_from_media(media_id: &str, tags: &str, app_state: State<'_, AppState>) -> Result<(), String> {
+    ensure_unlocked(&app_state)?;
     let media_id: MediaId = media_id.to_string();
     let tags_str = normalize_query(tags);
     let tags_str = tags_str.trim_end();
@@ -99,14 +104,13 @@ pub async fn remove_tag_from_media(media_id: &str, tags: &str, app_state: State<
     }
 
     let mut client = app_state.client.write().await;
-    for tag in &removed_tags {
-        client.remove_tag_from_media(&media_id, tag).await.unwrap();
-    }
+    client.remove_tags_from_media(&media_id, &removed_tags).await.map_err(|e| e.to_string())?;
     Ok(())
 }
 
 #[tauri::command(rename_all = "snake_case")]
 pub async fn delete_media(media_id: &str, app_handle: tauri::AppHandle, app_state: State<'_, AppState>) -> Result<bool, String> {
+    ensure_unlocked(&app_state)?;
     let confirmed = app_handle
         .dialog()
         .message("Are you sure you want to delete this media?")
@@ -130,26 +134,85 @@ pub async fn delete_media(media_id: &str, app_handle: tauri::AppHandle, app_stat
     Ok(true)
 }
 
+#[tauri::command(rename_all = "snake_case")]
+pub async fn merge_duplicates(media_id: &str, duplicate_ids: Vec<String>, delete_files: bool, app_handle: tauri::AppHandle, app_state: State<'_, AppState>) -> Result<bool, String> {
+    ensure_unlocked(&app_state)?;
+    if delete_files {
+        let confirmed = app_handle
+            .dialog()
+            .message(format!("Are you sure you want to delete {} file(s)?", duplicate_ids.len()))
+            .title("Confirm deletion")
+            .buttons(MessageDialogButtons::OkCancel)
+            .blocking_show();
+        if !confirmed {
+            return Ok(false);
+        }
+    }
+
+    let mut client = app_state.client.write().await;
+    let result = client.merge_duplicates(&media_id.to_string(), &duplicate_ids, delete_files).await.map_err(|e| e.to_string())?;
+    drop(client);
+    if !result.undeleted_files.is_empty() {
+        let undeleted_files = result.undeleted_files.iter().map(|(path, reason)| format!("{}: {}", path, reason)).collect::<Vec<String>>().join("\n");
+        show_err_dialog(&format!("The duplicates were merged, but these files could not be deleted:\n{}", undeleted_files), app_handle);
+    }
+    Ok(true)
+}
+
+/// Regenerates the thumbnail of a media whose file changed and records its new file info.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn refresh_media(media_id: &str, app_state: State<'_, AppState>) -> Result<(), String> {
+    ensure_unlocked(&app_state)?;
+    let mut client = app_state.client.write().await;
+    let media_id: MediaId = media_id.to_string();
+    client.regenerate_thumbnail(&media_id).await.map_err(|e| e.to_string())?;
+    client.record_file_info(&media_id).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn unlock_db(passphrase: &str, app_state: State<'_, AppState>) -> Result<(), String> {
+    let mut client = app_state.client.write().await;
+    client.unlock(passphrase).await.map_err(|e| e.to_string())?;
+    app_state.is_locked.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn undo(app_state: State<'_, AppState>) -> Result<bool, String> {
+    ensure_unlocked(&app_state)?;
+    let mut client = app_state.client.write().await;
+    let maybe_operation = client.undo().await.map_err(|e| e.to_string())?;
+    Ok(maybe_operation.is_some())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+pub async fn redo(app_state: State<'_, AppState>) -> Result<bool, String> {
+    ensure_unlocked(&app_state)?;
+    let mut client = app_state.client.write().await;
+    let maybe_operation = client.redo().await.map_err(|e| e.to_string())?;
+    Ok(maybe_operation.is_some())
+}
+
 #[tauri::command(rename_all = "snake_case")]
 pub async fn autocomplete_tags(query: &str, app_state: State<'_, AppState>) -> Result<Vec<AutocompleteObject>, String> {
-    let normalized_query = normalize_query(query);
-    if normalized_query.is_empty() {
+    let query = query.trim_start();
+    if query.is_empty() {
         return Ok(vec![]);
     }
     let page_size = DEFAULT_AUTOCOMPLETE_PAGE_SIZE;
     let client = app_state.client.read().await;
-    let autocomplete = client.autocomplete_tags(&normalized_query, page_size);
+    let autocomplete = client.autocomplete_tags(query, page_size);
     let autocomplete = autocomplete.iter().map(|x| {
-        let query = normalized_query.clone();
-        let tags = x.head.iter().map(|x| x.as_str()).chain(once(x.last.as_str()))
-            .map(|x| x.to_string()).collect::<Vec<String>>();
-        let suggestion = tags.join(" ");
+        let query = query.to_string();
+        let suggestion = format!("{}{}", x.head, x.last);
+        // a tag found by a part of it or despite typos is highlighted as a whole
         let highlighted_suggestion = match suggestion.starts_with(&query) {
-            true => query.clone() + "<mark>" + &suggestion[normalized_query.len()..] + "</mark>",
-            false => suggestion.clone(),
+            true => query.clone() + "<mark>" + &suggestion[query.len()..] + "</mark>",
+            false => format!("{}<mark>{}</mark>", x.head, x.last),
         };
         AutocompleteObject { query, suggestion, highlighted_suggestion, media_count: x.media_count }
-    }).sorted_by_key(|x| x.media_count).rev().collect::<Vec<AutocompleteObject>>();
+    }).collect::<Vec<AutocompleteObject>>();
     Ok(autocomplete)
 }
 
@@ -213,7 +276,8 @@ pub async fn export_db(app_handle: tauri::AppHandle, app_state: State<'_, AppSta
     Ok(())
 }
 
-async fn get_or_create_media(media_id: &MediaId, path: Option<&str>, app_state: &State<'_, AppState>) -> Result<Media, String> {
+/// A media that is not in the DB yet is only read from the file, it's added together with its first tags.
+async fn get_or_load_media(media_id: &MediaId, path: Option<&str>, app_state: &State<'_, AppState>) -> Result<Media, String> {
     let client = app_state.client.read().await;
     let mut maybe_media = client.get_media_by_id(&media_id);
     drop(client);
@@ -228,18 +292,11 @@ async fn get_or_create_media(media_id: &MediaId, path: Option<&str>, app_state:
 
     let path = path.unwrap();
     let client = app_state.client.read().await;
-    maybe_media = client.create_media_from_file(&path.into()).await.ok();
+    maybe_media = client.create_media_from_file(std::path::Path::new(path)).await.ok();
     drop(client);
     if maybe_media.is_none() {
         return Err("Media not found (1)".to_string());
     }
 
-    let mut client = app_state.client.write().await;
-    maybe_media = client.add_media(maybe_media.unwrap()).await.ok().map(|x| x.safe_unwrap());
-    drop(client);
-    if maybe_media.is_none() {
-        return Err("Media not found (2)".to_string());
-    }
-
     Ok(maybe_media.unwrap())
 }
@@ -3,18 +3,21 @@ mod streaming;
 
 use std::hash::Hasher;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use askama::Template;
 use axum::{routing::get, Router};
 use axum::body::Body;
-use axum::extract::{Path, Query, State};
+use axum::extract::{Path, Query, Request, State};
 use axum::http::StatusCode;
+use axum::middleware::{self, Next};
 use axum::response::{Html, IntoResponse, Response};
 use axum_macros::FromRef;
 use chrono::{DateTime, Utc};
 use http::header::*;
 use humanize_bytes::humanize_bytes_decimal;
 use itertools::Itertools;
-use log::info;
+use log::{info, warn};
 use random_port::{PortPicker, Protocol};
 use serde::{Deserialize, Serialize};
 use tauri::{Manager, WebviewUrl, WebviewWindowBuilder};
@@ -22,15 +25,18 @@ use tokio::sync::RwLock;
 use tower_http::trace::TraceLayer;
 use crate::client::TaganrogClient;
 use crate::config::AppConfig;
-use crate::entities::{Media, TagsAutocomplete};
-use crate::storage::FileStorage;
-use crate::utils::normalize_query;
+use crate::entities::{LibraryRootInfo, Media, MediaSort, TagsAutocomplete};
+use crate::storage::{AppStorage, ChangeKind};
+use crate::query::{normalize_search_query, Filter, QueryExpr};
+use crate::utils::str_utils::{fuzzy_find_all, max_typos};
 use crate::utils::str_utils::StringExtensions;
 use crate::web_ui::commands::*;
 use crate::web_ui::streaming::get_stream_response;
 
 // app properties
 const PORT: u16 = 1698;
+const DB_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+const FILE_SCAN_INTERVAL: Duration = Duration::from_secs(300);
 
 // icons
 const FAVICON: &[u8] = include_bytes!("assets/favicon.ico");
@@ -47,13 +53,28 @@ const ALGOLIA_STYLES: &[u8] = include_bytes!("assets/styles/algolia_classic_1.15
 
 const DEFAULT_MEDIA_PAGE_SIZE: usize = 3;
 const DEFAULT_AUTOCOMPLETE_PAGE_SIZE: usize = 6;
+const MAX_SIMILAR_MEDIA: usize = 12;
 
-pub async fn serve(config: AppConfig, client: TaganrogClient<FileStorage>) {
+pub async fn serve(config: AppConfig, mut client: TaganrogClient<AppStorage>) {
     let media_count = client.get_media_count();
     info!("media count: {}", media_count);
+    let is_locked = client.is_locked().await.unwrap_or(false);
 
     info!("initializing router...");
-    let app_state = AppState { config: Arc::new(config), client: Arc::new(RwLock::new(client)) };
+    let app_state = AppState {
+        config: Arc::new(config),
+        client: Arc::new(RwLock::new(client)),
+        is_locked: Arc::new(AtomicBool::new(is_locked)),
+    };
+    let pages = Router::new()
+        .route("/", get(index))
+        .route("/media/add", get(add_media_page))
+        .route("/media/random", get(get_random_media))
+        .route("/media/:media_id", get(get_media))
+        .route("/search", get(media_search))
+        .route("/tags_cloud", get(tags_cloud))
+        .route("/duplicates", get(duplicates))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), require_unlocked));
     let router = Router::new()
         // icons
         .route("/favicon.ico", get(favicon))
@@ -69,15 +90,11 @@ pub async fn serve(config: AppConfig, client: TaganrogClient<FileStorage>) {
         .route("/styles/algolia.min.css", get(get_algolia_styles))
 
         // pages
-        .route("/", get(index))
-        .route("/media/add", get(add_media_page))
-        .route("/media/random", get(get_random_media))
-        .route("/media/:media_id", get(get_media))
-        .route("/search", get(media_search))
-        .route("/tags_cloud", get(tags_cloud))
+        .merge(pages)
 
         // stream
         .route("/stream/*path", get(stream_file))
+        .route("/thumbnails/:media_id", get(get_thumbnail))
 
         .with_state(app_state.clone())
         .layer(TraceLayer::new_for_http());
@@ -90,11 +107,13 @@ pub async fn serve(config: AppConfig, client: TaganrogClient<FileStorage>) {
     tokio::spawn(async move {
         axum::serve(listener, router).await.expect("error running HTTP server");
     });
+    tokio::spawn(watch_db(app_state.client.clone(), app_state.is_locked.clone()));
+    tokio::spawn(scan_media_files(app_state.client.clone(), app_state.is_locked.clone(), app_state.config.hash_content));
 
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
-        .invoke_handler(tauri::generate_handler![choose_files, load_media_from_file, has_thumbnail, save_thumbnail, add_tag_to_media, remove_tag_from_media, delete_media, autocomplete_tags, parse_tags, show_err_dialog, show_info_dialog, show_media_in_file_manager, export_db])
+        .invoke_handler(tauri::generate_handler![choose_files, load_media_from_file, has_thumbnail, save_thumbnail, add_tag_to_media, remove_tag_from_media, delete_media, merge_duplicates, refresh_media, unlock_db, undo, redo, autocomplete_tags, parse_tags, show_err_dialog, show_info_dialog, show_media_in_file_manager, export_db])
         .setup(move |app| {
             app.manage(app_state);
             let url = format!("http://localhost:{}", port).parse().unwrap();
@@ -110,10 +129,83 @@ pub async fn serve(config: AppConfig, client: TaganrogClient<FileStorage>) {
         .expect("error while running Tauri application");
 }
 
+/// Picks up the changes other processes (e.g. `taganrog tag` in a terminal) make to the DB while the app is open.
+async fn watch_db(client: Arc<RwLock<TaganrogClient<AppStorage>>>, is_locked: Arc<AtomicBool>) {
+    let mut interval = tokio::time::interval(DB_WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if is_locked.load(Ordering::SeqCst) {
+            continue;
+        }
+        // the client is only held exclusively when there is something to sync, an error is reported by `sync`
+        if !client.read().await.has_changes().await.unwrap_or(true) {
+            continue;
+        }
+        match client.write().await.sync().await {
+            Ok(0) => {},
+            Ok(operations_count) => info!("applied {} db operations made by another process", operations_count),
+            Err(e) => warn!("failed to sync db: {}", e),
+        }
+    }
+}
+
+/// Keeps the `missing` and `modified` searches up to date with the files changed, deleted or moved outside of the app,
+/// records the file info of the media that have none yet and computes the perceptual hashes of the images that don't have one yet.
+/// Files are read without holding the client.
+async fn scan_media_files(client: Arc<RwLock<TaganrogClient<AppStorage>>>, is_locked: Arc<AtomicBool>, hash_content: bool) {
+    let mut interval = tokio::time::interval(FILE_SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        if is_locked.load(Ordering::SeqCst) {
+            continue;
+        }
+        let reader = client.read().await;
+        let media_to_check = reader.get_media_to_check();
+        let unhashed_images = reader.get_unhashed_images();
+        drop(reader);
+
+        let missing_media = TaganrogClient::<AppStorage>::find_missing_media(media_to_check.clone()).await;
+        client.read().await.set_missing_media(&missing_media);
+        if !missing_media.is_empty() {
+            info!("{} media files are missing", missing_media.len());
+        }
+
+        let file_checks = TaganrogClient::<AppStorage>::check_files(media_to_check, hash_content).await;
+        match client.write().await.record_file_checks(file_checks, ChangeKind::Background).await {
+            Ok(result) if !result.modified.is_empty() => info!("{} media files were modified", result.modified.len()),
+            Ok(_) => {},
+            Err(e) => warn!("failed to record file info: {}", e),
+        }
+        if unhashed_images.is_empty() {
+            continue;
+        }
+        let perceptual_hashes = TaganrogClient::<AppStorage>::compute_perceptual_hashes(unhashed_images).await;
+        match client.write().await.record_perceptual_hashes(perceptual_hashes, ChangeKind::Background).await {
+            Ok(0) => {},
+            Ok(hashed_count) => info!("computed the perceptual hashes of {} images", hashed_count),
+            Err(e) => warn!("failed to record perceptual hashes: {}", e),
+        }
+    }
+}
+
 #[derive(Clone, FromRef)]
 struct AppState {
     config: Arc<AppConfig>,
-    client: Arc<RwLock<TaganrogClient<FileStorage>>>,
+    client: Arc<RwLock<TaganrogClient<AppStorage>>>,
+    // an encrypted DB stays locked until the passphrase is entered on the unlock page
+    is_locked: Arc<AtomicBool>,
+}
+
+#[derive(Default, Template)]
+#[template(path = "unlock.html")]
+struct UnlockTemplate {}
+
+/// Shows the unlock page instead of any other page while the DB is locked.
+async fn require_unlocked(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.is_locked.load(Ordering::SeqCst) {
+        return HtmlTemplate(UnlockTemplate {}).into_response();
+    }
+    next.run(request).await
 }
 
 struct HtmlTemplate<T>(T);
@@ -138,11 +230,15 @@ impl<T> IntoResponse for HtmlTemplate<T>
 struct IndexTemplate {
     query: String,
     random_media_id: String,
+    offline_roots: Vec<LibraryRootInfo>,
 }
 
-async fn index() -> impl IntoResponse {
+async fn index(State(state): State<AppState>) -> impl IntoResponse {
     let random_media_id = rand::random::<u64>().to_string();
-    HtmlTemplate(IndexTemplate { query: "".to_string(), random_media_id })
+    let offline_roots = state.client.read().await.get_library_roots().into_iter()
+        .filter(|x| !x.is_online)
+        .collect();
+    HtmlTemplate(IndexTemplate { query: "".to_string(), random_media_id, offline_roots })
 }
 
 async fn favicon() -> impl IntoResponse { Response::<Body>::new(FAVICON.into()) }
@@ -153,6 +249,10 @@ struct SearchQuery {
     p: Option<usize>,
     ps: Option<usize>,
     path: Option<String>,
+    /// see `MediaSort`
+    sort: Option<String>,
+    /// `text` to search the words of `q` in the filenames and paths, see `QueryExpr::from_text`
+    mode: Option<String>,
 }
 
 #[derive(Default, Template)]
@@ -160,6 +260,8 @@ struct SearchQuery {
 pub struct SearchTemplate {
     query: String,
     page_size: usize,
+    /// `MediaSort` with its seed, so that the next pages of a random order are the same shuffle
+    sort: String,
     media_vec: Vec<ExtendedMedia>,
     current_page_number: usize,
     max_page_number: usize,
@@ -169,9 +271,42 @@ pub struct SearchTemplate {
     has_pages_before: bool,
     has_pages_after: bool,
     time_elapsed_ms: u128,
+    /// Why the query couldn't be parsed.
+    error: String,
+    /// The query with the unknown tags corrected, when it found nothing.
+    did_you_mean: String,
 }
 
 impl SearchTemplate {
+    pub fn is_sorted_by(&self, name: &&str) -> bool {
+        self.sort.split(':').next() == Some(*name)
+    }
+
+    pub fn sort_names(&self) -> [&'static str; 9] {
+        MediaSort::NAMES
+    }
+
+    /// Whether the query is a plain list of tags, so that its words can be searched in the file names instead.
+    pub fn can_search_text(&self) -> bool {
+        QueryExpr::parse(&self.query).is_ok_and(|x| x.is_some_and(|x| x.is_term_list()))
+    }
+
+    /// The query narrowed down to the media with the tag, e.g. `(cat OR dog) black`.
+    pub fn query_with(&self, tag: &str) -> String {
+        match QueryExpr::parse(&self.query) {
+            Ok(Some(expr)) => QueryExpr::And(vec![expr, QueryExpr::Term(tag.to_string())]).to_string(),
+            _ => format!("{} {}", self.query, tag),
+        }
+    }
+
+    /// The query without the tag, for the link that removes it from the search.
+    pub fn query_without(&self, tag: &str) -> String {
+        match QueryExpr::parse(&self.query) {
+            Ok(Some(expr)) => expr.without_term(tag).map(|x| x.to_string()).unwrap_or_default(),
+            _ => self.query.clone(),
+        }
+    }
+
     pub fn is_current_page(&self, page: &&usize) -> bool {
         self.current_page_number == **page
     }
@@ -191,15 +326,35 @@ pub struct ExtendedMedia {
     pub thumbnail_location_url: String,
     pub tags: Vec<ExtendedTag>,
     pub is_image: bool,
+    /// The media is under a library root that is offline.
+    pub is_offline: bool,
+    /// The filename split into the parts matching the `name:` and `path:` filters of the query and the others.
+    pub filename_parts: Vec<(String, bool)>,
+    /// Same for the location, empty if the query has no `path:` filter.
+    pub location_parts: Vec<(String, bool)>,
 }
 
 impl ExtendedMedia {
-    pub fn create_for_query(media: Media, app_config: &AppConfig, query_tags: &Vec<String>) -> Self {
+    pub fn create_for_query(media: Media, app_config: &AppConfig, query: &QueryExpr) -> Self {
+        let query_tags = query.terms();
         let mut media = ExtendedMedia::create(media, app_config);
-        media.tags.sort_by_key(|ex_tag| query_tags.iter().position(|tag| tag == &ex_tag.name).unwrap_or(usize::MAX));
+        media.tags.sort_by_key(|ex_tag| query_tags.iter().position(|tag| *tag == &ex_tag.name).unwrap_or(usize::MAX));
         media.tags.iter_mut().for_each(|tag| {
-            tag.is_in_query = query_tags.contains(&tag.name);
+            tag.is_in_query = query_tags.contains(&&tag.name);
         });
+        let filters = query.filters();
+        let name_terms = filters.iter().filter_map(|x| match x {
+            Filter::Name(term) | Filter::Path(term) => Some(term.as_str()),
+            _ => None,
+        }).collect::<Vec<&str>>();
+        let path_terms = filters.iter().filter_map(|x| match x {
+            Filter::Path(term) => Some(term.as_str()),
+            _ => None,
+        }).collect::<Vec<&str>>();
+        media.filename_parts = split_matches(&media.filename, &name_terms);
+        if !path_terms.is_empty() {
+            media.location_parts = split_matches(&media.location, &path_terms);
+        }
         media
     }
 
@@ -211,12 +366,17 @@ impl ExtendedMedia {
         }).collect();
         let location_url = convert_file_src(&media.location);
         let thumbnail_location = app_config.thumbnails_dir.join(format!("{}.png", &media.id)).to_string_lossy().to_string();
+        let encrypted_thumbnail_location = app_config.thumbnails_dir.join(format!("{}.png.enc", media.id));
         let thumbnail_location_url = if std::path::Path::new(&thumbnail_location).exists() {
             convert_file_src(&thumbnail_location)
+        } else if encrypted_thumbnail_location.exists() {
+            format!("/thumbnails/{}", media.id)
         } else {
             "/default_thumbnail.svg".to_string()
         };
         let human_size = humanize_bytes_decimal!(media.size).to_string();
+        let is_offline = app_config.library_roots.is_offline(&media.location);
+        let filename_parts = vec![(media.filename.clone(), false)];
         Self {
             id: media.id,
             filename: media.filename,
@@ -229,9 +389,37 @@ impl ExtendedMedia {
             thumbnail_location_url,
             tags,
             is_image: media.content_type.starts_with("image"),
+            is_offline,
             content_type: media.content_type,
+            filename_parts,
+            location_parts: vec![],
+        }
+    }
+}
+
+/// Splits the text into the parts matching any of the terms, fuzzily like the filters do, and the others.
+fn split_matches(text: &str, terms: &[&str]) -> Vec<(String, bool)> {
+    let ranges = terms.iter()
+        .flat_map(|x| fuzzy_find_all(text, x, max_typos(x)))
+        .sorted_by_key(|x| x.start)
+        .coalesce(|a, b| match b.start <= a.end {
+            true => Ok(a.start..a.end.max(b.end)),
+            false => Err((a, b)),
+        })
+        .collect::<Vec<_>>();
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    for range in ranges {
+        if range.start > offset {
+            parts.push((text[offset..range.start].to_string(), false));
         }
+        parts.push((text[range.clone()].to_string(), true));
+        offset = range.end;
     }
+    if offset < text.len() || parts.is_empty() {
+        parts.push((text[offset..].to_string(), false));
+    }
+    parts
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -260,27 +448,40 @@ async fn media_search(
     Query(query): Query<SearchQuery>,
 ) -> impl IntoResponse {
     let start = std::time::Instant::now();
-    let normalized_query = normalize_query(&query.q.unwrap_or_default());
-    if normalized_query.is_empty() {
-        return HtmlTemplate(SearchTemplate::default());
-    }
+    let raw_query = query.q.unwrap_or_default();
     let page_number = query.p.unwrap_or(1).max(1);
     let page_index = page_number - 1;
     let page_size = query.ps.unwrap_or(DEFAULT_MEDIA_PAGE_SIZE).max(1);
+    let sort = query.sort.filter(|x| !x.is_empty()).map(|x| x.parse::<MediaSort>()).transpose();
+    let parsed_query = match query.mode.as_deref() {
+        Some("text") => Ok(QueryExpr::from_text(&raw_query)),
+        _ => QueryExpr::parse(&raw_query).map_err(|e| e.to_string()),
+    };
+    let (expr, sort) = match (parsed_query, sort) {
+        (Ok(Some(expr)), Ok(sort)) => (expr, sort.unwrap_or_default()),
+        (Ok(None), _) => return HtmlTemplate(SearchTemplate::default()),
+        (Err(e), _) | (_, Err(e)) => return HtmlTemplate(SearchTemplate {
+            query: raw_query.trim().to_string(),
+            page_size,
+            sort: MediaSort::default().to_string(),
+            error: e,
+            ..SearchTemplate::default()
+        }),
+    };
+    let normalized_query = expr.to_string();
 
     let client = state.client.read().await;
-    let media_page = match normalized_query.as_str() {
-        "all" => client.get_all_media(page_size, page_index),
-        "no-thumbnail" => client.get_media_without_thumbnail(page_size, page_index),
-        _ => client.search_media(&normalized_query, page_size, page_index),
+    let media_page = client.search_media(&normalized_query, sort, page_size, page_index).unwrap_or_default();
+    let did_you_mean = match media_page.total_count {
+        0 => client.suggest_query(&normalized_query).unwrap_or_default(),
+        _ => String::new(),
     };
     drop(client);
 
     let page_number = media_page.page_index + 1;
 
-    let query_tags = extract_tags(&normalized_query);
     let media_vec = media_page.media_vec.into_iter()
-        .map(|x| ExtendedMedia::create_for_query(x, &state.config, &query_tags))
+        .map(|x| ExtendedMedia::create_for_query(x, &state.config, &expr))
         .collect::<Vec<ExtendedMedia>>();
 
     const PAGES_BEFORE: usize = 3;
@@ -296,6 +497,7 @@ async fn media_search(
     HtmlTemplate(SearchTemplate {
         query: normalized_query,
         page_size,
+        sort: sort.to_string(),
         media_vec,
         current_page_number: page_number,
         max_page_number: media_page.total_pages,
@@ -305,6 +507,8 @@ async fn media_search(
         has_pages_before: has_more_pages_before,
         has_pages_after: has_more_pages_after,
         time_elapsed_ms,
+        error: String::new(),
+        did_you_mean,
     })
 }
 
@@ -329,8 +533,13 @@ struct AutocompleteObject {
 pub struct MediaPageTemplate {
     query: String,
     page: usize,
+    /// The sort of the search the media was opened from, to go back to the same page.
+    sort: String,
     media: ExtendedMedia,
     media_exists: bool,
+    similar_media: Vec<ExtendedMedia>,
+    /// The content of the file changed since it was recorded, see `scan_modified_media`.
+    is_modified: bool,
     random_media_id: String,
 }
 
@@ -339,20 +548,26 @@ async fn get_media(
     Query(query): Query<SearchQuery>,
     Path(media_id): Path<String>,
 ) -> impl IntoResponse {
-    let normalized_query = normalize_query(&query.q.unwrap_or_default());
+    let normalized_query = normalize_search_query(&query.q.unwrap_or_default());
     let page = query.p.unwrap_or(1);
+    // an unknown sort falls back to the default one, the media is shown anyway
+    let sort = query.sort.and_then(|x| x.parse::<MediaSort>().ok()).unwrap_or_default().to_string();
     let client = state.client.read().await;
     let mut maybe_media = client.get_media_by_id(&media_id);
     if maybe_media.is_none() {
-        maybe_media = client.create_media_from_file(&query.path.unwrap_or_default().into()).await.ok();
+        maybe_media = client.create_media_from_file(&std::path::PathBuf::from(query.path.unwrap_or_default())).await.ok();
     }
+    drop(client);
     let random_media_id = rand::random::<u64>().to_string();
     if let Some(media) = maybe_media {
+        let similar_media = get_similar_media(&state, &media).await;
+        let client = state.client.read().await;
+        let is_modified = client.is_media_modified(&media.id);
         let mut media = ExtendedMedia::create(media, &state.config);
         media.tags = media.tags.into_iter().rev().collect();
-        HtmlTemplate(MediaPageTemplate { query: normalized_query, page, media, media_exists: true, random_media_id })
+        HtmlTemplate(MediaPageTemplate { query: normalized_query, page, sort, media, media_exists: true, similar_media, is_modified, random_media_id })
     } else {
-        HtmlTemplate(MediaPageTemplate { query: normalized_query, page, media: ExtendedMedia::default(), media_exists: false, random_media_id })
+        HtmlTemplate(MediaPageTemplate { query: normalized_query, page, sort, media: ExtendedMedia::default(), media_exists: false, similar_media: vec![], is_modified: false, random_media_id })
     }
 }
 
@@ -366,14 +581,43 @@ async fn get_random_media(
     State(state): State<AppState>,
 ) -> impl IntoResponse {
     let seed = query.seed.unwrap_or_else(|| rand::random());
-    let client = state.client.read().await;
+    let maybe_media = state.client.read().await.get_random_media(seed);
     let random_media_id = rand::random::<u64>().to_string();
-    match client.get_random_media(seed) {
+    match maybe_media {
         Some(media) => {
+            let similar_media = get_similar_media(&state, &media).await;
+            let is_modified = state.client.read().await.is_media_modified(&media.id);
             let media = ExtendedMedia::create(media, &state.config);
-            HtmlTemplate(MediaPageTemplate { query: "".to_string(), page: 1, media, media_exists: true, random_media_id })
+            HtmlTemplate(MediaPageTemplate { query: "".to_string(), page: 1, sort: String::new(), media, media_exists: true, similar_media, is_modified, random_media_id })
+        },
+        None => HtmlTemplate(MediaPageTemplate { query: "".to_string(), page: 1, sort: String::new(), media: ExtendedMedia::default(), media_exists: false, similar_media: vec![], is_modified: false, random_media_id })
+    }
+}
+
+/// An image without a perceptual hash is decoded without holding the client.
+async fn get_similar_media(state: &AppState, media: &Media) -> Vec<ExtendedMedia> {
+    let Some(perceptual_hash) = TaganrogClient::<AppStorage>::perceptual_hash_of(media).await else {
+        return vec![];
+    };
+    state.client.read().await.find_similar_to_hash(&media.id, perceptual_hash, state.config.similar_max_distance).into_iter()
+        .take(MAX_SIMILAR_MEDIA)
+        .map(|(x, _)| ExtendedMedia::create(x, &state.config))
+        .collect()
+}
+
+/// Serves a thumbnail that has to be decrypted first.
+async fn get_thumbnail(
+    State(state): State<AppState>,
+    Path(media_id): Path<String>,
+) -> impl IntoResponse {
+    let client = state.client.read().await;
+    match client.read_thumbnail(&media_id).await {
+        Ok(bytes) => {
+            let mut response = Response::new(Body::from(bytes));
+            response.headers_mut().insert("Content-Type", "image/png".parse().unwrap());
+            response
         },
-        None => HtmlTemplate(MediaPageTemplate { query: "".to_string(), page: 1, media: ExtendedMedia::default(), media_exists: false, random_media_id })
+        Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
     }
 }
 
@@ -422,12 +666,14 @@ async fn get_algolia_styles() -> impl IntoResponse {
 #[derive(Default, Template)]
 #[template(path = "add_media.html")]
 struct AddMediaTemplate {
+    /// Always empty, the header links to the tags cloud of the query.
+    query: String,
     random_media_id: String,
 }
 
 async fn add_media_page() -> impl IntoResponse {
     let random_media_id = rand::random::<u64>().to_string();
-    HtmlTemplate(AddMediaTemplate { random_media_id })
+    HtmlTemplate(AddMediaTemplate { query: String::new(), random_media_id })
 }
 
 #[derive(Default, Debug, Serialize)]
@@ -465,22 +711,55 @@ fn get_fg_color(bg_color: &str) -> String {
 pub struct TagsCloudTemplate {
     query: String,
     tags: Vec<TagsAutocomplete>,
+    error: String,
 }
 
 async fn tags_cloud(
     Query(query): Query<SearchQuery>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let normalized_query = normalize_query(&query.q.unwrap_or_default());
+    let normalized_query = normalize_search_query(&query.q.unwrap_or_default());
     let client = state.client.read().await;
-    let tags = client.get_all_tags();
+    // the cloud of a query shows the tags of the media it matches
+    let result = client.get_query_tags(&normalized_query);
     drop(client);
+    let (tags, error) = match result {
+        Ok(tags) => (tags, String::new()),
+        Err(e) => (vec![], e.to_string()),
+    };
     let tags = tags.iter()
         .sorted_by_key(|x| x.media_count).rev()
         .take(100)
         .cloned()
         .collect::<Vec<TagsAutocomplete>>();
-    HtmlTemplate(TagsCloudTemplate { query: normalized_query, tags })
+    HtmlTemplate(TagsCloudTemplate { query: normalized_query, tags, error })
+}
+
+#[derive(Template)]
+#[template(path = "duplicates.html")]
+pub struct DuplicatesTemplate {
+    query: String,
+    groups: Vec<Vec<ExtendedMedia>>,
+    error: String,
+}
+
+async fn duplicates(
+    Query(query): Query<SearchQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let normalized_query = normalize_search_query(&query.q.unwrap_or_default());
+    let client = state.client.read().await;
+    let candidates = client.get_duplicate_candidates();
+    drop(client);
+    let result = TaganrogClient::<AppStorage>::group_duplicates(candidates).await;
+    let (groups, error) = match result {
+        Ok(groups) => (groups, String::new()),
+        Err(e) => (vec![], e.to_string()),
+    };
+    let groups = groups.into_iter()
+        .map(|x| x.media.into_iter().map(|x| ExtendedMedia::create(x, &state.config)).collect())
+        .collect();
+    HtmlTemplate(DuplicatesTemplate { query: normalized_query, groups, error })
 }
 
 async fn stream_file(
@@ -493,7 +772,9 @@ async fn stream_file(
             let mut error_headers = HeaderMap::new();
             error_headers.insert(CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap());
             let error_body = e.to_string().as_bytes().to_vec();
-            (StatusCode::INTERNAL_SERVER_ERROR, error_headers, error_body)
+            let is_missing = e.downcast_ref::<std::io::Error>().is_some_and(|x| x.kind() == std::io::ErrorKind::NotFound);
+            let status_code = if is_missing { StatusCode::NOT_FOUND } else { StatusCode::INTERNAL_SERVER_ERROR };
+            (status_code, error_headers, error_body)
         },
     }
 }